@@ -0,0 +1,29 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+
+// Reading a value should not get slower as the key grows
+fn get_by_key_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_by_key_size");
+    for key_size in [16, 1024, 64 * 1024] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        let keys: Vec<String> = (0..100)
+            .map(|key_id| format!("{}{}", "k".repeat(key_size), key_id))
+            .collect();
+        for key in &keys {
+            store.set(key.clone(), "value".to_owned()).unwrap();
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(key_size), &keys, |b, keys| {
+            b.iter(|| {
+                for key in keys {
+                    store.get(key.clone()).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get_by_key_size);
+criterion_main!(benches);
@@ -1,8 +1,8 @@
 use clap::Parser;
-use kvs::{Result};
+use kvs::{is_compatible_version, KvsClient, KvsError, Result};
 use kvs::{Commands, NetworkConnection};
 use std::{
-    net::{SocketAddr, TcpStream},
+    net::{SocketAddr, ToSocketAddrs},
     process::exit,
 };
 
@@ -11,31 +11,117 @@ use std::{
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-    #[arg(long, value_name = "IP:PORT", global = true)]
+    /// The address of the server, by IP or host name [default: 127.0.0.1:4000]
+    #[arg(long, value_name = "HOST:PORT", global = true)]
     addr: Option<String>,
+    /// Fail instead of warning when the server has a different major version
+    #[arg(long, global = true)]
+    strict_version: bool,
+    /// The shared secret the server was started with
+    #[arg(long, value_name = "TOKEN", global = true)]
+    auth_token: Option<String>,
+    /// Keep all keys of the request within this namespace
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+    /// Keep all keys of the request within this numbered database [default: 0]
+    #[arg(long, value_name = "N", global = true)]
+    db: Option<u64>,
+    /// Let the connection coalesce small writes (Nagle's algorithm), for batch clients
+    #[arg(long, global = true)]
+    nagle: bool,
+    /// Print the time the server spent on the request to stderr
+    #[arg(long, global = true)]
+    timing: bool,
 }
 
-pub fn main() -> Result<()> {
-    let cli: Cli = Cli::parse();
-    let mut ip_port: SocketAddr = "127.0.0.1:4000".parse()?;
+pub fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+}
 
-    if let Some(ipaddr) = cli.addr.as_deref() {
-        ip_port = ipaddr.parse()?;
+/// Resolves the address of the server, which may name the host instead of its IP
+fn resolve(addr: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .map_err(|err| KvsError::Config(format!("could not resolve host {:?}: {}", addr, err)))?
+        .collect();
+    if addrs.is_empty() {
+        let error = format!("could not resolve host {:?}: no address found", addr);
+        return Err(KvsError::Config(error));
     }
+    Ok(addrs)
+}
+
+fn run() -> Result<()> {
+    let cli: Cli = Cli::parse();
+    let addrs = resolve(cli.addr.as_deref().unwrap_or("127.0.0.1:4000"))?;
 
     // Connect to server
-    let mut stream = TcpStream::connect(ip_port)?;
+    let mut client = KvsClient::connect(addrs.as_slice())?;
+    if cli.nagle {
+        client.set_nodelay(false)?;
+    }
 
-    NetworkConnection::send_network_message(
-        NetworkConnection::Request {
-            command: cli.command,
-        },
-        &mut stream,
-    )?;
+    // servers predating the version exchange don't announce any
+    let version = client.server_version().unwrap_or("unknown").to_string();
+    if !is_compatible_version(env!("CARGO_PKG_VERSION"), &version) {
+        if cli.strict_version {
+            eprintln!("{}", KvsError::IncompatibleVersion(version));
+            exit(1);
+        }
+        eprintln!(
+            "Warning: server version {} differs from client version {}",
+            version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    let mut setup = Ok(());
+    if let Some(token) = cli.auth_token.as_deref() {
+        setup = setup.and_then(|()| client.authenticate(token));
+    }
+    if let Some(namespace) = cli.namespace.as_deref() {
+        setup = setup.and_then(|()| client.select_namespace(namespace));
+    }
+    if let Some(db) = cli.db {
+        setup = setup.and_then(|()| client.select_db(db));
+    }
+    match setup {
+        Ok(()) => (),
+        Err(KvsError::Remote(error)) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+        Err(err) => return Err(err),
+    }
+
+    // Scans are streamed rather than answered with a single response
+    if let Commands::Scan { prefix } = cli.command {
+        for pair in client.scan(prefix)? {
+            match pair {
+                Ok((key, value)) => println!("{} {}", key, value),
+                Err(KvsError::Remote(error)) => {
+                    eprintln!("{}", error);
+                    exit(1);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        return Ok(());
+    }
 
     // Get response
-    let buf = NetworkConnection::receive_network_message(&mut stream)?;
-    let response = NetworkConnection::deserialize_message(buf)?;
+    let response = if cli.timing {
+        let (response, server_time_micros) = client.request_timed(cli.command)?;
+        if let Some(server_time_micros) = server_time_micros {
+            eprintln!("Server time: {} us", server_time_micros);
+        }
+        response
+    } else {
+        client.request(cli.command)?
+    };
 
     match response {
         NetworkConnection::Response { value } => {
@@ -46,6 +132,47 @@ pub fn main() -> Result<()> {
             exit(1);
         }
         NetworkConnection::Ok => (),
+        NetworkConnection::Keys { keys, next_cursor } => {
+            for key in keys {
+                println!("{}", key);
+            }
+            if let Some(next_cursor) = next_cursor {
+                eprintln!("Next cursor: {}", next_cursor);
+            }
+        }
+        NetworkConnection::Ttl { seconds } => {
+            println!("{}", seconds);
+        }
+        NetworkConnection::Value { value } => match value {
+            Some(value) => println!("{}", value),
+            None => println!("{}", KvsError::KeyDoesNotExist),
+        },
+        NetworkConnection::NotModified => println!("Not modified"),
+        NetworkConnection::StrLen { len } => match len {
+            Some(len) => println!("{}", len),
+            None => println!("{}", KvsError::KeyDoesNotExist),
+        },
+        NetworkConnection::ModifiedAt { millis } => match millis {
+            Some(millis) => println!("{}", millis),
+            None => println!("{}", KvsError::KeyDoesNotExist),
+        },
+        NetworkConnection::Compacted { reclaimed } => {
+            println!("{}", reclaimed);
+        }
+        NetworkConnection::Values { values } => {
+            for value in values {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("{}", KvsError::KeyDoesNotExist),
+                }
+            }
+        }
+        NetworkConnection::Stats { stats } => {
+            println!("key_count: {}", stats.key_count);
+            println!("disk_bytes: {}", stats.disk_bytes);
+            println!("compacting: {}", stats.compacting);
+            println!("open_readers: {}", stats.open_readers);
+        }
         _ => {
             println!("Unexpected from server: {:?}", response);
             exit(1);
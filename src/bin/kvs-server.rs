@@ -1,20 +1,745 @@
-use clap::Parser;
-use kvs::{get_current_engine, log_engine};
-use kvs::{Commands, KvStore, KvsEngine, KvsError, NetworkConnection, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use kvs::GenerationFollower;
+use kvs::{configure_stream, get_current_engine, is_compatible_version, log_engine, ttl_seconds};
+use kvs::{db_key, key_in_db, set_buffer_pool_size, DEFAULT_BUFFER_POOL_SIZE};
+use kvs::{Commands, FlushPolicy, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError};
+use kvs::{EngineStats, NetworkConnection, Result, SledKvsEngine, StoreEvent};
 use slog::*;
-use std::ops::Deref;
 use std::{
-    net::{SocketAddr, TcpListener, TcpStream},
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 #[derive(Parser)]
-#[command(version, about, propagate_version = true)]
+#[command(
+    version,
+    about,
+    propagate_version = true,
+    after_help = "Options not given on the command line are read from KVS_<OPTION> \
+                  environment variables, e.g. KVS_ADDR or KVS_MAX_KEYS"
+)]
 struct Cli {
     #[arg(long, value_name = "ENGINE-NAME")]
     engine: Option<String>,
+    /// The directory holding the data and the engine marker
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    data_dir: PathBuf,
+    /// An address to listen on, by IP or host name, may be repeated
+    /// [default: 127.0.0.1:4000]
+    #[arg(long, value_name = "HOST:PORT")]
+    addr: Vec<String>,
+    /// Forward every successful write to the follower server at this address
     #[arg(long, value_name = "IP:PORT")]
-    addr: Option<String>,
+    replicate_to: Option<String>,
+    /// What to do with writes the follower could not receive
+    #[arg(long, value_enum, default_value_t = ReplicaDown::Log)]
+    replica_down: ReplicaDown,
+    /// Replay the sealed generation files of the primary server at this address
+    #[arg(long, value_name = "IP:PORT")]
+    follow: Option<String>,
+    /// How often to poll the primary for new generations
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    follow_interval: u64,
+    /// Limit the requests served to each client IP address per second
+    #[arg(long, value_name = "OPS-PER-SEC")]
+    rate_limit: Option<f64>,
+    /// Require clients to authenticate with this shared secret
+    ///
+    /// The token is also used to authenticate with the follower and the primary.
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+    /// Accept clients authenticating with TOKEN, confined to the keys of NAMESPACE
+    ///
+    /// Unlike --auth-token, a tenant token cannot select another namespace, nor none.
+    /// Repeat the option for every tenant.
+    #[arg(long = "tenant", value_name = "NAMESPACE=TOKEN")]
+    tenants: Vec<Tenant>,
+    /// Refuse requests from clients which did not select a namespace
+    #[arg(long)]
+    require_namespace: bool,
+    /// Accept admin commands such as `compact`
+    #[arg(long)]
+    allow_admin: bool,
+    /// Enable TCP keepalive on connections, probing after this many idle seconds
+    #[arg(long, value_name = "SECS")]
+    tcp_keepalive: Option<u64>,
+    /// Close connections sending a message larger than this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 16 * 1024 * 1024)]
+    max_message_size: u64,
+    /// Reject sets of values larger than this many bytes before they reach the store
+    #[arg(long, value_name = "BYTES")]
+    max_value_size: Option<u64>,
+    /// Close connections which send nothing for this many seconds
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+    /// Keep this many buffers of received messages for reuse, 0 to allocate every buffer
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BUFFER_POOL_SIZE)]
+    buffer_pool_size: usize,
+    /// When writes are synced to disk: `always`, `on-commit-batch`, `manual`
+    /// or `interval:<MILLIS>` [default: manual with kvs, always with sled]
+    ///
+    /// Sled only writes to disk when flushing, so with `manual` its writes are lost
+    /// if the server is killed, not only on a crash of the system.
+    #[arg(long, value_name = "POLICY")]
+    flush_policy: Option<FlushPolicy>,
+    /// Evict a key before a set of a new key would exceed this many keys
+    #[arg(long, value_name = "N")]
+    max_keys: Option<usize>,
+    /// Which key to evict once --max-keys is reached
+    #[arg(long, value_enum, default_value_t = Eviction::Lru)]
+    eviction: Eviction,
+    /// Fork into the background, detached from the terminal
+    #[arg(long, conflicts_with = "foreground")]
+    daemon: bool,
+    /// Stay attached to the terminal, the default
+    #[arg(long)]
+    foreground: bool,
+    /// Write the process id of the daemon to this file
+    #[arg(long, value_name = "FILE", requires = "daemon")]
+    pidfile: Option<PathBuf>,
+    /// Append the log of the daemon to this file instead of discarding it
+    ///
+    /// `.log` files in the data directory belong to the store, so the file must be
+    /// named or placed otherwise.
+    #[arg(long, value_name = "FILE", requires = "daemon")]
+    log_file: Option<PathBuf>,
+}
+
+/// What clients must do, and may do, on a connection
+struct Policy<'a> {
+    auth_token: Option<&'a str>,
+    tenants: &'a [Tenant],
+    require_namespace: bool,
+    allow_admin: bool,
+    max_message_size: u64,
+    max_value_size: Option<u64>,
+}
+
+/// A client token bound to the namespace it can reach
+#[derive(Clone)]
+struct Tenant {
+    namespace: String,
+    token: String,
+}
+
+impl FromStr for Tenant {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (namespace, token) = s
+            .split_once('=')
+            .ok_or_else(|| "expected NAMESPACE=TOKEN".to_string())?;
+        if namespace.is_empty() || namespace.contains(':') {
+            return Err(format!("invalid namespace: {}", namespace));
+        }
+        if token.is_empty() {
+            return Err("the token must not be empty".to_string());
+        }
+        Ok(Tenant {
+            namespace: namespace.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// What the primary does with writes a follower could not receive
+#[derive(Clone, Copy, ValueEnum)]
+enum ReplicaDown {
+    /// Log the failure and drop the write
+    Log,
+    /// Keep the write and retry it until the follower receives it, holding back the
+    /// writes after it
+    Buffer,
+}
+
+/// Which key the server evicts when it holds too many keys
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Eviction {
+    /// The least recently read or written key
+    Lru,
+    /// The least recently added key
+    Fifo,
+}
+
+/// The engine holding the data of the server
+enum Engine {
+    Kvs(Box<KvStore>),
+    Sled(SledKvsEngine),
+}
+
+/// The store served, through the engine it was opened with
+///
+/// Reads and writes go through `KvsEngine`. The log of a `KvStore` also records
+/// expiry and modification times and can be compacted and fetched by generation;
+/// sled keys never expire, have no known modification time, and the commands on
+/// generations are refused.
+struct Store {
+    engine: Engine,
+    // the keys of the databases other than 0, which the stats leave out
+    other_db_keys: u64,
+}
+
+impl Store {
+    fn new(engine: Engine) -> Result<Self> {
+        let mut store = Store {
+            engine,
+            other_db_keys: 0,
+        };
+        store.count_other_db_keys()?;
+        Ok(store)
+    }
+
+    /// Counts the keys of the databases other than 0 from scratch
+    fn count_other_db_keys(&mut self) -> Result<()> {
+        // the keys of other databases all start with NUL
+        let keys = self.keys_page("\0", None, usize::MAX)?;
+        self.other_db_keys = keys
+            .iter()
+            .filter(|key| key_in_db(0, key).is_none())
+            .count() as u64;
+        Ok(())
+    }
+
+    fn engine(&mut self) -> &mut dyn KvsEngine {
+        match &mut self.engine {
+            Engine::Kvs(store) => store.as_mut(),
+            Engine::Sled(engine) => engine,
+        }
+    }
+
+    /// Returns the `KvStore`, for the commands only its log supports
+    fn kvs(&mut self) -> Option<&mut KvStore> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Some(store),
+            Engine::Sled(_) => None,
+        }
+    }
+
+    fn contains(&mut self, key: &str) -> Result<bool> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Ok(store.modified_at(key).is_some()),
+            Engine::Sled(engine) => Ok(engine.get(key.to_owned())?.is_some()),
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine().get(key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let added = key_in_db(0, &key).is_none() && !self.contains(&key)?;
+        self.engine().set(key, value)?;
+        self.other_db_keys += u64::from(added);
+        Ok(())
+    }
+
+    /// Removes `key`, returning `KvsError::KeyDoesNotExist` if it is missing with
+    /// either engine
+    fn remove(&mut self, key: String) -> Result<()> {
+        let other_db = key_in_db(0, &key).is_none();
+        if (other_db || matches!(self.engine, Engine::Sled(_))) && !self.contains(&key)? {
+            return Err(KvsError::KeyDoesNotExist);
+        }
+        self.engine().remove(key)?;
+        self.other_db_keys -= u64::from(other_db);
+        Ok(())
+    }
+
+    /// Returns up to `limit` keys starting with `prefix` which come after `after`
+    ///
+    /// Sled keys are paged by scanning the whole prefix.
+    fn keys_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Ok(store.keys_page(prefix, after, limit)),
+            Engine::Sled(_) => {
+                let pairs = self.scan_page(prefix, after, limit)?;
+                Ok(pairs.into_iter().map(|(key, _)| key).collect())
+            }
+        }
+    }
+
+    /// Returns up to `limit` pairs whose keys start with `prefix` and come after `after`
+    fn scan_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => store.scan_page(prefix, after, limit),
+            Engine::Sled(engine) => Ok(engine
+                .scan(prefix)?
+                .into_iter()
+                .filter(|(key, _)| after.is_none_or(|after| key.as_str() > after))
+                .take(limit)
+                .collect()),
+        }
+    }
+
+    fn ttl(&mut self, key: &str) -> Result<Option<Option<Duration>>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Ok(store.ttl(key)),
+            Engine::Sled(_) => Ok(self.contains(key)?.then_some(None)),
+        }
+    }
+
+    /// Returns when `key` was last written, 0 if unknown, or `None` if it is missing
+    fn modified_at(&mut self, key: &str) -> Result<Option<u64>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Ok(store.modified_at(key)),
+            Engine::Sled(_) => Ok(self.contains(key)?.then_some(0)),
+        }
+    }
+
+    fn value_len(&mut self, key: &str) -> Result<Option<u64>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => Ok(store.value_len(key)),
+            Engine::Sled(engine) => {
+                let value = engine.get(key.to_owned())?;
+                Ok(value.map(|value| value.len() as u64))
+            }
+        }
+    }
+
+    /// See `KvStore::get_if_modified_since`; sled values are always returned
+    fn get_if_modified_since(&mut self, key: String, since: u64) -> Result<Option<Option<String>>> {
+        match &mut self.engine {
+            Engine::Kvs(store) => store.get_if_modified_since(key, since),
+            Engine::Sled(engine) => engine.get(key).map(Some),
+        }
+    }
+
+    /// Returns the stats of the engine, counting the keys of database 0 only
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut stats = self.engine().stats()?;
+        stats.key_count = stats.key_count.saturating_sub(self.other_db_keys);
+        Ok(stats)
+    }
+}
+
+/// Bounds the number of keys of the store by evicting keys before sets of new keys
+///
+/// Keys present when the server starts are considered accessed in key order.
+struct KeyLimit {
+    max_keys: usize,
+    eviction: Eviction,
+    next_tick: u64,
+    // the tick of the last access of each key, and the keys by tick
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+}
+
+impl KeyLimit {
+    fn new(max_keys: usize, eviction: Eviction, keys: Vec<String>) -> Self {
+        let mut limit = KeyLimit {
+            max_keys,
+            eviction,
+            next_tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        };
+        for key in keys {
+            limit.touch(&key);
+        }
+        limit
+    }
+
+    /// Records an access of `key`, which only counts for a new key with FIFO eviction
+    fn touch(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.get(key).copied() {
+            if self.eviction == Eviction::Fifo {
+                return;
+            }
+            self.order.remove(&tick);
+        }
+        self.ticks.insert(key.to_string(), self.next_tick);
+        self.order.insert(self.next_tick, key.to_string());
+        self.next_tick += 1;
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// Removes keys from the store until setting `key` keeps within the limit
+    ///
+    /// Returns the evicted keys
+    fn make_room(&mut self, key: &str, store: &mut Store) -> Result<Vec<String>> {
+        let mut evicted = Vec::new();
+        if self.ticks.contains_key(key) {
+            return Ok(evicted);
+        }
+        while self.ticks.len() >= self.max_keys {
+            let Some((_, victim)) = self.order.pop_first() else {
+                break;
+            };
+            self.ticks.remove(&victim);
+            match store.remove(victim.clone()) {
+                Ok(()) | Err(KvsError::KeyDoesNotExist) => evicted.push(victim),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Tracks the keys accessed by a command which succeeded
+    ///
+    /// Every key of the store is tracked, so reads of other keys are misses.
+    fn record(&mut self, command: &Commands) {
+        let mut read = |key: &str| {
+            if self.ticks.contains_key(key) {
+                self.touch(key);
+            }
+        };
+        match command {
+            Commands::Get { key } | Commands::GetIfModifiedSince { key, .. } => read(key),
+            Commands::SnapshotGet { keys } => keys.iter().for_each(|key| read(key)),
+            Commands::Set { key, .. } | Commands::SetNoReply { key, .. } => self.touch(key),
+            Commands::Rm { key } => self.forget(key),
+            Commands::Ping
+            | Commands::Keys { .. }
+            | Commands::Ttl { .. }
+            | Commands::ModifiedAt { .. }
+            | Commands::StrLen { .. }
+            | Commands::Scan { .. }
+            | Commands::Compact
+            | Commands::Stats
+            | Commands::FetchGen { .. }
+            | Commands::Select { .. }
+            | Commands::FlushDb => (),
+        }
+    }
+}
+
+/// The number of writes waiting for the follower beyond which writes are dropped
+const REPLICA_QUEUE_LEN: usize = 1024;
+/// How long to wait before sending buffered writes to a follower which was down again
+const REPLICA_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Forwards successful writes to a follower server
+///
+/// Writes are queued for a background thread sending them to the follower, so the
+/// primary never waits on it. When `REPLICA_QUEUE_LEN` writes are already waiting,
+/// which only happens while the follower is slow or down, further writes are dropped
+/// with a warning instead of holding up the primary.
+struct Replicator {
+    addr: SocketAddr,
+    queue: Option<mpsc::SyncSender<Commands>>,
+    forwarder: Option<thread::JoinHandle<()>>,
+}
+
+impl Replicator {
+    fn new(
+        addr: SocketAddr,
+        auth_token: Option<String>,
+        on_down: ReplicaDown,
+        log: &Logger,
+    ) -> Self {
+        let (queue, writes) = mpsc::sync_channel(REPLICA_QUEUE_LEN);
+        let forwarder = Forwarder {
+            addr,
+            auth_token,
+            on_down,
+            log: log.clone(),
+        };
+        Replicator {
+            addr,
+            queue: Some(queue),
+            forwarder: Some(thread::spawn(move || forwarder.run(writes))),
+        }
+    }
+
+    /// Queues `command` for the follower, dropping it if the queue is full
+    fn replicate(&mut self, command: Commands, log: &Logger) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if let Err(mpsc::TrySendError::Full(_)) = queue.try_send(command) {
+            warn!(log, "Dropped a write, too many are waiting for the follower";
+                "Follower Address" => self.addr);
+        }
+    }
+
+    /// Waits for the queued writes to be sent, or dropped if the follower is down
+    fn finish(&mut self) {
+        self.queue = None;
+        if let Some(forwarder) = self.forwarder.take() {
+            let _ = forwarder.join();
+        }
+    }
+}
+
+/// Sends the writes queued by a `Replicator` to the follower
+struct Forwarder {
+    addr: SocketAddr,
+    auth_token: Option<String>,
+    on_down: ReplicaDown,
+    log: Logger,
+}
+
+impl Forwarder {
+    /// Sends the queued writes until the `Replicator` is gone, taking every write
+    /// queued by then along with the first one
+    fn run(self, writes: mpsc::Receiver<Commands>) {
+        while let Ok(first) = writes.recv() {
+            let mut batch = vec![first];
+            batch.extend(writes.try_iter());
+            let mut retrying = false;
+            while let Err(err) = self.send(&batch) {
+                let drop =
+                    matches!(self.on_down, ReplicaDown::Log) || SHUTDOWN.load(Ordering::SeqCst);
+                if !retrying || drop {
+                    warn!(self.log, "Could not replicate to follower";
+                        "Follower Address" => self.addr, "Error" => err.to_string(),
+                        "Writes" => batch.len(), "Dropped" => drop);
+                }
+                if drop {
+                    break;
+                }
+                retrying = true;
+                thread::sleep(REPLICA_RETRY_DELAY);
+            }
+        }
+    }
+
+    /// Sends `writes` in order
+    ///
+    /// The follower serves one connection at a time, so the connection is only held
+    /// for a batch: consecutive sets are streamed without replies, and confirmed by
+    /// the reply to the next remove or to a final ping. A remove ends the connection,
+    /// the writes after it go on a new one. Sending a batch again after a failure
+    /// leaves the follower as if it was sent once, since it repeats the writes in order.
+    fn send(&self, writes: &[Commands]) -> Result<()> {
+        let mut writes = writes.iter().peekable();
+        while writes.peek().is_some() {
+            let mut client = KvsClient::connect(self.addr)?;
+            // a new connection would not confirm the sets sent on this one
+            client.set_max_retries(0);
+            if let Some(token) = &self.auth_token {
+                client.authenticate(token)?;
+            }
+            let last = loop {
+                match writes.next() {
+                    Some(Commands::Set { key, value }) => {
+                        client.set_no_reply(key.clone(), value.clone())?;
+                    }
+                    Some(write) => break write.clone(),
+                    None => break Commands::Ping,
+                }
+            };
+            if let NetworkConnection::Error { error } = client.request(last)? {
+                warn!(self.log, "Follower rejected a replicated write"; "Error" => error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Token buckets limiting the request rate of every client IP address
+///
+/// A bucket holds up to one second worth of requests and refills continuously.
+struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, returning whether one was available
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let (tokens, last) = self.buckets.entry(ip).or_insert((self.rate, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The number of pairs a scan reads from the store at a time
+const SCAN_PAGE_SIZE: usize = 256;
+
+/// Set once SIGINT or SIGTERM is received
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Stops the server on SIGINT and SIGTERM once the request in progress is handled
+///
+/// A blocked `accept` is not interrupted by the signal, so a thread watching for
+/// the shutdown wakes it up with a connection of its own.
+fn handle_shutdown_signals(addr: SocketAddr) {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    thread::spawn(move || {
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = TcpStream::connect(addr);
+    });
+}
+
+/// The pipe through which a daemon reports its startup to the process it forked from
+struct Startup(File);
+
+// the first byte of the report of a daemon which started serving
+const STARTED: u8 = b'+';
+
+impl Startup {
+    /// Lets the parent exit successfully once the server is ready to serve
+    fn started(self) -> Result<()> {
+        (&self.0).write_all(&[STARTED])?;
+        Ok(())
+    }
+
+    /// Hands `error` to the parent, which prints it and exits unsuccessfully
+    fn failed(self, error: &str) {
+        let _ = (&self.0).write_all(format!("-{}", error).as_bytes());
+    }
+}
+
+/// Forks the server into the background
+///
+/// The child starts a new session with its standard output and error redirected to
+/// `log_file`, or discarded, and reports through the returned [`Startup`] whether it
+/// opened the store and bound its addresses. The parent waits for that report, then
+/// writes the process id of the child to `pidfile` and exits, or prints the error of
+/// the child and exits unsuccessfully. It must run before any thread is spawned.
+fn daemonize(pidfile: Option<&Path>, log_file: Option<&Path>) -> Result<Startup> {
+    // files are opened first so that errors still reach the terminal
+    let log = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: the descriptors were just opened and are owned by nothing else
+    let (mut report, reporter) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: the process is still single-threaded
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().into()),
+        0 => drop(report),
+        pid => {
+            // the report ends once the child dropped its end, started or not
+            drop(reporter);
+            let mut outcome = Vec::new();
+            report.read_to_end(&mut outcome)?;
+            match outcome.split_first() {
+                Some((&STARTED, _)) => {}
+                Some((_, error)) => {
+                    eprintln!("Error: {}", String::from_utf8_lossy(error));
+                    exit(1);
+                }
+                None => {
+                    eprintln!("Error: the daemon exited during startup");
+                    exit(1);
+                }
+            }
+            if let Some(pidfile) = pidfile {
+                fs::write(pidfile, format!("{}\n", pid))?;
+            }
+            exit(0);
+        }
+    }
+
+    // SAFETY: the descriptors are open for the duration of the calls
+    unsafe {
+        if libc::setsid() == -1
+            || libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(Startup(reporter))
+}
+
+fn open_store(cli: &Cli, log: &Logger) -> Result<(String, Store)> {
+    let current_engine = get_current_engine(&cli.data_dir)?;
+    let engine_name = match cli.engine.as_deref() {
+        Some(eng_name) => {
+            // check if current eng type is the same as eng name
+            if current_engine.is_some_and(|v| v != eng_name) {
+                return Err(KvsError::WrongEngineType(eng_name.to_string()));
+            }
+            eng_name.to_string()
+        }
+        None => current_engine.unwrap_or_else(|| "kvs".to_string()),
+    };
+    log_engine(&cli.data_dir, engine_name.clone())?;
+    if engine_name == "sled" {
+        let flush_policy = cli.flush_policy.unwrap_or(FlushPolicy::Always);
+        let engine = SledKvsEngine::open_with_flush_policy(&cli.data_dir, flush_policy)?;
+        return Ok((engine_name, Store::new(Engine::Sled(engine))?));
+    }
+    if engine_name != "kvs" {
+        return Err(KvsError::UnknownEngineType(engine_name));
+    }
+
+    let event_log = log.clone();
+    let options = KvStoreOptions::new()
+        .flush_policy(cli.flush_policy.unwrap_or(FlushPolicy::Manual))
+        .on_event(move |event| match event {
+            StoreEvent::ClockMovedBackward { by } => {
+                warn!(event_log, "System clock moved backward, key expiry may be off";
+                        "Seconds" => by.as_secs_f64());
+            }
+            StoreEvent::ReadersNearFdLimit { open, limit } => {
+                warn!(event_log, "Open generation files are near the open file limit";
+                        "Open" => open, "Limit" => limit);
+            }
+            StoreEvent::UnknownFile { path } => {
+                warn!(event_log, "Unknown file in the data directory left in place";
+                        "Path" => %path.display());
+            }
+            _ => {}
+        });
+    let store = KvStore::open_with_options(&cli.data_dir, options)?;
+    Ok((engine_name, Store::new(Engine::Kvs(Box::new(store)))?))
 }
 
 fn setup_logging() -> Logger {
@@ -25,108 +750,850 @@ fn setup_logging() -> Logger {
     slog::Logger::root(drain, o!())
 }
 
-pub fn main() -> Result<()> {
-    let cli: Cli = Cli::parse();
+pub fn main() {
+    let mut startup = None;
+    if let Err(err) = run(&mut startup) {
+        eprintln!("Error: {}", err);
+        if let Some(startup) = startup {
+            startup.failed(&err.to_string());
+        }
+        exit(1);
+    }
+}
+
+/// Reads the environment variable `name` as a `T`, if it is set
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| KvsError::Config(format!("invalid {} {:?}: {}", name, value, err))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            Err(KvsError::Config(format!("{} is not valid unicode", name)))
+        }
+    }
+}
+
+/// Fills the options not given on the command line from `KVS_*` environment variables
+///
+/// Flags take precedence over the environment, which takes precedence over the
+/// defaults. `KVS_ADDR` holds a comma-separated list of addresses, `KVS_TENANTS` one
+/// of tenants.
+fn apply_env(cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+    let from_flag = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    macro_rules! from_env {
+        ($field:ident, $name:literal) => {
+            if !from_flag(stringify!($field)) {
+                if let Some(value) = env_var($name)? {
+                    cli.$field = value;
+                }
+            }
+        };
+        (Some $field:ident, $name:literal) => {
+            if !from_flag(stringify!($field)) {
+                if let Some(value) = env_var($name)? {
+                    cli.$field = Some(value);
+                }
+            }
+        };
+    }
+
+    if !from_flag("addr") {
+        if let Some(addrs) = env_var::<String>("KVS_ADDR")? {
+            cli.addr = addrs
+                .split(',')
+                .map(|addr| addr.trim().to_owned())
+                .collect();
+            for addr in &cli.addr {
+                resolve_addr("KVS_ADDR", addr)?;
+            }
+        }
+    }
+    if !from_flag("tenants") {
+        if let Some(tenants) = env_var::<String>("KVS_TENANTS")? {
+            cli.tenants = tenants
+                .split(',')
+                .map(|tenant| {
+                    tenant.trim().parse().map_err(|err| {
+                        KvsError::Config(format!("invalid KVS_TENANTS {:?}: {}", tenants, err))
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+    }
+    from_env!(Some engine, "KVS_ENGINE");
+    from_env!(data_dir, "KVS_DATA_DIR");
+    from_env!(Some replicate_to, "KVS_REPLICATE_TO");
+    from_env!(Some follow, "KVS_FOLLOW");
+    from_env!(follow_interval, "KVS_FOLLOW_INTERVAL");
+    from_env!(Some rate_limit, "KVS_RATE_LIMIT");
+    from_env!(Some auth_token, "KVS_AUTH_TOKEN");
+    from_env!(require_namespace, "KVS_REQUIRE_NAMESPACE");
+    from_env!(allow_admin, "KVS_ALLOW_ADMIN");
+    from_env!(Some tcp_keepalive, "KVS_TCP_KEEPALIVE");
+    from_env!(max_message_size, "KVS_MAX_MESSAGE_SIZE");
+    from_env!(Some max_value_size, "KVS_MAX_VALUE_SIZE");
+    from_env!(Some idle_timeout, "KVS_IDLE_TIMEOUT");
+    from_env!(buffer_pool_size, "KVS_BUFFER_POOL_SIZE");
+    from_env!(Some flush_policy, "KVS_FLUSH_POLICY");
+    from_env!(Some max_keys, "KVS_MAX_KEYS");
+    Ok(())
+}
+
+/// Resolves the address given by `option`, which may name the host instead of its IP
+///
+/// A host name resolving to several addresses stands for the first of them.
+fn resolve_addr(option: &str, addr: &str) -> Result<SocketAddr> {
+    let error = |reason: String| {
+        KvsError::Config(format!(
+            "could not resolve {} {:?}: {}",
+            option, addr, reason
+        ))
+    };
+    addr.to_socket_addrs()
+        .map_err(|err| error(err.to_string()))?
+        .next()
+        .ok_or_else(|| error("no address found".to_string()))
+}
+
+/// Checks the options whose values clap cannot validate on its own
+fn check_config(cli: &Cli) -> Result<()> {
+    if let Some(engine) = cli.engine.as_deref() {
+        if !matches!(engine, "kvs" | "sled") {
+            let error = format!("unknown engine {:?}, expected kvs or sled", engine);
+            return Err(KvsError::Config(error));
+        }
+    }
+    // a bucket holding less than one token never allows a request
+    if let Some(rate) = cli.rate_limit {
+        if !(rate >= 1.0 && rate.is_finite()) {
+            let error = format!("--rate-limit must be at least 1, got {}", rate);
+            return Err(KvsError::Config(error));
+        }
+    }
+    if cli.max_keys == Some(0) {
+        return Err(KvsError::Config("--max-keys must not be zero".to_string()));
+    }
+    // a token shared by two tenants, or with --auth-token, would grant either access
+    let mut tokens: Vec<&str> = cli.tenants.iter().map(|t| t.token.as_str()).collect();
+    tokens.extend(cli.auth_token.as_deref());
+    tokens.sort_unstable();
+    if tokens.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(KvsError::Config(
+            "--tenant tokens must be unique".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the server, reporting through `startup` once it is ready if it was daemonized
+fn run(startup: &mut Option<Startup>) -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    apply_env(&mut cli, &matches)?;
+    check_config(&cli)?;
+    if cli.daemon {
+        *startup = Some(daemonize(cli.pidfile.as_deref(), cli.log_file.as_deref())?);
+    }
 
     // set up logging
     let log = setup_logging();
     info!(log, "Server Startup"; "Server Version Number" => env!("CARGO_PKG_VERSION"));
 
-    let mut ip_port: SocketAddr = "127.0.0.1:4000".parse()?;
-    let mut engine_name = String::from("");
-
-    if let Some(ipaddr) = cli.addr.as_deref() {
-        ip_port = ipaddr.parse()?;
+    let mut addrs = cli
+        .addr
+        .iter()
+        .map(|addr| resolve_addr("--addr", addr))
+        .collect::<Result<Vec<_>>>()?;
+    if addrs.is_empty() {
+        addrs.push("127.0.0.1:4000".parse()?);
     }
 
-    match cli.engine.as_deref() {
-        Some(eng_name) => match eng_name {
-            "kvs" | "sled" => { 
-                println!("{}", eng_name);
-                if get_current_engine(Path::new("."))?.is_some_and(|v| v != eng_name.to_string()) {
-                    return Err(KvsError::WrongEngineType(eng_name.to_string()));
-                }
-                // check if current eng type is the same as eng name
-                engine_name = eng_name.into();
-                log_engine(Path::new("."), engine_name.clone())?;
+    if let Some(eng_name) = cli.engine.as_deref() {
+        println!("{}", eng_name);
+    }
+    let (engine_name, mut store) = match open_store(&cli, &log) {
+        Ok(opened) => opened,
+        Err(err) => {
+            error!(log, "Could not open the store"; "Error" => err.to_string());
+            let error = format!(
+                "could not open the store in {}: {}",
+                cli.data_dir.display(),
+                err
+            );
+            eprintln!("Error: {}", error);
+            if let Some(startup) = startup.take() {
+                startup.failed(&error);
             }
-            _ => return Err(KvsError::UnknownEngineType(eng_name.to_string())),
-        },
-        None => {
-            engine_name = get_current_engine(Path::new("."))?.map_or("kvs".to_string(), |v| v);
-            log_engine(Path::new("."), engine_name.clone())?;
+            exit(1);
+        }
+    };
+
+    let mut replicator = match cli.replicate_to.as_deref() {
+        Some(follower) => Some(Replicator::new(
+            resolve_addr("--replicate-to", follower)?,
+            cli.auth_token.clone(),
+            cli.replica_down,
+            &log,
+        )),
+        None => None,
+    };
+
+    let mut rate_limiter = cli.rate_limit.map(RateLimiter::new);
+    let mut key_limit = match cli.max_keys {
+        Some(max_keys) => {
+            let keys = store.keys_page("", None, usize::MAX)?;
+            Some(KeyLimit::new(max_keys, cli.eviction, keys))
         }
+        None => None,
+    };
+
+    let compaction_status = store.kvs().map(|store| store.compaction_status());
+    if cli.follow.is_some() && store.kvs().is_none() {
+        return Err(KvsError::Config(
+            "--follow needs the kvs engine".to_string(),
+        ));
     }
+    let store = Arc::new(Mutex::new(store));
 
-    // Open store
-    let mut store: KvStore = KvStore::open(Path::new(".")).unwrap();
+    if let Some(primary) = cli.follow.as_deref() {
+        let mut follower = GenerationFollower::new(resolve_addr("--follow", primary)?);
+        if let Some(token) = &cli.auth_token {
+            follower = follower.auth_token(token.clone());
+        }
+        let interval = Duration::from_secs(cli.follow_interval);
+        let store = Arc::clone(&store);
+        let log = log.clone();
+        info!(log, "Following primary"; "Primary Address" => primary);
+        thread::spawn(move || loop {
+            let mut store = store.lock().unwrap();
+            let replayed = follower.poll(store.kvs().expect("checked to be a KvStore"));
+            // the generations may hold keys of any database
+            if let Err(err) = replayed.and_then(|_| store.count_other_db_keys()) {
+                warn!(log, "Could not fetch generations from primary"; "Error" => err.to_string());
+            }
+            drop(store);
+            thread::sleep(interval);
+        });
+    }
 
-    info!(log, "Received Configuration"; "Engine name" => engine_name, "Ip Address and Port" => ip_port);
-    let listener = TcpListener::bind(ip_port)?;
+    info!(log, "Received Configuration"; "Engine name" => engine_name);
+    if let Some(replicator) = &replicator {
+        info!(log, "Replicating writes"; "Follower Address" => replicator.addr);
+    }
+    let policy = Policy {
+        auth_token: cli.auth_token.as_deref(),
+        tenants: &cli.tenants,
+        require_namespace: cli.require_namespace,
+        allow_admin: cli.allow_admin,
+        max_message_size: cli.max_message_size,
+        max_value_size: cli.max_value_size,
+    };
+    let listeners = addrs
+        .iter()
+        .map(TcpListener::bind)
+        .collect::<io::Result<Vec<_>>>()?;
+    if let Some(startup) = startup.take() {
+        startup.started()?;
+    }
+    // the port may have been picked by the system
+    let shutdown_addr = listeners[0].local_addr()?;
+    // every listener feeds its connections to the single loop serving them
+    let (connections, incoming) = mpsc::channel();
+    for listener in listeners {
+        info!(log, "Listening"; "Ip Address and Port" => listener.local_addr()?);
+        let connections = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if connections.send(stream).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(connections);
+    handle_shutdown_signals(shutdown_addr);
+    set_buffer_pool_size(cli.buffer_pool_size);
+    let keepalive = cli.tcp_keepalive.map(Duration::from_secs);
+    let idle_timeout = cli.idle_timeout.map(Duration::from_secs);
 
-    for stream in listener.incoming() {
+    for stream in incoming {
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            break;
+        }
         info!(log, "Received a Connection");
-        handle_request(stream?, &mut store, &log)?;
+        let stream = stream?;
+        if let Err(err) = configure_stream(&stream, true, keepalive)
+            .and_then(|()| Ok(stream.set_read_timeout(idle_timeout)?))
+        {
+            warn!(log, "Could not configure the connection"; "Error" => err.to_string());
+        }
+        // a failing connection is closed without stopping the server
+        if let Err(err) = handle_request(
+            stream,
+            &mut store.lock().unwrap(),
+            &mut replicator,
+            &mut rate_limiter,
+            &mut key_limit,
+            &policy,
+            &log,
+        ) {
+            match err {
+                KvsError::Io(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    info!(log, "Closed an idle connection");
+                }
+                err => warn!(log, "Closed a connection"; "Error" => err.to_string()),
+            }
+        }
     }
 
+    info!(log, "Shutting down");
+    // the lock is only granted once a compaction in progress has finished
+    if compaction_status.is_some_and(|status| status.is_compacting()) {
+        info!(log, "Waiting for the compaction in progress");
+    }
+    store.lock().unwrap().engine().flush()?;
+    if let Some(replicator) = replicator.as_mut() {
+        replicator.finish();
+    }
+    if let Some(pidfile) = &cli.pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
     Ok(())
 }
 
-fn handle_request(mut stream: TcpStream, store: &mut KvStore, log: &Logger) -> Result<()> {
-    let buf = NetworkConnection::receive_network_message(&mut stream)?;
+fn handle_request(
+    mut stream: TcpStream,
+    store: &mut Store,
+    replicator: &mut Option<Replicator>,
+    rate_limiter: &mut Option<RateLimiter>,
+    key_limit: &mut Option<KeyLimit>,
+    policy: &Policy,
+    log: &Logger,
+) -> Result<()> {
+    let buf =
+        NetworkConnection::receive_network_message_limited(&mut stream, policy.max_message_size)?;
 
-    let message = NetworkConnection::deserialize_message(buf)?;
+    let mut message = NetworkConnection::deserialize_message(buf)?;
 
-    info!(log, "Parsing a network message");
-    if let NetworkConnection::Request { command } = message {
-        match command {
-            Commands::Get { key } => {
-                let value = store.get(key);
-                match value {
-                    Ok(val) => match val {
-                        Some(val) => NetworkConnection::send_network_message(
-                            NetworkConnection::Response { value: val },
-                            &mut stream,
-                        )?,
-                        None => NetworkConnection::send_network_message(
-                            NetworkConnection::Response {
-                                value: KvsError::KeyDoesNotExist.to_string(),
-                            },
-                            &mut stream,
-                        )?,
+    // Clients announce their version before sending the request
+    if let NetworkConnection::Handshake { version } = message {
+        if !is_compatible_version(env!("CARGO_PKG_VERSION"), &version) {
+            warn!(log, "Incompatible client version"; "Client Version Number" => version);
+        }
+        NetworkConnection::send_network_message(
+            NetworkConnection::Handshake {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            &mut stream,
+        )?;
+        let buf = NetworkConnection::receive_network_message_limited(
+            &mut stream,
+            policy.max_message_size,
+        )?;
+        message = NetworkConnection::deserialize_message(buf)?;
+    }
+
+    // With a token configured, clients must authenticate before their request.
+    // A tenant token binds the connection to the namespace of the tenant.
+    let mut authenticated = policy.auth_token.is_none() && policy.tenants.is_empty();
+    let mut bound_namespace = None;
+    if let NetworkConnection::Auth { token } = message {
+        if let Some(auth_token) = policy.auth_token {
+            authenticated = constant_time_eq(token.as_bytes(), auth_token.as_bytes());
+        }
+        for tenant in policy.tenants {
+            if constant_time_eq(token.as_bytes(), tenant.token.as_bytes()) {
+                authenticated = true;
+                bound_namespace = Some(tenant.namespace.clone());
+            }
+        }
+        if !authenticated {
+            warn!(log, "Rejected an invalid auth token");
+            NetworkConnection::send_network_message(
+                NetworkConnection::Error {
+                    error: "invalid auth token".to_string(),
+                },
+                &mut stream,
+            )?;
+            return Ok(());
+        }
+        NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)?;
+        let buf = NetworkConnection::receive_network_message_limited(
+            &mut stream,
+            policy.max_message_size,
+        )?;
+        message = NetworkConnection::deserialize_message(buf)?;
+    }
+    if !authenticated {
+        NetworkConnection::send_network_message(
+            NetworkConnection::Error {
+                error: "authentication required".to_string(),
+            },
+            &mut stream,
+        )?;
+        return Ok(());
+    }
+
+    // A namespaced connection can only reach the keys prefixed with its namespace
+    let mut namespace = bound_namespace.clone();
+    if let NetworkConnection::Namespace { namespace: name } = message {
+        if name.is_empty() || name.contains(':') {
+            NetworkConnection::send_network_message(
+                NetworkConnection::Error {
+                    error: format!("invalid namespace: {}", name),
+                },
+                &mut stream,
+            )?;
+            return Ok(());
+        }
+        if bound_namespace.as_ref().is_some_and(|bound| *bound != name) {
+            warn!(log, "Rejected a namespace outside of the tenant"; "Namespace" => &name);
+            NetworkConnection::send_network_message(
+                NetworkConnection::Error {
+                    error: format!("namespace not allowed for this token: {}", name),
+                },
+                &mut stream,
+            )?;
+            return Ok(());
+        }
+        namespace = Some(name);
+        NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)?;
+        let buf = NetworkConnection::receive_network_message_limited(
+            &mut stream,
+            policy.max_message_size,
+        )?;
+        message = NetworkConnection::deserialize_message(buf)?;
+    }
+    if policy.require_namespace && namespace.is_none() {
+        NetworkConnection::send_network_message(
+            NetworkConnection::Error {
+                error: "namespace required".to_string(),
+            },
+            &mut stream,
+        )?;
+        return Ok(());
+    }
+
+    // requests without a reply are followed by more requests, up to one with a reply
+    let mut keyspace = KeySpace { namespace, db: 0 };
+    loop {
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            let ip = stream.peer_addr()?.ip();
+            if !rate_limiter.allow(ip) {
+                warn!(log, "Rate limited a request"; "Client Address" => ip.to_string());
+                NetworkConnection::send_network_message(
+                    NetworkConnection::Error {
+                        error: "rate limited".to_string(),
                     },
-                    Err(err) => NetworkConnection::send_network_message(
-                        NetworkConnection::Error {
-                            error: err.to_string(),
-                        },
-                        &mut stream,
-                    )?,
-                }
+                    &mut stream,
+                )?;
+                return Ok(());
             }
-            Commands::Set { key, value } => {
-                if let Err(err) = store.set(key, value) {
-                    NetworkConnection::send_network_message(
-                        NetworkConnection::Error {
-                            error: err.to_string(),
-                        },
-                        &mut stream,
-                    )?
-                }
-                NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)?
-            }
-            Commands::Rm { key } => {
-                if let Err(err) = store.remove(key) {
-                    NetworkConnection::send_network_message(
-                        NetworkConnection::Error {
-                            error: err.to_string(),
-                        },
-                        &mut stream,
-                    )?
-                }
-                NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)?
+        }
+
+        info!(log, "Parsing a network message");
+        let (command, timed) = match message {
+            NetworkConnection::Request { command } => (command, false),
+            NetworkConnection::TimedRequest { command } => (command, true),
+            // Drop any other network command type sent to server silently
+            _ => return Ok(()),
+        };
+        // a selected database applies to the following requests of the connection
+        if let Commands::Select { db } = command {
+            keyspace.db = db;
+            NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)?;
+            let buf = NetworkConnection::receive_network_message_limited(
+                &mut stream,
+                policy.max_message_size,
+            )?;
+            message = NetworkConnection::deserialize_message(buf)?;
+            continue;
+        }
+        let command = match keyspace.confine(command) {
+            Some(command) => command,
+            None => {
+                NetworkConnection::send_network_message(
+                    NetworkConnection::Error {
+                        error: "not available within a namespace or database".to_string(),
+                    },
+                    &mut stream,
+                )?;
+                return Ok(());
             }
+        };
+        if let Commands::Scan { prefix } = &command {
+            return stream_scan(&mut stream, store, prefix, &keyspace);
+        }
+        if let Commands::FlushDb = command {
+            let response = flush_db(store, &keyspace, policy, replicator, key_limit, log);
+            return NetworkConnection::send_network_message(response, &mut stream);
         }
-    } // Drop any other network command type sent to server silently
+        let write = match &command {
+            Commands::Set { .. } | Commands::Rm { .. } => Some(command.clone()),
+            Commands::SetNoReply { key, value } => Some(Commands::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            _ => None,
+        };
+        let no_reply = matches!(command, Commands::SetNoReply { .. });
+        let oversized = check_value_size(&command, policy);
 
-    Ok(())
+        let start = Instant::now();
+        let mut evicted = Vec::new();
+        let mut tracked = None;
+        if let (Some(key_limit), None) = (key_limit.as_mut(), &oversized) {
+            if let Commands::Set { key, .. } | Commands::SetNoReply { key, .. } = &command {
+                evicted = key_limit.make_room(key, store)?;
+            }
+            tracked = Some(command.clone());
+        }
+        let response = match oversized {
+            Some(response) => {
+                warn!(log, "Rejected an oversized value");
+                response
+            }
+            None => execute(command, store, policy, &keyspace),
+        };
+        let server_time_micros = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+
+        let succeeded = matches!(response, NetworkConnection::Ok);
+        if let (Some(key_limit), Some(command)) = (key_limit.as_mut(), tracked) {
+            if !matches!(response, NetworkConnection::Error { .. }) {
+                key_limit.record(&command);
+            }
+        }
+        for key in evicted {
+            info!(log, "Evicted a key"; "Key" => &key);
+            if let Some(replicator) = replicator.as_mut() {
+                replicator.replicate(Commands::Rm { key }, log);
+            }
+        }
+        if no_reply {
+            if let NetworkConnection::Error { error } = response {
+                warn!(log, "Could not apply a set without reply"; "Error" => error);
+            }
+        } else {
+            if timed {
+                NetworkConnection::send_network_message(
+                    NetworkConnection::Timing { server_time_micros },
+                    &mut stream,
+                )?;
+            }
+            NetworkConnection::send_network_message(response, &mut stream)?;
+        }
+
+        if let (Some(replicator), Some(write), true) = (replicator.as_mut(), write, succeeded) {
+            replicator.replicate(write, log);
+        }
+
+        if !no_reply {
+            return Ok(());
+        }
+        let buf = NetworkConnection::receive_network_message_limited(
+            &mut stream,
+            policy.max_message_size,
+        )?;
+        message = NetworkConnection::deserialize_message(buf)?;
+    }
+}
+
+/// Streams the pairs whose keys start with `prefix` to the client, a page at a time
+///
+/// Only the keys of `keyspace` are streamed, as the client named them. Scans are not
+/// timed, since the response is not known in full before sending its first part.
+fn stream_scan(
+    stream: &mut TcpStream,
+    store: &mut Store,
+    prefix: &str,
+    keyspace: &KeySpace,
+) -> Result<()> {
+    let mut after = None;
+    loop {
+        let page = match store.scan_page(prefix, after.as_deref(), SCAN_PAGE_SIZE) {
+            Ok(page) => page,
+            Err(err) => {
+                let error = err.to_string();
+                return NetworkConnection::send_network_message(
+                    NetworkConnection::Error { error },
+                    stream,
+                );
+            }
+        };
+        let Some((last, _)) = page.last() else {
+            return NetworkConnection::send_network_message(NetworkConnection::ScanEnd, stream);
+        };
+        after = Some(last.clone());
+        for (key, value) in page {
+            let Some(key) = keyspace.decode(&key) else {
+                continue;
+            };
+            let key = key.to_string();
+            NetworkConnection::send_network_message(
+                NetworkConnection::ScanItem { key, value },
+                stream,
+            )?;
+        }
+    }
+}
+
+/// Removes every key of `keyspace`, replicating each removal
+///
+/// Outside of a namespace, the keys of the namespaces within the database cannot be
+/// told apart from its other keys and are removed too, so this is an admin command.
+fn flush_db(
+    store: &mut Store,
+    keyspace: &KeySpace,
+    policy: &Policy,
+    replicator: &mut Option<Replicator>,
+    key_limit: &mut Option<KeyLimit>,
+    log: &Logger,
+) -> NetworkConnection {
+    if keyspace.namespace.is_none() && !policy.allow_admin {
+        return NetworkConnection::Error {
+            error: "admin commands are disabled outside of a namespace".to_string(),
+        };
+    }
+    let keys = match store.keys_page(&keyspace.encode(""), None, usize::MAX) {
+        Ok(keys) => keys,
+        Err(err) => {
+            return NetworkConnection::Error {
+                error: err.to_string(),
+            }
+        }
+    };
+    for key in keys {
+        if keyspace.decode(&key).is_none() {
+            continue;
+        }
+        if let Err(err) = store.remove(key.clone()) {
+            return NetworkConnection::Error {
+                error: err.to_string(),
+            };
+        }
+        let removal = Commands::Rm { key };
+        if let Some(key_limit) = key_limit.as_mut() {
+            key_limit.record(&removal);
+        }
+        if let Some(replicator) = replicator.as_mut() {
+            replicator.replicate(removal, log);
+        }
+    }
+    NetworkConnection::Ok
+}
+
+/// Returns the error response for a set whose value exceeds `--max-value-size`
+///
+/// Whole messages are already bounded by `--max-message-size` while they are read,
+/// this bounds the values written to the store.
+fn check_value_size(command: &Commands, policy: &Policy) -> Option<NetworkConnection> {
+    let max_value_size = policy.max_value_size?;
+    let (Commands::Set { value, .. } | Commands::SetNoReply { value, .. }) = command else {
+        return None;
+    };
+    let size = value.len() as u64;
+    (size > max_value_size).then(|| NetworkConnection::Error {
+        error: format!(
+            "value of {} bytes exceeds the maximum value size of {} bytes",
+            size, max_value_size
+        ),
+    })
+}
+
+/// Runs `command` against the store and returns the response for the client
+fn execute(
+    command: Commands,
+    store: &mut Store,
+    policy: &Policy,
+    keyspace: &KeySpace,
+) -> NetworkConnection {
+    let error = |err: KvsError| NetworkConnection::Error {
+        error: err.to_string(),
+    };
+    // the commands on the log of a `KvStore`
+    let unsupported = || NetworkConnection::Error {
+        error: "not supported by the sled engine".to_string(),
+    };
+    match command {
+        Commands::Get { key } => match store.get(key) {
+            Ok(Some(value)) => NetworkConnection::Response { value },
+            Ok(None) => NetworkConnection::Response {
+                value: KvsError::KeyDoesNotExist.to_string(),
+            },
+            Err(err) => error(err),
+        },
+        // the store is locked for the whole request, so no write interleaves
+        Commands::SnapshotGet { keys } => match keys
+            .into_iter()
+            .map(|key| store.get(key))
+            .collect::<Result<_>>()
+        {
+            Ok(values) => NetworkConnection::Values { values },
+            Err(err) => error(err),
+        },
+        Commands::Set { key, value } | Commands::SetNoReply { key, value } => {
+            match store.set(key, value) {
+                Ok(()) => NetworkConnection::Ok,
+                Err(err) => error(err),
+            }
+        }
+        Commands::Ping => NetworkConnection::Ok,
+        // a connection only pages through the keys of its namespace and database
+        Commands::Keys { cursor, limit } => {
+            let prefix = keyspace.encode("");
+            let mut after = cursor.map(|cursor| keyspace.encode(&cursor));
+            let limit = limit.max(1);
+            let mut keys = Vec::new();
+            while keys.len() <= limit {
+                let page = match store.keys_page(&prefix, after.as_deref(), limit + 1) {
+                    Ok(page) => page,
+                    Err(err) => return error(err),
+                };
+                let Some(last) = page.last() else {
+                    break;
+                };
+                after = Some(last.clone());
+                keys.extend(
+                    page.iter()
+                        .filter_map(|key| keyspace.decode(key).map(str::to_owned)),
+                );
+            }
+            let more = keys.len() > limit;
+            keys.truncate(limit);
+            let next_cursor = keys.last().filter(|_| more).cloned();
+            NetworkConnection::Keys { keys, next_cursor }
+        }
+        Commands::Ttl { key } => match store.ttl(&key) {
+            Ok(ttl) => NetworkConnection::Ttl {
+                seconds: ttl_seconds(ttl),
+            },
+            Err(err) => error(err),
+        },
+        Commands::GetIfModifiedSince { key, since } => {
+            match store.get_if_modified_since(key, since) {
+                Ok(Some(value)) => NetworkConnection::Value { value },
+                Ok(None) => NetworkConnection::NotModified,
+                Err(err) => error(err),
+            }
+        }
+        Commands::StrLen { key } => match store.value_len(&key) {
+            Ok(len) => NetworkConnection::StrLen { len },
+            Err(err) => error(err),
+        },
+        Commands::ModifiedAt { key } => match store.modified_at(&key) {
+            Ok(millis) => NetworkConnection::ModifiedAt { millis },
+            Err(err) => error(err),
+        },
+        Commands::Scan { .. } => unreachable!("scans are streamed by handle_request"),
+        Commands::Select { .. } | Commands::FlushDb => {
+            unreachable!("databases are handled by handle_request")
+        }
+        Commands::Rm { key } => match store.remove(key) {
+            Ok(()) => NetworkConnection::Ok,
+            Err(err) => error(err),
+        },
+        Commands::Compact if !policy.allow_admin => NetworkConnection::Error {
+            error: "admin commands are disabled".to_string(),
+        },
+        Commands::Compact => match store.kvs().map(KvStore::compact) {
+            Some(Ok(reclaimed)) => NetworkConnection::Compacted { reclaimed },
+            Some(Err(err)) => error(err),
+            None => unsupported(),
+        },
+        // stats are only available in database 0, whose count leaves out other databases
+        Commands::Stats => match store.stats() {
+            Ok(stats) => NetworkConnection::Stats { stats },
+            Err(err) => error(err),
+        },
+        Commands::FetchGen { gen } => {
+            match store.kvs().map(|store| store.read_sealed_generation(gen)) {
+                Some(Ok(Some((gen, data)))) => NetworkConnection::Generation { gen, data },
+                // the follower is up to date
+                Some(Ok(None)) => NetworkConnection::Ok,
+                Some(Err(err)) => error(err),
+                None => unsupported(),
+            }
+        }
+    }
+}
+
+/// The keys a connection is confined to by its namespace and database
+struct KeySpace {
+    namespace: Option<String>,
+    db: u64,
+}
+
+impl KeySpace {
+    /// Returns the key stored for `key` of this key space
+    fn encode(&self, key: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => db_key(self.db, &format!("{}:{}", namespace, key)),
+            None => db_key(self.db, key),
+        }
+    }
+
+    /// Returns the key of this key space stored as `stored`, `None` if it belongs to
+    /// another database or namespace
+    fn decode<'a>(&self, stored: &'a str) -> Option<&'a str> {
+        let key = key_in_db(self.db, stored)?;
+        match &self.namespace {
+            Some(namespace) => key.strip_prefix(namespace.as_str())?.strip_prefix(':'),
+            None => Some(key),
+        }
+    }
+
+    /// Returns `command` with its keys stored in this key space
+    ///
+    /// Returns `None` for commands which are not confined to a set of keys, unless
+    /// the connection has neither a namespace nor a database other than 0
+    fn confine(&self, command: Commands) -> Option<Commands> {
+        let stored = |key: String| self.encode(&key);
+        match command {
+            Commands::Set { key, value } => Some(Commands::Set {
+                key: stored(key),
+                value,
+            }),
+            Commands::Get { key } => Some(Commands::Get { key: stored(key) }),
+            Commands::SetNoReply { key, value } => Some(Commands::SetNoReply {
+                key: stored(key),
+                value,
+            }),
+            Commands::Rm { key } => Some(Commands::Rm { key: stored(key) }),
+            Commands::Ttl { key } => Some(Commands::Ttl { key: stored(key) }),
+            Commands::ModifiedAt { key } => Some(Commands::ModifiedAt { key: stored(key) }),
+            Commands::StrLen { key } => Some(Commands::StrLen { key: stored(key) }),
+            Commands::GetIfModifiedSince { key, since } => Some(Commands::GetIfModifiedSince {
+                key: stored(key),
+                since,
+            }),
+            // confined by `execute` and `flush_db`, which decode the keys they list
+            Commands::Keys { .. } | Commands::FlushDb => Some(command),
+            Commands::Scan { prefix } => Some(Commands::Scan {
+                prefix: stored(prefix),
+            }),
+            Commands::SnapshotGet { keys } => Some(Commands::SnapshotGet {
+                keys: keys.into_iter().map(stored).collect(),
+            }),
+            Commands::Ping => Some(Commands::Ping),
+            Commands::Compact | Commands::Stats | Commands::FetchGen { .. } => {
+                (self.namespace.is_none() && self.db == 0).then_some(command)
+            }
+            Commands::Select { .. } => None,
+        }
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
@@ -0,0 +1,642 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::Verification;
+use kvs::{
+    get_current_engine, key_in_db, log_engine, ttl_seconds, Commands, KvStore, KvsEngine, KvsError,
+    Result, SledKvsEngine,
+};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    process::{self, exit},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Parser)]
+#[command(version, about, propagate_version = true, after_help = EXIT_CODES)]
+struct Cli {
+    #[command(subcommand)]
+    command: LocalCommands,
+    /// The exit code of a `get` for a missing key
+    #[arg(long, value_name = "CODE", default_value_t = 2, global = true)]
+    miss_exit_code: i32,
+    /// How values are written on the command line and printed
+    #[arg(long, value_enum, default_value_t = Encoding::Raw, global = true)]
+    encoding: Encoding,
+    /// Print nothing but errors, leaving the outcome to the exit code
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Describe what the command does on stderr
+    #[arg(long, short, global = true)]
+    verbose: bool,
+}
+
+/// Whether `--quiet` was given
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// Whether `--verbose` was given
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Prints a line of the result to stdout, unless `--quiet` was given
+macro_rules! out {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a note about the result to stderr, unless `--quiet` was given
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a detail of the operation to stderr if `--verbose` was given
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// The encoding of values on the command line
+#[derive(Clone, Copy, ValueEnum)]
+enum Encoding {
+    /// Values are used as they are
+    Raw,
+    /// Values are standard base64 with padding
+    Base64,
+}
+
+impl Encoding {
+    /// Decodes a value given on the command line
+    fn decode(self, value: String) -> Result<String> {
+        match self {
+            Encoding::Raw => Ok(value),
+            Encoding::Base64 => base64_decode(&value)
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "invalid base64 value").into()
+                }),
+        }
+    }
+
+    /// Encodes a stored value for printing
+    fn encode(self, value: String) -> String {
+        match self {
+            Encoding::Raw => value,
+            Encoding::Base64 => base64_encode(value.as_bytes()),
+        }
+    }
+}
+
+/// The exit code contract of the command, shown after the help
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  error, or `rm` of a missing key
+  2  `get` of a missing key, unless changed by --miss-exit-code";
+
+/// Commands run directly against the store in the current directory
+#[derive(Subcommand)]
+enum LocalCommands {
+    #[command(flatten)]
+    Store(Commands),
+    /// Dumps all live key/value pairs to a file (`-` for stdout)
+    Export { file: PathBuf },
+    /// Loads key/value pairs from a file (`-` for stdin) into the store
+    Import { file: PathBuf },
+    /// Removes every key starting with the prefix
+    RemovePrefix {
+        prefix: String,
+        /// Reports the keys that would be removed without removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Prints every record of a generation file with its offset and length
+    Replay {
+        logfile: PathBuf,
+        /// Only prints the records of this key
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Checks every generation of the store, against its footer when it has one
+    Verify {
+        /// Decodes every record, even of generations with a footer
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Copies every key/value pair of a data directory into another one using a
+    /// different engine
+    ///
+    /// The destination is marked as using the destination engine. Deadlines of keys
+    /// and value types are not carried over.
+    Migrate {
+        /// The engine of the source directory
+        #[arg(long, value_enum)]
+        from: Engine,
+        /// The engine of the destination directory
+        #[arg(long, value_enum)]
+        to: Engine,
+        /// The source data directory
+        #[arg(long, value_name = "DIR")]
+        src: PathBuf,
+        /// The destination data directory
+        #[arg(long, value_name = "DIR")]
+        dst: PathBuf,
+    },
+    /// Measures the throughput of sets and random gets against a temporary store
+    ///
+    /// The store is created in the temporary directory of the system and removed
+    /// afterwards, leaving the store in the current directory alone.
+    Bench {
+        /// The number of sets, and then of gets
+        #[arg(long, value_name = "N", default_value_t = 10_000)]
+        ops: usize,
+        /// The size of every value in bytes
+        #[arg(long, value_name = "B", default_value_t = 100)]
+        value_size: usize,
+        /// The engine to measure
+        #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+        engine: Engine,
+    },
+    /// Shows or overwrites the engine marker of the store
+    #[command(subcommand)]
+    Engine(EngineCommands),
+    /// Reports the key count, stale bytes, generations and log size of the store
+    Info {
+        /// The format of the report
+        #[arg(long, value_enum, default_value_t = Output::Text)]
+        output: Output,
+    },
+}
+
+/// Commands on the engine marker of the data directory
+#[derive(Subcommand)]
+enum EngineCommands {
+    /// Prints the engine recorded in the marker
+    Show,
+    /// Records the engine in the marker, replacing a missing or corrupted one
+    ///
+    /// Only the marker changes, the data of the store is not converted; see `migrate`.
+    Set {
+        #[arg(value_enum)]
+        name: Engine,
+    },
+}
+
+/// A storage engine, named as in the engine marker of a data directory
+#[derive(Clone, Copy, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl Engine {
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+        }
+    }
+
+    /// Opens the data directory at `path` with this engine, which must be the one
+    /// recorded in the directory if any
+    fn open(self, path: &Path) -> Result<Box<dyn KvsEngine>> {
+        if get_current_engine(path)?.is_some_and(|engine| engine != self.name()) {
+            return Err(KvsError::WrongEngineType(self.name().to_string()));
+        }
+        Ok(match self {
+            Engine::Kvs => Box::new(KvStore::open(path)?),
+            Engine::Sled => Box::new(SledKvsEngine::open(path)?),
+        })
+    }
+}
+
+/// The format of a report
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    /// One `name: value` line per field
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+/// The number of keys listed by a dry run
+const DRY_RUN_SAMPLE: usize = 10;
+
+pub fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let cli: Cli = Cli::parse();
+    QUIET.store(cli.quiet, Ordering::Relaxed);
+    VERBOSE.store(cli.verbose, Ordering::Relaxed);
+    let started = Instant::now();
+    // the log is only read, so the store is left alone
+    if let LocalCommands::Replay { logfile, key } = &cli.command {
+        return replay(logfile, key.as_deref(), cli.encoding);
+    }
+    if let LocalCommands::Verify { deep } = cli.command {
+        return verify(deep);
+    }
+    if let LocalCommands::Migrate { from, to, src, dst } = &cli.command {
+        return migrate(*from, *to, src, dst);
+    }
+    if let LocalCommands::Bench {
+        ops,
+        value_size,
+        engine,
+    } = cli.command
+    {
+        return bench(ops, value_size, engine);
+    }
+    // a corrupted marker must stay fixable, so the store is not opened
+    if let LocalCommands::Engine(command) = &cli.command {
+        return engine(command);
+    }
+    let mut store = match KvStore::open(Path::new(".")) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!(
+                "Error: could not open the store in the current directory: {}",
+                err
+            );
+            exit(1);
+        }
+    };
+    verbose!(
+        "Opened the store in the current directory: {} keys in {} generations",
+        store.stats()?.key_count,
+        store.generation_count()
+    );
+
+    match cli.command {
+        LocalCommands::Store(Commands::Set { key, value }) => {
+            let value = cli.encoding.decode(value)?;
+            verbose!("Setting {} to a value of {} bytes", key, value.len());
+            store.set(key, value)?
+        }
+        LocalCommands::Store(Commands::Get { key }) => match store.get(key)? {
+            Some(value) => out!("{}", cli.encoding.encode(value)),
+            None => {
+                out!("{}", KvsError::KeyDoesNotExist);
+                exit(cli.miss_exit_code);
+            }
+        },
+        LocalCommands::Store(Commands::SnapshotGet { keys }) => {
+            for key in keys {
+                match store.get(key)? {
+                    Some(value) => out!("{}", cli.encoding.encode(value)),
+                    None => out!("{}", KvsError::KeyDoesNotExist),
+                }
+            }
+        }
+        LocalCommands::Store(Commands::Keys { cursor, limit }) => {
+            let limit = limit.max(1);
+            let mut keys = store.keys_page("", cursor.as_deref(), limit + 1);
+            let more = keys.len() > limit;
+            keys.truncate(limit);
+            for key in &keys {
+                out!("{}", key);
+            }
+            if let Some(next_cursor) = keys.last().filter(|_| more) {
+                note!("Next cursor: {}", next_cursor);
+            }
+        }
+        LocalCommands::Store(Commands::Ttl { key }) => out!("{}", ttl_seconds(store.ttl(&key))),
+        LocalCommands::Store(Commands::GetIfModifiedSince { key, since }) => {
+            match store.get_if_modified_since(key, since)? {
+                Some(Some(value)) => out!("{}", cli.encoding.encode(value)),
+                Some(None) => {
+                    out!("{}", KvsError::KeyDoesNotExist);
+                    exit(cli.miss_exit_code);
+                }
+                None => out!("Not modified"),
+            }
+        }
+        LocalCommands::Store(Commands::StrLen { key }) => match store.value_len(&key) {
+            Some(len) => out!("{}", len),
+            None => {
+                out!("{}", KvsError::KeyDoesNotExist);
+                exit(cli.miss_exit_code);
+            }
+        },
+        LocalCommands::Store(Commands::ModifiedAt { key }) => match store.modified_at(&key) {
+            Some(millis) => out!("{}", millis),
+            None => {
+                out!("{}", KvsError::KeyDoesNotExist);
+                exit(cli.miss_exit_code);
+            }
+        },
+        LocalCommands::Store(Commands::Scan { prefix }) => {
+            for (key, value) in store.scan(&prefix)? {
+                out!("{} {}", key, cli.encoding.encode(value));
+            }
+        }
+        LocalCommands::Store(Commands::Rm { key }) => match store.remove(key.clone()) {
+            Ok(()) => verbose!("Removed {}", key),
+            Err(KvsError::KeyDoesNotExist) => {
+                out!("{}", KvsError::KeyDoesNotExist);
+                exit(1);
+            }
+            Err(err) => return Err(err),
+        },
+        LocalCommands::Store(Commands::Compact) => out!("{}", store.compact()?),
+        LocalCommands::Store(Commands::Stats) => {
+            let stats = store.stats()?;
+            out!("key_count: {}", stats.key_count);
+            out!("disk_bytes: {}", stats.disk_bytes);
+            out!("compacting: {}", stats.compacting);
+            out!("open_readers: {}", stats.open_readers);
+        }
+        // the store is read as database 0, leaving the keys of other databases
+        LocalCommands::Store(Commands::FlushDb) => {
+            let keys: Vec<_> = store
+                .keys(..)
+                .into_iter()
+                .filter(|key| key_in_db(0, key).is_some())
+                .collect();
+            verbose!("Removing {} keys", keys.len());
+            for key in keys {
+                store.remove(key)?;
+            }
+        }
+        LocalCommands::Store(
+            Commands::FetchGen { .. }
+            | Commands::SetNoReply { .. }
+            | Commands::Ping
+            | Commands::Select { .. },
+        ) => {
+            unreachable!("not available from the command line")
+        }
+        LocalCommands::Export { file } => {
+            if file == Path::new("-") {
+                store.export(io::stdout().lock())?;
+            } else {
+                store.export(BufWriter::new(File::create(file)?))?;
+            }
+        }
+        LocalCommands::Import { file } => {
+            if file == Path::new("-") {
+                store.import(io::stdin().lock())?;
+            } else {
+                store.import(File::open(file)?)?;
+            }
+        }
+        LocalCommands::RemovePrefix { prefix, dry_run } => {
+            let keys: Vec<_> = store
+                .keys(prefix.clone()..)
+                .into_iter()
+                .take_while(|key| key.starts_with(&prefix))
+                .collect();
+            if dry_run {
+                out!("Would remove {} keys", keys.len());
+                for key in keys.iter().take(DRY_RUN_SAMPLE) {
+                    out!("{}", key);
+                }
+                if keys.len() > DRY_RUN_SAMPLE {
+                    out!("...");
+                }
+            } else {
+                verbose!("Removing {} keys", keys.len());
+                for key in keys {
+                    store.remove(key)?;
+                }
+            }
+        }
+        LocalCommands::Replay { .. }
+        | LocalCommands::Verify { .. }
+        | LocalCommands::Migrate { .. }
+        | LocalCommands::Bench { .. }
+        | LocalCommands::Engine(_) => {
+            unreachable!("handled without opening the store")
+        }
+        LocalCommands::Info { output } => {
+            let stats = store.stats()?;
+            let counts = [
+                ("key_count", stats.key_count),
+                ("uncompacted_bytes", store.uncompacted_bytes()),
+                ("generation_count", store.generation_count() as u64),
+                ("log_bytes", stats.disk_bytes),
+            ];
+            let engine = get_current_engine(".")?;
+            match output {
+                Output::Text => {
+                    for (name, count) in counts {
+                        out!("{}: {}", name, count);
+                    }
+                    if let Some(engine) = engine {
+                        out!("engine: {}", engine);
+                    }
+                }
+                Output::Json => {
+                    let mut fields: Vec<_> = counts
+                        .iter()
+                        .map(|(name, count)| format!("\"{}\":{}", name, count))
+                        .collect();
+                    if let Some(engine) = engine {
+                        fields.push(format!("\"engine\":{:?}", engine));
+                    }
+                    out!("{{{}}}", fields.join(","));
+                }
+            }
+        }
+    }
+
+    verbose!("Done in {:?}", started.elapsed());
+    Ok(())
+}
+
+/// Prints the records of `logfile`, only those of `key` if given
+fn replay(logfile: &Path, key: Option<&str>, encoding: Encoding) -> Result<()> {
+    for record in KvStore::decode_log(File::open(logfile)?)? {
+        if key.is_some_and(|key| key != record.key) {
+            continue;
+        }
+        match record.value {
+            Some(value) => out!(
+                "{} {} set {} {}",
+                record.offset,
+                record.len,
+                record.key,
+                encoding.encode(value)
+            ),
+            None => out!("{} {} rm {}", record.offset, record.len, record.key),
+        }
+    }
+    Ok(())
+}
+
+/// Copies every pair of the `from` engine at `src` into the `to` engine at `dst`
+fn migrate(from: Engine, to: Engine, src: &Path, dst: &Path) -> Result<()> {
+    if src == dst {
+        let error = "the source and destination directories must differ".to_string();
+        return Err(KvsError::Config(error));
+    }
+    let source = from.open(src)?;
+    let mut destination = to.open(dst)?;
+    log_engine(dst, to.name().to_string())?;
+
+    verbose!("Copying from {} to {}", src.display(), dst.display());
+    let mut count = 0;
+    for pair in source.iter()? {
+        let (key, value) = pair?;
+        destination.set(key, value)?;
+        count += 1;
+    }
+    destination.flush()?;
+    out!("Migrated {} keys", count);
+    Ok(())
+}
+
+/// A directory removed with everything in it when dropped
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `ops` sets of `value_size` byte values and then `ops` gets of random keys
+/// against a temporary store of `engine`, printing the throughput of each
+fn bench(ops: usize, value_size: usize, engine: Engine) -> Result<()> {
+    if ops == 0 {
+        return Err(KvsError::Config("--ops must not be zero".to_string()));
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let dir = ScratchDir(env::temp_dir().join(format!("kvs-bench-{}-{}", process::id(), nanos)));
+    verbose!("Benchmarking {} in {}", engine.name(), dir.0.display());
+    let mut store = engine.open(&dir.0)?;
+
+    let key = |i: usize| format!("key{:010}", i);
+    let value = "v".repeat(value_size);
+    let mut latencies = Vec::with_capacity(ops);
+    for i in 0..ops {
+        let start = Instant::now();
+        store.set(key(i), value.clone())?;
+        latencies.push(start.elapsed());
+    }
+    report("set", &mut latencies);
+
+    // xorshift, seeded from the clock so every run reads in a different order
+    let mut state = nanos | 1;
+    latencies.clear();
+    for _ in 0..ops {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let start = Instant::now();
+        let found = store.get(key((state % ops as u64) as usize))?;
+        latencies.push(start.elapsed());
+        if found.is_none() {
+            return Err(KvsError::KeyDoesNotExist);
+        }
+    }
+    report("get", &mut latencies);
+    Ok(())
+}
+
+/// Prints the throughput and 99th percentile latency of the operations taking `latencies`
+fn report(operation: &str, latencies: &mut [Duration]) {
+    let total: Duration = latencies.iter().sum();
+    let ops_per_sec = latencies.len() as f64 / total.as_secs_f64().max(f64::MIN_POSITIVE);
+    latencies.sort_unstable();
+    let p99 = latencies[(latencies.len() * 99).div_ceil(100) - 1];
+    out!(
+        "{}: {} ops, {:.0} ops/sec, p99 {} us",
+        operation,
+        latencies.len(),
+        ops_per_sec,
+        p99.as_micros()
+    );
+}
+
+/// Prints or replaces the engine marker of the current directory
+fn engine(command: &EngineCommands) -> Result<()> {
+    match command {
+        EngineCommands::Show => match get_current_engine(".")? {
+            Some(engine) => out!("{}", engine),
+            None => out!("No engine recorded"),
+        },
+        EngineCommands::Set { name } => {
+            if let Some(previous) = get_current_engine(".")? {
+                verbose!("Replacing the engine marker {:?}", previous);
+            }
+            log_engine(".", name.name().to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints how every generation of the store in the current directory was checked
+///
+/// The store is opened read-only, so checking it leaves the directory untouched.
+fn verify(deep: bool) -> Result<()> {
+    let mut store = KvStore::open_read_only(".")?;
+    for (gen, verification) in store.verify(deep)? {
+        let method = match verification {
+            Verification::Footer => "footer",
+            Verification::Replay => "replay",
+        };
+        out!("{}: ok ({})", gen, method);
+    }
+    Ok(())
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let sextet = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns `None` if `text` is not valid base64
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text
+        .strip_suffix("==")
+        .or(text.strip_suffix('='))
+        .unwrap_or(text);
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut pending) = (0u32, 0);
+    for byte in text.bytes() {
+        let sextet = BASE64_ALPHABET.iter().position(|&c| c == byte)? as u32;
+        bits = bits << 6 | sextet;
+        pending += 6;
+        if pending >= 8 {
+            pending -= 8;
+            decoded.push((bits >> pending) as u8);
+            bits &= (1 << pending) - 1;
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,558 @@
+use crate::common::{configure_stream, Commands, NetworkConnection};
+use crate::{KvStore, KvsError, Result};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+/// The number of times a request is sent again on a new connection by default
+const MAX_RETRIES: u32 = 1;
+
+/// A connection to a kvs server
+pub struct KvsClient {
+    stream: TcpStream,
+    addr: SocketAddr,
+    max_retries: u32,
+    server_version: Option<String>,
+    auth_token: Option<String>,
+    namespace: Option<String>,
+    db: Option<u64>,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr` and exchanges crate versions with it
+    ///
+    /// A server predating the version exchange drops the connection on the unknown
+    /// message, in which case the client connects again and speaks the legacy
+    /// protocol, see [`KvsClient::connect_legacy`].
+    /// `TCP_NODELAY` is enabled on the connection, see [`KvsClient::set_nodelay`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if connecting or the version exchange fails,
+    /// `KvsError::Remote` if the server refuses the exchange
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let mut client = KvsClient::connect_legacy(addr)?;
+        match client.handshake() {
+            Ok(version) => client.server_version = Some(version),
+            Err(KvsError::Io(err)) if is_broken(&err) => {
+                client = KvsClient::connect_legacy(client.addr)?;
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(client)
+    }
+
+    /// Connects to the server at `addr` without exchanging crate versions, as servers
+    /// predating the exchange expect
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if connecting fails
+    pub fn connect_legacy(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        configure_stream(&stream, true, None)?;
+        let addr = stream.peer_addr()?;
+
+        Ok(KvsClient {
+            stream,
+            addr,
+            max_retries: MAX_RETRIES,
+            server_version: None,
+            auth_token: None,
+            namespace: None,
+            db: None,
+        })
+    }
+
+    /// Announces the crate version of the client and returns the one of the server
+    fn handshake(&mut self) -> Result<String> {
+        NetworkConnection::send_network_message(
+            NetworkConnection::Handshake {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            &mut self.stream,
+        )?;
+        let buf = NetworkConnection::receive_network_message(&mut self.stream)?;
+        match NetworkConnection::deserialize_message(buf)? {
+            NetworkConnection::Handshake { version } => Ok(version),
+            NetworkConnection::Error { error } => Err(KvsError::Remote(error)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected response to Handshake",
+            )
+            .into()),
+        }
+    }
+
+    /// Sets whether small messages are sent right away instead of being coalesced
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if setting the socket option fails
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.stream.set_nodelay(nodelay)?;
+        Ok(())
+    }
+
+    /// Sets how many times a request is sent again on a new connection after the
+    /// connection broke, e.g. because the server restarted, once by default
+    ///
+    /// A request whose sending failed never reached the server and is always sent
+    /// again. Once a request was sent, only reads and sets, which can be applied
+    /// twice without harm, are sent again; other requests fail with
+    /// `KvsError::UnknownOutcome` since they may have been applied. The new
+    /// connection is authenticated and confined to the namespace and database like
+    /// this one.
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Returns the crate version announced by the server, `None` if the server
+    /// predates the version exchange
+    pub fn server_version(&self) -> Option<&str> {
+        self.server_version.as_deref()
+    }
+
+    /// Authenticates the connection with the shared secret of the server
+    ///
+    /// # Errors
+    ///
+    /// This function will return `KvsError::Remote` if the server rejects the token,
+    /// or an error if sending the token or receiving the answer fails
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.exchange(NetworkConnection::Auth {
+            token: token.to_string(),
+        })?;
+        self.auth_token = Some(token.to_string());
+        Ok(())
+    }
+
+    /// Confines the requests of the connection to the keys of `namespace`
+    ///
+    /// # Errors
+    ///
+    /// This function will return `KvsError::Remote` if the server rejects the namespace,
+    /// or an error if sending the namespace or receiving the answer fails
+    pub fn select_namespace(&mut self, namespace: &str) -> Result<()> {
+        self.exchange(NetworkConnection::Namespace {
+            namespace: namespace.to_string(),
+        })?;
+        self.namespace = Some(namespace.to_string());
+        Ok(())
+    }
+
+    /// Confines the following requests of the connection to the keys of database `db`
+    ///
+    /// Databases are independent keyspaces multiplexed over the store of the server,
+    /// database 0 being the default. Within a namespace, the database is selected
+    /// within the namespace, so this must be called after [`KvsClient::select_namespace`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return `KvsError::Remote` if the server rejects the database,
+    /// or an error if sending the request or receiving the answer fails
+    pub fn select_db(&mut self, db: u64) -> Result<()> {
+        self.exchange(NetworkConnection::Request {
+            command: Commands::Select { db },
+        })?;
+        self.db = Some(db);
+        Ok(())
+    }
+
+    /// Sends a message the server acknowledges with `Ok` or refuses with `Error`
+    fn exchange(&mut self, message: NetworkConnection) -> Result<()> {
+        NetworkConnection::send_network_message(message, &mut self.stream)?;
+        let buf = NetworkConnection::receive_network_message(&mut self.stream)?;
+        match NetworkConnection::deserialize_message(buf)? {
+            NetworkConnection::Error { error } => Err(KvsError::Remote(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a command to the server and returns its response along with the time
+    /// the server spent running it, in microseconds
+    ///
+    /// The time is `None` if the server answered without running the command, e.g.
+    /// when it refused the request.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending the request or receiving the response fails
+    pub fn request_timed(self, command: Commands) -> Result<(NetworkConnection, Option<u64>)> {
+        self.send_retrying(command, true)
+    }
+
+    /// Sends `command` and receives its response, on new connections up to
+    /// `max_retries` times if the connection breaks, see [`KvsClient::set_max_retries`]
+    fn send_retrying(
+        mut self,
+        command: Commands,
+        timed: bool,
+    ) -> Result<(NetworkConnection, Option<u64>)> {
+        let mut retries = 0;
+        loop {
+            let message = if timed {
+                NetworkConnection::TimedRequest {
+                    command: command.clone(),
+                }
+            } else {
+                NetworkConnection::Request {
+                    command: command.clone(),
+                }
+            };
+            let err = match NetworkConnection::send_network_message(message, &mut self.stream) {
+                Ok(()) => match self.receive_response() {
+                    Err(KvsError::Io(err)) if is_broken(&err) && !is_idempotent(&command) => {
+                        return Err(KvsError::UnknownOutcome(err));
+                    }
+                    Err(KvsError::Io(err)) if is_broken(&err) => err,
+                    result => return result,
+                },
+                Err(KvsError::Io(err)) if is_broken(&err) => err,
+                Err(err) => return Err(err),
+            };
+            if retries == self.max_retries {
+                return Err(err.into());
+            }
+            retries += 1;
+            self = self.reconnect()?;
+        }
+    }
+
+    /// Receives the response to a request, preceded by its timing for a timed request
+    fn receive_response(&mut self) -> Result<(NetworkConnection, Option<u64>)> {
+        let buf = NetworkConnection::receive_network_message(&mut self.stream)?;
+        match NetworkConnection::deserialize_message(buf)? {
+            NetworkConnection::Timing { server_time_micros } => {
+                let buf = NetworkConnection::receive_network_message(&mut self.stream)?;
+                let response = NetworkConnection::deserialize_message(buf)?;
+                Ok((response, Some(server_time_micros)))
+            }
+            response => Ok((response, None)),
+        }
+    }
+
+    /// Opens a new connection to the server, authenticated and confined to the
+    /// namespace and database like this one
+    fn reconnect(&self) -> Result<KvsClient> {
+        let mut client = match self.server_version {
+            Some(_) => KvsClient::connect(self.addr)?,
+            None => KvsClient::connect_legacy(self.addr)?,
+        };
+        client.max_retries = self.max_retries;
+        if let Some(token) = &self.auth_token {
+            client.authenticate(token)?;
+        }
+        if let Some(namespace) = &self.namespace {
+            client.select_namespace(namespace)?;
+        }
+        if let Some(db) = self.db {
+            client.select_db(db)?;
+        }
+        Ok(client)
+    }
+
+    /// Sends a set the server applies without sending any response
+    ///
+    /// Any number of these can be streamed on the connection ahead of a final
+    /// [`KvsClient::request`], e.g. of `Commands::Ping`, whose response confirms that
+    /// all of them were handled. Until then the writes are unacknowledged: they are
+    /// lost if the connection or the server fails, and a set the server rejects is
+    /// only logged by the server, never reported to the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending the request fails
+    pub fn set_no_reply(&mut self, key: String, value: String) -> Result<()> {
+        NetworkConnection::send_network_message(
+            NetworkConnection::Request {
+                command: Commands::SetNoReply { key, value },
+            },
+            &mut self.stream,
+        )
+    }
+
+    /// Lists the keys starting with `prefix` along with their values
+    ///
+    /// The pairs are received one message at a time as the returned iterator is
+    /// advanced, so a large scan is never held in memory as a whole. They come in
+    /// the collation order of the server store.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending the request fails
+    pub fn scan(mut self, prefix: String) -> Result<ScanStream> {
+        NetworkConnection::send_network_message(
+            NetworkConnection::Request {
+                command: Commands::Scan { prefix },
+            },
+            &mut self.stream,
+        )?;
+        Ok(ScanStream {
+            stream: self.stream,
+            done: false,
+        })
+    }
+
+    /// Lists every key of the server, requesting `page_size` keys at a time
+    ///
+    /// The keys come in the collation order of the server store. Since the server
+    /// answers a single request per connection, every page after the first is
+    /// requested on a new connection, authenticated and confined to the namespace
+    /// and database like this one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the address of the server is unknown
+    pub fn keys(self, page_size: usize) -> Result<KeysIter> {
+        Ok(KeysIter {
+            addr: self.stream.peer_addr()?,
+            auth_token: self.auth_token.clone(),
+            namespace: self.namespace.clone(),
+            db: self.db,
+            client: Some(self),
+            page_size,
+            cursor: None,
+            page: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Gets the value of `key` from the server only if it was written after `since`,
+    /// in milliseconds since the Unix epoch
+    ///
+    /// Returns `None` if the value was not written since, sparing its transfer, and
+    /// `Some(None)` if the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `KvsError::Remote` if the server fails the request,
+    /// or an error if sending the request or receiving the response fails
+    pub fn get_if_modified_since(self, key: String, since: u64) -> Result<Option<Option<String>>> {
+        match self.request(Commands::GetIfModifiedSince { key, since })? {
+            NetworkConnection::Value { value } => Ok(Some(value)),
+            NetworkConnection::NotModified => Ok(None),
+            NetworkConnection::Error { error } => Err(KvsError::Remote(error)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected response to GetIfModifiedSince",
+            )
+            .into()),
+        }
+    }
+
+    /// Sends a command to the server and returns its response
+    ///
+    /// The server answers a single request per connection, so the client is consumed.
+    /// A broken connection is replaced as described in [`KvsClient::set_max_retries`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending the request or receiving the response fails,
+    /// and `KvsError::UnknownOutcome` if the connection broke after sending a request
+    /// which is not sent again
+    pub fn request(self, command: Commands) -> Result<NetworkConnection> {
+        self.send_retrying(command, false)
+            .map(|(response, _)| response)
+    }
+}
+
+/// Returns whether `err` tells that the connection to the server is gone
+fn is_broken(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Returns whether applying `command` twice has the same effect as applying it once
+fn is_idempotent(command: &Commands) -> bool {
+    match command {
+        Commands::Get { .. }
+        | Commands::Set { .. }
+        | Commands::SnapshotGet { .. }
+        | Commands::Ttl { .. }
+        | Commands::ModifiedAt { .. }
+        | Commands::StrLen { .. }
+        | Commands::GetIfModifiedSince { .. }
+        | Commands::Keys { .. }
+        | Commands::Scan { .. }
+        | Commands::Stats
+        | Commands::Ping
+        | Commands::FetchGen { .. }
+        | Commands::Select { .. }
+        | Commands::FlushDb => true,
+        // a repeated removal fails, and a set without reply is never answered
+        Commands::Rm { .. } | Commands::Compact | Commands::SetNoReply { .. } => false,
+    }
+}
+
+/// The key/value pairs streamed by the server in response to [`KvsClient::scan`]
+///
+/// The iterator ends after the last pair, or after yielding the first error.
+/// An error reported by the server is yielded as `KvsError::Remote`.
+pub struct ScanStream {
+    stream: TcpStream,
+    done: bool,
+}
+
+impl Iterator for ScanStream {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let message = NetworkConnection::receive_network_message(&mut self.stream)
+            .and_then(NetworkConnection::deserialize_message);
+        let end = match message {
+            Ok(NetworkConnection::ScanItem { key, value }) => return Some(Ok((key, value))),
+            Ok(NetworkConnection::ScanEnd) => None,
+            Ok(NetworkConnection::Error { error }) => Some(Err(KvsError::Remote(error))),
+            Ok(_) => Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected message in a scan",
+            )
+            .into())),
+            Err(err) => Some(Err(err)),
+        };
+        self.done = true;
+        end
+    }
+}
+
+/// The keys of a server listed a page at a time by [`KvsClient::keys`]
+///
+/// The iterator ends after the last key, or after yielding the first error.
+pub struct KeysIter {
+    addr: SocketAddr,
+    auth_token: Option<String>,
+    namespace: Option<String>,
+    db: Option<u64>,
+    // the connection for the first page
+    client: Option<KvsClient>,
+    page_size: usize,
+    cursor: Option<String>,
+    page: VecDeque<String>,
+    done: bool,
+}
+
+impl KeysIter {
+    /// Requests the page after the cursor, on a new connection past the first page
+    fn fetch_page(&mut self) -> Result<()> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => {
+                let mut client = KvsClient::connect(self.addr)?;
+                if let Some(token) = &self.auth_token {
+                    client.authenticate(token)?;
+                }
+                if let Some(namespace) = &self.namespace {
+                    client.select_namespace(namespace)?;
+                }
+                if let Some(db) = self.db {
+                    client.select_db(db)?;
+                }
+                client
+            }
+        };
+        let command = Commands::Keys {
+            cursor: self.cursor.take(),
+            limit: self.page_size,
+        };
+        match client.request(command)? {
+            NetworkConnection::Keys { keys, next_cursor } => {
+                self.page.extend(keys);
+                self.done = next_cursor.is_none();
+                self.cursor = next_cursor;
+                Ok(())
+            }
+            NetworkConnection::Error { error } => Err(KvsError::Remote(error)),
+            _ => Err(
+                io::Error::new(io::ErrorKind::InvalidData, "unexpected response to Keys").into(),
+            ),
+        }
+    }
+}
+
+impl Iterator for KeysIter {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(key) = self.page.pop_front() {
+                return Some(Ok(key));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.fetch_page() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// Keeps a store up to date by replaying the sealed generations of a primary server
+///
+/// The follower tracks the next generation it needs and only asks the primary for
+/// generations from that number on.
+pub struct GenerationFollower {
+    primary: SocketAddr,
+    auth_token: Option<String>,
+    next_gen: u64,
+}
+
+impl GenerationFollower {
+    /// Creates a follower of the primary at `primary` starting from its first generation
+    pub fn new(primary: SocketAddr) -> Self {
+        GenerationFollower {
+            primary,
+            auth_token: None,
+            next_gen: 0,
+        }
+    }
+
+    /// Authenticates with `token` on every connection to the primary
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// Returns the lowest generation number the follower has not replayed yet
+    pub fn next_gen(&self) -> u64 {
+        self.next_gen
+    }
+
+    /// Fetches every new sealed generation from the primary and replays it into `store`
+    ///
+    /// Returns the number of generations replayed
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if talking to the primary or replaying a generation fails
+    pub fn poll(&mut self, store: &mut KvStore) -> Result<usize> {
+        let mut replayed = 0;
+        loop {
+            let command = Commands::FetchGen { gen: self.next_gen };
+            let mut client = KvsClient::connect(self.primary)?;
+            if let Some(token) = &self.auth_token {
+                client.authenticate(token)?;
+            }
+            match client.request(command)? {
+                NetworkConnection::Generation { gen, data } => {
+                    store.replay_generation(data.as_slice())?;
+                    self.next_gen = gen + 1;
+                    replayed += 1;
+                }
+                NetworkConnection::Error { error } => return Err(KvsError::Remote(error)),
+                _ => return Ok(replayed),
+            }
+        }
+    }
+}
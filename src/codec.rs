@@ -0,0 +1,114 @@
+use crate::kvs::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Turns serializable values into bytes and back
+///
+/// The log format and the wire format each pick their codec independently,
+/// see [`KvStoreOptions::codec`](crate::KvStoreOptions::codec) and
+/// [`NetworkConnection::serialize_message_with`](crate::NetworkConnection::serialize_message_with).
+pub trait Codec {
+    /// Encodes `value` into bytes
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the value cannot be encoded
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value from `bytes`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `bytes` do not hold a value of type `T`
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+
+    /// Returns whether the encoding records field names, so fields which are
+    /// skipped when serializing can be told apart on decoding
+    fn is_self_describing(&self) -> bool {
+        true
+    }
+}
+
+/// The flexbuffers encoding, the default of the store and the wire protocol
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlexbuffersCodec;
+
+impl Codec for FlexbuffersCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(flexbuffers::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(flexbuffers::from_slice(bytes)?)
+    }
+}
+
+/// The compact bincode encoding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    fn is_self_describing(&self) -> bool {
+        false
+    }
+}
+
+/// The JSON encoding, convenient when inspecting logs or traffic by hand
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Selects one of the built-in codecs at construction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    /// See [`FlexbuffersCodec`]
+    #[default]
+    Flexbuffers,
+    /// See [`BincodeCodec`]
+    Bincode,
+    /// See [`JsonCodec`]
+    Json,
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Flexbuffers => FlexbuffersCodec.encode(value),
+            CodecKind::Bincode => BincodeCodec.encode(value),
+            CodecKind::Json => JsonCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            CodecKind::Flexbuffers => FlexbuffersCodec.decode(bytes),
+            CodecKind::Bincode => BincodeCodec.decode(bytes),
+            CodecKind::Json => JsonCodec.decode(bytes),
+        }
+    }
+
+    fn is_self_describing(&self) -> bool {
+        match self {
+            CodecKind::Flexbuffers => FlexbuffersCodec.is_self_describing(),
+            CodecKind::Bincode => BincodeCodec.is_self_describing(),
+            CodecKind::Json => JsonCodec.is_self_describing(),
+        }
+    }
+}
@@ -2,38 +2,243 @@
 // Our KVS supports only 3 commands i.e., set k v, get k, rm k; All the elements are strings. So we will use an enum to represent and then we can serialize / deserialize that
 
 use std::{
-    fs::{self, File},
-    io::{BufRead, BufReader, Read, Write},
+    cell::RefCell,
+    fs,
+    io::{self, Read, Write},
     net::TcpStream,
-    path::{Path, PathBuf},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::Result;
+use crate::codec::{Codec, FlexbuffersCodec};
+use crate::{EngineStats, KvsError, Result};
 use clap::Subcommand;
+use flexbuffers::FlexbufferSerializer;
 use serde::{Deserialize, Serialize};
 
 /// Enums describing the commands supported by the KVS
-#[derive(Subcommand, Debug, Serialize, Deserialize)]
+#[derive(Subcommand, Debug, Clone, Serialize, Deserialize)]
 pub enum Commands {
     /// Sets the value of a key in the database
-    Set { key: String, value: String },
+    Set {
+        /// The key to set
+        key: String,
+        /// The value to store under `key`
+        value: String,
+    },
     /// Gets the value of a key from the database
-    Get { key: String },
+    Get {
+        /// The key to look up
+        key: String,
+    },
     /// Removes the key from the database
-    Rm { key: String },
+    Rm {
+        /// The key to remove
+        key: String,
+    },
+    /// Gets the values of several keys as of a single moment
+    SnapshotGet {
+        /// The keys to look up
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Reports the seconds left before a key expires, -1 if it never expires and
+    /// -2 if it does not exist
+    Ttl {
+        /// The key to look up
+        key: String,
+    },
+    /// Reports when the value of a key was last written, in milliseconds since the
+    /// Unix epoch
+    ModifiedAt {
+        /// The key to look up
+        key: String,
+    },
+    /// Reports the length of the value of a key in bytes, without transferring it
+    StrLen {
+        /// The key to look up
+        key: String,
+    },
+    /// Gets the value of a key only if it was written after a point in time
+    GetIfModifiedSince {
+        /// The key to look up
+        key: String,
+        /// The point in time in milliseconds since the Unix epoch
+        since: u64,
+    },
+    /// Lists the keys of the database a page at a time
+    Keys {
+        /// List the keys after this one, as returned with the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// The maximum number of keys to list, at least one is always listed
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Lists the keys starting with a prefix along with their values
+    ///
+    /// The server streams the pairs as `ScanItem` messages followed by `ScanEnd`,
+    /// see [`KvsClient::scan`](crate::KvsClient::scan).
+    Scan {
+        /// The prefix of the keys to list
+        prefix: String,
+    },
+    /// Compacts the log of the database, reporting the bytes reclaimed
+    Compact,
+    /// Reports the number of keys and the disk usage of the database
+    Stats,
+    /// Removes every key of the selected database, see `Select`
+    FlushDb,
+    /// Sets the value of a key without the server sending any response
+    ///
+    /// The write is unacknowledged: the client cannot tell whether it was applied,
+    /// since a rejected set is only logged by the server, and it is lost if the
+    /// connection or the server fails before a later request is answered. See
+    /// [`KvsClient::set_no_reply`](crate::KvsClient::set_no_reply).
+    #[command(skip)]
+    SetNoReply {
+        /// The key to set
+        key: String,
+        /// The value to store under `key`
+        value: String,
+    },
+    /// Does nothing, answered with `Ok` once every earlier request of the connection is handled
+    #[command(skip)]
+    Ping,
+    /// Fetches the raw contents of the first sealed generation numbered `gen` or later
+    #[command(skip)]
+    FetchGen {
+        /// The lowest generation number to fetch
+        gen: u64,
+    },
+    /// Confines the following requests of the connection to the keys of database `db`
+    ///
+    /// Answered with `Ok` ahead of the next request, see
+    /// [`KvsClient::select_db`](crate::KvsClient::select_db). Database 0 is the
+    /// default and holds the keys stored without a database, see
+    /// [`db_key`](crate::db_key) for how the server stores the keys of each database.
+    #[command(skip)]
+    Select {
+        /// The number of the database
+        db: u64,
+    },
 }
 
 /// Describes the type of message that can be sent or received from the stream
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NetworkConnection {
     /// A message request usually sent by the client
-    Request { command: Commands },
+    Request {
+        /// The command to execute on the server
+        command: Commands,
+    },
     /// A message response containing a `value`
-    Response { value: String },
+    Response {
+        /// The value returned by the server
+        value: String,
+    },
     /// A message signaling an error
-    Error { error: String },
+    Error {
+        /// The error message
+        error: String,
+    },
     /// A message response signalling that the request was handled  
     Ok,
+    /// A message request asking the server to report how long it spent on the command
+    TimedRequest {
+        /// The command to run
+        command: Commands,
+    },
+    /// A message sent ahead of the response to a `TimedRequest`
+    ///
+    /// Messages never nest, so deserializing one has bounded depth.
+    Timing {
+        /// The time the server spent running the command, in microseconds
+        server_time_micros: u64,
+    },
+    /// A message response carrying the raw contents of a generation file
+    Generation {
+        /// The number of the generation
+        gen: u64,
+        /// The contents of the generation file
+        data: Vec<u8>,
+    },
+    /// A message response to `Compact` carrying the number of bytes reclaimed
+    Compacted {
+        /// The number of stale bytes dropped from the log
+        reclaimed: u64,
+    },
+    /// A message response to `SnapshotGet` with the values in the order of the keys
+    ///
+    /// No write is applied between reading the first and the last value.
+    Values {
+        /// The value of each key, `None` if the key does not exist
+        values: Vec<Option<String>>,
+    },
+    /// A message response to `Ttl`
+    Ttl {
+        /// The seconds left before the key expires, rounded to the nearest second,
+        /// -1 if it never expires and -2 if it does not exist
+        seconds: i64,
+    },
+    /// A message response to `ModifiedAt`
+    ModifiedAt {
+        /// When the value was last written in milliseconds since the Unix epoch, 0 if
+        /// it was written before this was tracked and `None` if the key does not exist
+        millis: Option<u64>,
+    },
+    /// A message response to `StrLen`
+    StrLen {
+        /// The length of the value in bytes, `None` if the key does not exist
+        len: Option<u64>,
+    },
+    /// A message response to `GetIfModifiedSince` carrying the value of the key
+    Value {
+        /// The value of the key, `None` if the key does not exist
+        value: Option<String>,
+    },
+    /// A message response to `GetIfModifiedSince` if the value was not written since
+    NotModified,
+    /// A message response to `Keys` with a page of keys in collation order
+    Keys {
+        /// The keys of the page
+        keys: Vec<String>,
+        /// The cursor listing the next page, `None` if this is the last page
+        next_cursor: Option<String>,
+    },
+    /// A message streaming one key/value pair in response to `Scan`
+    ScanItem {
+        /// The key of the pair
+        key: String,
+        /// The value of the pair
+        value: String,
+    },
+    /// A message ending the stream of `ScanItem` messages
+    ScanEnd,
+    /// A message response to `Stats`
+    Stats {
+        /// The statistics of the engine of the server
+        stats: EngineStats,
+    },
+    /// A message exchanged before a request carrying the crate version of the sender
+    Handshake {
+        /// The `CARGO_PKG_VERSION` of the sender
+        version: String,
+    },
+    /// A message sent after the handshake to authenticate with the shared secret of the server
+    Auth {
+        /// The shared secret
+        token: String,
+    },
+    /// A message sent after authenticating to confine the connection to a namespace
+    ///
+    /// The server stores the keys of the connection as `<namespace>:<key>`.
+    Namespace {
+        /// The name of the namespace, which must not be empty or contain `:`
+        namespace: String,
+    },
 }
 
 impl NetworkConnection {
@@ -43,9 +248,19 @@ impl NetworkConnection {
     ///
     /// This function will return an error if the serialization fails
     pub fn serialize_message(&self) -> Result<Vec<u8>> {
-        let mut s = flexbuffers::FlexbufferSerializer::new();
-        self.serialize(&mut s)?;
-        Ok(s.take_buffer())
+        self.serialize_message_with(&FlexbuffersCodec)
+    }
+
+    /// Returns the message of this [`NetworkConnection`] serialized with `codec`
+    ///
+    /// Both ends of a connection must agree on the codec, the framing of
+    /// [`NetworkConnection::send_network_message`] stays the same for all codecs.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the serialization fails
+    pub fn serialize_message_with(&self, codec: &impl Codec) -> Result<Vec<u8>> {
+        codec.encode(self)
     }
 
     /// Returns the NetworkConnection enum from a vector of bytes
@@ -54,44 +269,359 @@ impl NetworkConnection {
     ///
     /// This function will return an error if deserialization fails
     pub fn deserialize_message(buf: Vec<u8>) -> Result<NetworkConnection> {
-        let r = flexbuffers::Reader::get_root(buf.as_slice())?;
-        Ok(NetworkConnection::deserialize(r)?)
+        NetworkConnection::deserialize_message_with(buf, &FlexbuffersCodec)
+    }
+
+    /// Returns the NetworkConnection enum from a vector of bytes serialized with `codec`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if deserialization fails
+    pub fn deserialize_message_with(buf: Vec<u8>, codec: &impl Codec) -> Result<NetworkConnection> {
+        let message = codec.decode(buf.as_slice());
+        recycle_buffer(buf);
+        message
     }
 
     /// Serializes a message and sends it into a stream
     ///
+    /// The serializer is reused by the following messages sent on the same thread,
+    /// see [`set_buffer_pool_size`].
+    ///
     /// # Errors
     ///
     /// This function will return an error if the serialization fails
-    /// or writing to the TcpStream fails
+    /// or writing to the TcpStream fails. On a non-blocking stream, writes which
+    /// would block are retried for a few seconds before giving up
     pub fn send_network_message(
         network_connection: NetworkConnection,
         stream: &mut TcpStream,
     ) -> Result<()> {
-        let message = network_connection.serialize_message()?;
-        stream.write_all(&message.len().to_le_bytes())?;
-        stream.write_all(b"\n")?;
-        stream.write_all(network_connection.serialize_message()?.as_slice())?;
-        stream.flush()?;
-        Ok(())
+        let mut stream = RetryingStream::new(stream)?;
+        with_serializer(|serializer| {
+            network_connection.serialize(&mut *serializer)?;
+            let message = serializer.view();
+            stream.write_all(&(message.len() as u64).to_le_bytes())?;
+            stream.write_all(b"\n")?;
+            stream.write_all(message)?;
+            stream.flush()?;
+            Ok(())
+        })
     }
 
-    /// Receives a message from a TcpStream
+    /// Receives a message of any size from a TcpStream
+    ///
+    /// See [`NetworkConnection::receive_network_message_limited`].
     ///
     /// # Errors
     ///
-    /// This function will return an error if reading from the buffer fails
+    /// This function will return an error if reading from the stream fails, the
+    /// stream ends early, or the framing is malformed
     pub fn receive_network_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
-        let mut buf_reader = BufReader::new(stream);
-        let mut buf: Vec<u8> = Vec::new();
-        buf_reader.read_until(b'\n', &mut buf)?;
-        let content_size = usize::from_le_bytes(buf.trim_ascii().try_into().unwrap());
-        let mut content_buf = vec![0u8; content_size];
-        buf_reader.read_exact(&mut content_buf)?;
+        NetworkConnection::receive_network_message_limited(stream, u64::MAX)
+    }
+
+    /// Receives a message of at most `max_size` bytes from a TcpStream
+    ///
+    /// A message is framed by its length as 8 little-endian bytes and a newline. The
+    /// content is only buffered as it arrives, so a bogus length cannot cause a large
+    /// allocation by itself. On a non-blocking stream, reads which would block are
+    /// retried for a few seconds before giving up. The content is read into a buffer
+    /// of the pool of this thread, which takes it back once the message is
+    /// deserialized, see [`set_buffer_pool_size`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading from the stream fails, the
+    /// stream ends early, or the framing is malformed. Returns `KvsError::MessageTooLarge`
+    /// without reading the content if the announced size exceeds `max_size`
+    pub fn receive_network_message_limited(
+        stream: &mut TcpStream,
+        max_size: u64,
+    ) -> Result<Vec<u8>> {
+        let mut stream = RetryingStream::new(stream)?;
+        let mut size = [0u8; 8];
+        let mut newline = [0u8; 1];
+        stream.read_exact(&mut size)?;
+        stream.read_exact(&mut newline)?;
+        if newline != *b"\n" {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "malformed message framing").into(),
+            );
+        }
+        let content_size = u64::from_le_bytes(size);
+        if content_size > max_size {
+            return Err(KvsError::MessageTooLarge(content_size));
+        }
+
+        let mut content_buf = take_buffer();
+        (&mut stream)
+            .take(content_size)
+            .read_to_end(&mut content_buf)?;
+        if (content_buf.len() as u64) < content_size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
         Ok(content_buf)
     }
 }
 
+/// The number of received message buffers each thread keeps for reuse by default
+pub const DEFAULT_BUFFER_POOL_SIZE: usize = 4;
+/// Buffers which grew larger than this are freed instead of kept for reuse
+const MAX_POOLED_BUFFER_CAPACITY: usize = 1024 * 1024;
+
+static BUFFER_POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_POOL_SIZE);
+
+thread_local! {
+    /// Buffers of received messages kept for the next messages received on this thread
+    static BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    /// The serializer kept for the next message sent on this thread
+    static SERIALIZER: RefCell<Option<FlexbufferSerializer>> = const { RefCell::new(None) };
+}
+
+/// Sets how many buffers of received messages each thread keeps for reuse,
+/// [`DEFAULT_BUFFER_POOL_SIZE`] by default
+///
+/// Reusing buffers across the messages of a thread, along with the serializer of
+/// sent messages, saves allocations under high throughput. A size of 0 allocates
+/// fresh buffers and a fresh serializer for every message.
+pub fn set_buffer_pool_size(size: usize) {
+    BUFFER_POOL_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Returns an empty buffer, reused from the pool of this thread if it holds one
+fn take_buffer() -> Vec<u8> {
+    BUFFER_POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .map(|mut buf| {
+            buf.clear();
+            buf
+        })
+        .unwrap_or_default()
+}
+
+/// Keeps `buf` for reuse by the following messages, unless the pool of this thread is full
+fn recycle_buffer(buf: Vec<u8>) {
+    if buf.capacity() == 0 || buf.capacity() > MAX_POOLED_BUFFER_CAPACITY {
+        return;
+    }
+    BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < BUFFER_POOL_SIZE.load(Ordering::Relaxed) {
+            pool.push(buf);
+        }
+    });
+}
+
+/// Runs `f` with a reset serializer, the one kept by this thread unless pooling is disabled
+fn with_serializer<T>(f: impl FnOnce(&mut FlexbufferSerializer) -> Result<T>) -> Result<T> {
+    if BUFFER_POOL_SIZE.load(Ordering::Relaxed) == 0 {
+        return f(&mut FlexbufferSerializer::new());
+    }
+    SERIALIZER.with(|serializer| {
+        let mut serializer = serializer.borrow_mut();
+        let serializer = serializer.get_or_insert_with(FlexbufferSerializer::new);
+        serializer.reset();
+        let result = f(serializer);
+        // a large message leaves a large buffer behind, which is not worth keeping
+        if serializer.view().len() > MAX_POOLED_BUFFER_CAPACITY {
+            *serializer = FlexbufferSerializer::new();
+        }
+        result
+    })
+}
+
+/// How long a message may stall on a non-blocking stream before giving up
+const NONBLOCKING_DEADLINE: Duration = Duration::from_secs(5);
+/// How long to wait before retrying an operation on a non-blocking stream
+const NONBLOCKING_BACKOFF: Duration = Duration::from_millis(1);
+
+/// A stream retrying operations which fail with `WouldBlock` while the socket is non-blocking
+///
+/// Operations are retried with a short backoff until [`NONBLOCKING_DEADLINE`] has passed
+/// since the stream was wrapped. On a blocking socket `WouldBlock` only signals an
+/// expired read or write timeout, so it is passed on unchanged.
+struct RetryingStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> RetryingStream<'a> {
+    fn new(stream: &'a TcpStream) -> io::Result<Self> {
+        let deadline = is_nonblocking(stream)?.then(|| Instant::now() + NONBLOCKING_DEADLINE);
+        Ok(RetryingStream { stream, deadline })
+    }
+
+    fn retry<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match op() {
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        && self
+                            .deadline
+                            .is_some_and(|deadline| Instant::now() < deadline) =>
+                {
+                    thread::sleep(NONBLOCKING_BACKOFF)
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Read for RetryingStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream = self.stream;
+        self.retry(|| stream.read(buf))
+    }
+}
+
+impl Write for RetryingStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.stream;
+        self.retry(|| stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut stream = self.stream;
+        self.retry(|| stream.flush())
+    }
+}
+
+#[cfg(unix)]
+fn is_nonblocking(stream: &TcpStream) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: F_GETFL only reads the flags of a descriptor the stream keeps open
+    let flags = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_NONBLOCK != 0)
+}
+
+#[cfg(not(unix))]
+fn is_nonblocking(_stream: &TcpStream) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Returns whether two crate versions can talk to each other
+///
+/// Following semver, they must share the same major version, and the same minor
+/// version as well while the major version is 0.
+pub fn is_compatible_version(ours: &str, theirs: &str) -> bool {
+    fn release(version: &str) -> (Option<&str>, Option<&str>) {
+        let mut parts = version.split('.');
+        let major = parts.next();
+        let minor = parts.next().filter(|_| major == Some("0"));
+        (major, minor)
+    }
+    release(ours) == release(theirs)
+}
+
+/// Returns the time left before a key expires as reported by `Commands::Ttl`, like Redis
+///
+/// That is the seconds left rounded to the nearest second, -1 for a key which never
+/// expires and -2 for a missing key, given the result of [`KvStore::ttl`](crate::KvStore::ttl).
+pub fn ttl_seconds(ttl: Option<Option<Duration>>) -> i64 {
+    match ttl {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(ttl)) => i64::try_from((ttl.as_millis() + 500) / 1000).unwrap_or(i64::MAX),
+    }
+}
+
+/// Returns the key the server stores for `key` of database `db`, see `Commands::Select`
+///
+/// The keys of database 0 are stored as they are, so a store written before
+/// databases existed keeps its keys there. The keys of any other database are stored
+/// as `\0<db>\0<key>`: keys passed on a command line cannot hold a NUL character, so
+/// no existing key of database 0 names a key of another database.
+pub fn db_key(db: u64, key: &str) -> String {
+    if db == 0 {
+        key.to_owned()
+    } else {
+        format!("\0{}\0{}", db, key)
+    }
+}
+
+/// Returns the key of database `db` the server stores as `stored`, `None` if it
+/// belongs to another database
+///
+/// This reverses [`db_key`].
+pub fn key_in_db(db: u64, stored: &str) -> Option<&str> {
+    let in_db = stored.strip_prefix('\0').and_then(|rest| {
+        let (stored_db, key) = rest.split_once('\0')?;
+        let parsed: u64 = stored_db.parse().ok()?;
+        // only the form `db_key` writes, anything else is a key of database 0
+        (parsed != 0 && parsed.to_string() == stored_db).then_some((parsed, key))
+    });
+    match in_db {
+        Some((stored_db, key)) => (stored_db == db).then_some(key),
+        None => (db == 0).then_some(stored),
+    }
+}
+
+/// Sets `TCP_NODELAY` on `stream` and, with a `keepalive` idle time, enables TCP keepalive
+///
+/// Keepalive is only supported on Unix platforms and ignored elsewhere.
+///
+/// # Errors
+///
+/// This function will return an error if setting a socket option fails
+pub fn configure_stream(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(idle) = keepalive {
+        set_keepalive(stream, idle)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> Result<()> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let set = |level, name, value: libc::c_int| {
+        // SAFETY: the option value is a live c_int of the given size
+        let res = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    let idle = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)?;
+    #[cfg(target_vendor = "apple")]
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, idle)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> Result<()> {
+    Ok(())
+}
+
+/// Returns the engine recorded in the data directory at `path`, if any
+///
+/// # Errors
+///
+/// This function will return an error if the engine marker cannot be read
 pub fn get_current_engine(path: impl Into<PathBuf>) -> Result<Option<String>> {
     let mut file_path: PathBuf = path.into();
     file_path.push("ENGINE_MODE.txt");
@@ -102,10 +632,19 @@ pub fn get_current_engine(path: impl Into<PathBuf>) -> Result<Option<String>> {
     Ok(None)
 }
 
+/// Records `engine_type` as the engine used by the data directory at `path`
+///
+/// The marker is replaced atomically, so a crash leaves either the old or the new one.
+///
+/// # Errors
+///
+/// This function will return an error if the engine marker cannot be written
 pub fn log_engine(path: impl Into<PathBuf>, engine_type: String) -> Result<()> {
     let mut file_path: PathBuf = path.into();
     file_path.push("ENGINE_MODE.txt");
-    fs::create_dir_all(&file_path.parent().unwrap())?;
-    fs::write(file_path, engine_type)?;
+    fs::create_dir_all(file_path.parent().unwrap())?;
+    let temp_path = file_path.with_extension("tmp");
+    fs::write(&temp_path, engine_type)?;
+    fs::rename(temp_path, file_path)?;
     Ok(())
 }
@@ -1,19 +1,74 @@
-use crate::KvsEngine;
-use crate::Result;
+use crate::kvs::check_not_empty;
+use crate::{EngineStats, FlushPolicy, KvsEngine, PairIter, Result};
 use sled::Db;
+use std::path::PathBuf;
+use std::time::Instant;
 
+/// The sled backed engine for kvs crate
 pub struct SledKvsEngine {
     db: Db,
+    flush_policy: FlushPolicy,
+    // whether writes were made since the last flush
+    unflushed: bool,
+    // when the database was last flushed, and how often
+    last_flush: Instant,
+    flush_count: u64,
+}
+
+impl SledKvsEngine {
+    /// Opens a `SledKvsEngine` with the given path, flushing after every write
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors from sled while opening the database
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        SledKvsEngine::open_with_flush_policy(path, FlushPolicy::Always)
+    }
+
+    /// Opens a `SledKvsEngine` with the given path, flushing as `flush_policy` asks
+    ///
+    /// Sled's own periodic flushing is disabled, so writes are only flushed by the policy.
+    /// Every write is a commit of its own, so `Always` and `OnCommitBatch` behave the same.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors from sled while opening the database
+    pub fn open_with_flush_policy(
+        path: impl Into<PathBuf>,
+        flush_policy: FlushPolicy,
+    ) -> Result<Self> {
+        let db = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(None)
+            .open()?;
+        Ok(SledKvsEngine {
+            db,
+            flush_policy,
+            unflushed: false,
+            last_flush: Instant::now(),
+            flush_count: 0,
+        })
+    }
+
+    /// Flushes the write just made if the flush policy asks for it
+    fn commit(&mut self) -> Result<()> {
+        self.unflushed = true;
+        if self.flush_policy.syncs_commit(self.last_flush.elapsed()) {
+            self.flush()?;
+        }
+        Ok(())
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&mut self, key: String, value: String) -> Result<()> {
+        check_not_empty(&key)?;
         self.db.insert(key.as_bytes(), value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+        self.commit()
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
+        check_not_empty(&key)?;
         let value: Option<String> = self
             .db
             .get(key.as_bytes())?
@@ -23,8 +78,58 @@ impl KvsEngine for SledKvsEngine {
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
+        check_not_empty(&key)?;
         self.db.remove(key.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+        self.commit()
+    }
+
+    fn flush(&mut self) -> Result<u64> {
+        let flushed = self.db.flush()?;
+        if self.unflushed {
+            self.unflushed = false;
+            self.last_flush = Instant::now();
+            self.flush_count += 1;
+        }
+        Ok(flushed as u64)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            key_count: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            flush_count: self.flush_count,
+            compacting: false,
+            open_readers: 0,
+        })
+    }
+
+    /// Returns the key/value pairs in byte order of the keys
+    ///
+    /// Writes made while iterating may or may not be seen.
+    fn iter(&self) -> Result<PairIter> {
+        Ok(Box::new(self.db.iter().map(|pair| {
+            let (key, value) = pair?;
+            Ok((
+                String::from_utf8_lossy(&key).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ))
+        })))
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.db
+            .scan_prefix(prefix.as_bytes())
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ))
+            })
+            .collect()
     }
 }
@@ -1,3 +1,4 @@
+use crate::kvs::ValueType;
 use std::net::AddrParseError;
 use std::num::TryFromIntError;
 use std::{error, fmt, io};
@@ -13,12 +14,23 @@ pub enum KvsError {
     Deserializer(flexbuffers::DeserializationError),
     /// Reader error variant for kvs crate
     Reader(flexbuffers::ReaderError),
+    /// Bincode error variant for kvs crate
+    Bincode(bincode::Error),
+    /// JSON error variant for kvs crate
+    Json(serde_json::Error),
     /// Key does not exist error variant for kvs crate
     KeyDoesNotExist,
     /// Int conversion error variant for kvs crate
     TryFromInt(TryFromIntError),
-    /// Unknown Command Type
-    UnexpectedCommandType,
+    /// Unknown Command Type found in the log where a `Set` was expected
+    UnexpectedCommandType {
+        /// The key that was being read
+        key: String,
+        /// The generation of the log holding the record, 0 for an export
+        gen: u64,
+        /// The offset of the record in the log or export
+        pos: u64,
+    },
     /// IP Address Parse Error
     AddrParseError(AddrParseError),
     /// Unknown Engine Type
@@ -27,6 +39,94 @@ pub enum KvsError {
     SledError(sled::Error),
     /// Wrong Engine Type Selected
     WrongEngineType(String),
+    /// The directory was created with a different number of shards
+    WrongShardCount(String),
+    /// An error reported by a remote server
+    Remote(String),
+    /// The peer runs an incompatible crate version
+    IncompatibleVersion(String),
+    /// A network message announced a size above the limit
+    MessageTooLarge(u64),
+    /// The key was rejected by the key validator of the store
+    InvalidKey(String),
+    /// A log record does not span exactly the size given by its length prefix
+    MisalignedRecord(usize),
+    /// The value of the key is not of the type the operation expects
+    WrongValueType {
+        /// The key that was being read
+        key: String,
+        /// The type the value is stored with
+        value_type: ValueType,
+    },
+    /// Incrementing the integer value of the key overflowed
+    IntegerOverflow(String),
+    /// No generation number follows the given one
+    GenerationOverflow(u64),
+    /// The store was opened read-only and cannot be written to
+    ReadOnly,
+    /// A configuration value is invalid
+    Config(String),
+    /// The connection broke after a request was sent, so it may or may not have been applied
+    UnknownOutcome(io::Error),
+    /// A generation failed its integrity check
+    CorruptGeneration {
+        /// The number of the generation
+        gen: u64,
+        /// What is wrong with the generation
+        reason: String,
+    },
+}
+
+/// The broad category of a `KvsError`
+///
+/// Unlike the variants of `KvsError`, which grow with the crate, the set of kinds
+/// is stable, so consumers can match on it exhaustively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The key does not exist
+    NotFound,
+    /// Reading or writing the underlying storage failed
+    Io,
+    /// A record or message could not be encoded or decoded
+    Serialization,
+    /// The peer broke or refused the network protocol
+    Protocol,
+    /// The store or server was set up or opened inconsistently
+    Config,
+    /// Any other error, e.g. a rejected key or value
+    Other,
+}
+
+impl KvsError {
+    /// Returns the category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            KvsError::KeyDoesNotExist => ErrorKind::NotFound,
+            KvsError::Io(_) | KvsError::SledError(_) | KvsError::UnknownOutcome(_) => ErrorKind::Io,
+            KvsError::Serializer(_)
+            | KvsError::Deserializer(_)
+            | KvsError::Reader(_)
+            | KvsError::Bincode(_)
+            | KvsError::Json(_)
+            | KvsError::TryFromInt(_)
+            | KvsError::UnexpectedCommandType { .. }
+            | KvsError::MisalignedRecord(_)
+            | KvsError::CorruptGeneration { .. } => ErrorKind::Serialization,
+            KvsError::Remote(_)
+            | KvsError::IncompatibleVersion(_)
+            | KvsError::MessageTooLarge(_) => ErrorKind::Protocol,
+            KvsError::AddrParseError(_)
+            | KvsError::UnknownEngineType(_)
+            | KvsError::WrongEngineType(_)
+            | KvsError::WrongShardCount(_)
+            | KvsError::ReadOnly
+            | KvsError::Config(_) => ErrorKind::Config,
+            KvsError::InvalidKey(_)
+            | KvsError::WrongValueType { .. }
+            | KvsError::IntegerOverflow(_)
+            | KvsError::GenerationOverflow(_) => ErrorKind::Other,
+        }
+    }
 }
 
 impl fmt::Display for KvsError {
@@ -35,23 +135,79 @@ impl fmt::Display for KvsError {
             KvsError::Io(ref err) => write!(f, "IO error: {}", err),
             KvsError::Serializer(ref err) => write!(f, "Serialization error: {}", err),
             KvsError::Reader(ref err) => write!(f, "Reader error: {}", err),
+            KvsError::Bincode(ref err) => write!(f, "Bincode error: {}", err),
+            KvsError::Json(ref err) => write!(f, "JSON error: {}", err),
             KvsError::Deserializer(ref err) => write!(f, "Deserialization error: {}", err),
             KvsError::TryFromInt(ref err) => write!(f, "Deserialization error: {}", err),
             KvsError::KeyDoesNotExist => {
                 write!(f, "Key not found")
             }
-            KvsError::UnexpectedCommandType => {
-                write!(f, "Unexpected command type")
-            }
+            KvsError::UnexpectedCommandType { key, gen, pos } => write!(
+                f,
+                "Unexpected command type for key {} in generation {} at offset {}",
+                key, gen, pos
+            ),
             KvsError::AddrParseError(ref err) => write!(f, "IP Address Parse error: {}", err),
             KvsError::UnknownEngineType(eng_type) => write!(f, "Unknown Engine type: {}", eng_type),
             KvsError::SledError(ref err) => write!(f, "Sled Error: {}", err),
-            KvsError::WrongEngineType(engine_type) => write!(f, "Wrong Engine Type Detected"),
+            KvsError::WrongEngineType(eng_type) => write!(
+                f,
+                "The data directory is used by a different engine than {}",
+                eng_type
+            ),
+            KvsError::WrongShardCount(count) => {
+                write!(f, "Store was created with {} shards", count)
+            }
+            KvsError::Remote(error) => write!(f, "Remote error: {}", error),
+            KvsError::IncompatibleVersion(version) => {
+                write!(f, "Incompatible peer version: {}", version)
+            }
+            KvsError::MessageTooLarge(size) => {
+                write!(f, "Message of {} bytes exceeds the size limit", size)
+            }
+            KvsError::InvalidKey(key) => write!(f, "Invalid key: {:?}", key),
+            KvsError::MisalignedRecord(size) => write!(
+                f,
+                "Log record does not match its length prefix of {} bytes",
+                size
+            ),
+            KvsError::WrongValueType { key, value_type } => {
+                write!(f, "Value of key {} is of type {:?}", key, value_type)
+            }
+            KvsError::IntegerOverflow(key) => write!(f, "Integer overflow for key {}", key),
+            KvsError::GenerationOverflow(gen) => {
+                write!(f, "No generation number is left after generation {}", gen)
+            }
+            KvsError::ReadOnly => write!(f, "The store was opened read-only"),
+            KvsError::Config(error) => write!(f, "Configuration error: {}", error),
+            KvsError::UnknownOutcome(ref err) => write!(
+                f,
+                "Connection broke after sending the request, it may or may not have been applied: {}",
+                err
+            ),
+            KvsError::CorruptGeneration { gen, reason } => {
+                write!(f, "Generation {} is corrupted: {}", gen, reason)
+            }
         }
     }
 }
 
-impl error::Error for KvsError {}
+impl error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KvsError::Io(ref err) | KvsError::UnknownOutcome(ref err) => Some(err),
+            KvsError::Serializer(ref err) => Some(err),
+            KvsError::Deserializer(ref err) => Some(err),
+            KvsError::Reader(ref err) => Some(err),
+            KvsError::Bincode(ref err) => Some(err),
+            KvsError::Json(ref err) => Some(err),
+            KvsError::TryFromInt(ref err) => Some(err),
+            KvsError::AddrParseError(ref err) => Some(err),
+            KvsError::SledError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> Self {
@@ -77,6 +233,18 @@ impl From<flexbuffers::ReaderError> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> Self {
+        KvsError::Bincode(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> Self {
+        KvsError::Json(err)
+    }
+}
+
 impl From<TryFromIntError> for KvsError {
     fn from(err: TryFromIntError) -> Self {
         KvsError::TryFromInt(err)
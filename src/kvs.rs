@@ -1,6 +1,13 @@
+use crate::codec::{Codec, CodecKind};
 use crate::error::KvsError;
+use crate::options::{
+    Collation, DirLayout, FlushPolicy, KvStoreOptions, OpenMode, SharedFileHook, StoreEvent,
+    TtlClock,
+};
+use crate::trace::OpSpan;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::OpenOptions;
@@ -8,49 +15,507 @@ use std::fs::{self, File};
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::{prelude::*, SeekFrom};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::{io, result};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use std::{io, result, thread};
 
 /// Result type for the kvs crate
 pub type Result<T> = result::Result<T, KvsError>;
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// The key/value pairs listed by [`KvsEngine::iter`]
+pub type PairIter = Box<dyn Iterator<Item = Result<(String, String)>>>;
+
+const INDEX_SNAPSHOT: &str = "INDEX_SNAPSHOT";
+// stems of the files an interrupted compaction may leave behind, the first is the
+// name a compaction writes its copy under
+const COMPACTION_TEMP_STEMS: [&str; 2] = ["temp", "temp_log"];
+const ARCHIVE_DIR: &str = "archive";
+// the magic number starting every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+// the offset basis of the FNV-1a checksum in generation footers
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+// the longest encoded footer searched for at the end of a generation
+const MAX_FOOTER_LEN: usize = 256;
 
 /// The trait for kvs store
+///
+/// Keys must not be empty: every engine fails `set`, `get` and `remove` of the
+/// empty key with `KvsError::InvalidKey`.
 pub trait KvsEngine {
+    /// Sets the value of a string key to a string
     fn set(&mut self, key: String, value: String) -> Result<()>;
+    /// Gets the string value of a given string key
     fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// Removes a given key
     fn remove(&mut self, key: String) -> Result<()>;
+    /// Returns the key/value pairs whose keys start with `prefix`, in byte order of the keys
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>>;
+    /// Returns every key/value pair, read one at a time as the iterator advances
+    ///
+    /// The order of the pairs depends on the engine.
+    fn iter(&self) -> Result<PairIter>;
+    /// Makes all writes durable, returning the number of bytes that were still buffered
+    fn flush(&mut self) -> Result<u64>;
+    /// Returns the number of bytes the engine occupies on disk
+    fn size_on_disk(&self) -> Result<u64>;
+    /// Returns the statistics every engine reports
+    fn stats(&self) -> Result<EngineStats>;
+}
+
+/// Statistics reported by every engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of live keys
+    pub key_count: u64,
+    /// The number of bytes the engine occupies on disk
+    pub disk_bytes: u64,
+    /// The number of times writes were synced to disk since the engine was opened
+    pub flush_count: u64,
+    /// Whether a compaction is running
+    #[serde(default)]
+    pub compacting: bool,
+    /// The number of files held open for reading, one per generation unless
+    /// [`KvStoreOptions::reader_cache_size`](crate::KvStoreOptions::reader_cache_size) closes some
+    #[serde(default)]
+    pub open_readers: u64,
+}
+
+/// Tells whether a compaction of a `KvStore` is running, without locking the store
+///
+/// Returned by [`KvStore::compaction_status`], it stays up to date for as long as it
+/// is kept.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStatus(Arc<AtomicBool>);
+
+impl CompactionStatus {
+    /// Returns whether a compaction is running
+    pub fn is_compacting(&self) -> bool {
+        self.0.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Marks a compaction as running until the returned guard is dropped
+    fn start(&self) -> CompactionGuard {
+        self.0.store(true, atomic::Ordering::SeqCst);
+        CompactionGuard(Arc::clone(&self.0))
+    }
+}
+
+/// Marks the compaction as finished when dropped, however it ended
+struct CompactionGuard(Arc<AtomicBool>);
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Shares the syncs of the log between the writers of a shared store, see
+/// [`KvStore::set_shared`]
+#[derive(Default)]
+struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct GroupCommitState {
+    // the generation and position up to which the log is known to be synced
+    synced: (u64, u64),
+    // the furthest generation and position a waiting writer appended up to, with
+    // the file of that generation
+    pending: Option<((u64, u64), Arc<LogFile>)>,
+    // whether a leader is syncing the log for the writers waiting behind it
+    syncing: bool,
+    syncs: u64,
+}
+
+impl GroupCommit {
+    /// Waits until the log is synced up to `pos` of the generation `file` belongs to
+    ///
+    /// The first writer to wait while no sync is running leads: it syncs the furthest
+    /// position any waiting writer appended up to, and wakes every writer it covered.
+    fn wait(&self, pos: (u64, u64), file: &Arc<LogFile>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= pos {
+                return Ok(());
+            }
+            if state
+                .pending
+                .as_ref()
+                .is_none_or(|(pending, _)| *pending < pos)
+            {
+                state.pending = Some((pos, Arc::clone(file)));
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            let (target, target_file) = state.pending.take().unwrap();
+            state.syncing = true;
+            drop(state);
+            let synced = target_file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if synced.is_ok() {
+                state.synced = state.synced.max(target);
+                state.syncs += 1;
+            }
+            self.synced.notify_all();
+            synced?;
+        }
+    }
+
+    /// Returns the number of syncs made by leaders
+    fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+}
+
+/// A write appended to a shared store, durable once the group commit synced past it
+struct SyncTicket {
+    group_commit: Arc<GroupCommit>,
+    pos: (u64, u64),
+    file: Arc<LogFile>,
+}
+
+/// A record decoded from a log by [`KvStore::decode_log`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The offset of the record in the log
+    pub offset: u64,
+    /// The length of the record including its length prefix
+    pub len: u64,
+    /// The key the record is about
+    pub key: String,
+    /// The value set by the record, or `None` if it removes the key
+    pub value: Option<String>,
+}
+
+/// How [`KvStore::verify`] checked a generation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The checksum of its footer matched, without decoding its records
+    Footer,
+    /// Every record was decoded
+    Replay,
+}
+
+/// Where the record holding a value lives, returned by [`KvStore::get_meta`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// The generation of the log holding the record
+    pub gen: u64,
+    /// The offset of the record in the log
+    pub pos: u64,
+    /// The length of the record including its length prefix
+    pub len: u64,
+}
+
+/// The type of a value, set by the typed API of [`KvStore`]
+///
+/// Values are stored as strings whatever their type: integers and floats in their
+/// decimal form and bytes in lowercase hex, which is also what the string API reads
+/// back. Values written through the string API are of type `Str`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ValueType {
+    /// A string
+    #[default]
+    Str,
+    /// A 64-bit signed integer
+    Int,
+    /// A 64-bit float
+    Float,
+    /// A byte string
+    Bytes,
+}
+
+impl ValueType {
+    fn is_str(&self) -> bool {
+        *self == ValueType::Str
+    }
 }
 
 /// The store for kvs crate
+///
+/// The data directory holds one `<gen>.log` file per generation, where `<gen>` is
+/// the decimal generation number, laid out as described by [`DirLayout`]. The
+/// names can be changed with [`KvStoreOptions::log_prefix`] and
+/// [`KvStoreOptions::log_extension`]. Any other file named that way, as well as a
+/// temporary index snapshot, is a leftover of an interrupted write and is removed
+/// on open. So are empty generation files, which
+/// a store leaves behind when it is closed or crashes before writing to them.
+/// Compacted generations may be kept in an `archive/` subdirectory, see
+/// [`KvStoreOptions::retain_compacted`].
 pub struct KvStore {
     // directory for the log and other data
     path: PathBuf,
     // map generation number to the file reader
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    // writer of the current log
-    writer: BufWriterWithPos<File>,
+    readers: Readers,
+    // writer of the current log, `None` for a read-only store
+    writer: Option<BufWriterWithPos<LogFile>>,
     current_gen: u64,
-    index: BTreeMap<String, CommandPos>,
+    index: BTreeMap<IndexKey, CommandPos>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // the number of bytes representing live commands
+    live: u64,
+    // when the log was last synced, and how often
+    last_sync: Instant,
+    sync_count: u64,
+    // the wall-clock and monotonic time when the store was opened
+    clock_anchor: (u64, Instant),
+    // the wall-clock time of the last write
+    last_write_wall: u64,
+    // when the last write was committed
+    last_write: Instant,
+    // whether the open readers were reported near the limit on open files
+    readers_near_fd_limit: bool,
+    // whether `close` already persisted the index, so dropping the store skips it
+    closed: bool,
+    compaction_status: CompactionStatus,
+    // a merge copied by `compact_shared` between releases of the lock
+    merge_job: Option<MergeJob>,
+    // where compactions are handed to the background thread of a shared store
+    compaction_requests: Option<mpsc::Sender<usize>>,
+    // whether a compaction was handed over and has not started yet
+    compaction_requested: bool,
+    // shares the syncs of the writes made through `set_shared` and `remove_shared`
+    group_commit: Arc<GroupCommit>,
+    // whether a commit leaves its sync to the group commit, and whether one did
+    defer_sync: bool,
+    sync_deferred: bool,
+    // a handle on the file of the current generation, synced by group commit leaders
+    sync_file: Option<(u64, Arc<LogFile>)>,
+    naming: LogNaming,
+    options: KvStoreOptions,
 }
 
 /// The command set for serialization and storage
 #[derive(Debug, Serialize, Deserialize)]
 enum KvsLogLine {
-    Set { key: String, value: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        // left out for strings, whose records are the same as before types existed
+        #[serde(default, skip_serializing_if = "ValueType::is_str")]
+        value_type: ValueType,
+        // the deadline in milliseconds since the Unix epoch, left out for permanent keys
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        // when the value was written in milliseconds since the Unix epoch, 0 for
+        // records written before it was tracked, which is left out so they keep
+        // their encoding
+        #[serde(default, skip_serializing_if = "is_zero")]
+        modified_at: u64,
+    },
+    Rm {
+        key: String,
+    },
+    // ends a sealed generation: the number of records before it and the checksum of
+    // their bytes
+    Footer {
+        records: u64,
+        checksum: u64,
+    },
+}
+
+fn is_zero(millis: &u64) -> bool {
+    *millis == 0
+}
+
+/// A `KvsLogLine` always holding every field, for codecs which are not self-describing
+///
+/// New variants are only ever appended, so older records keep their encoding.
+#[derive(Serialize, Deserialize)]
+enum DenseLogLine {
+    Set {
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+    },
+    Rm {
+        key: String,
+    },
+    Footer {
+        records: u64,
+        checksum: u64,
+    },
+    // a `Set` along with the time it was written
+    SetModified {
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        modified_at: u64,
+    },
+}
+
+impl From<KvsLogLine> for DenseLogLine {
+    fn from(logline: KvsLogLine) -> Self {
+        match logline {
+            KvsLogLine::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                modified_at,
+            } => DenseLogLine::SetModified {
+                key,
+                value,
+                value_type,
+                expires_at,
+                modified_at,
+            },
+            KvsLogLine::Rm { key } => DenseLogLine::Rm { key },
+            KvsLogLine::Footer { records, checksum } => DenseLogLine::Footer { records, checksum },
+        }
+    }
+}
+
+impl From<DenseLogLine> for KvsLogLine {
+    fn from(logline: DenseLogLine) -> Self {
+        match logline {
+            DenseLogLine::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+            } => KvsLogLine::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                modified_at: 0,
+            },
+            DenseLogLine::SetModified {
+                key,
+                value,
+                value_type,
+                expires_at,
+                modified_at,
+            } => KvsLogLine::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                modified_at,
+            },
+            DenseLogLine::Rm { key } => KvsLogLine::Rm { key },
+            DenseLogLine::Footer { records, checksum } => KvsLogLine::Footer { records, checksum },
+        }
+    }
+}
+
+/// A key of the index, ordered by the collation of the store
+///
+/// Two keys are only equal if their bytes are, whatever the collation.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct IndexKey {
+    key: String,
+    collation: Collation,
+}
+
+impl IndexKey {
+    pub(crate) fn new(key: String, collation: Collation) -> Self {
+        IndexKey { key, collation }
+    }
+
+    /// Returns the key, dropping the collation
+    pub(crate) fn into_key(self) -> String {
+        self.key
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.collation.compare(&self.key, &other.key)
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// snapshots store plain keys, the collation is reapplied on open
+impl Serialize for IndexKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.key)
+    }
 }
 
 /// Represents the position and length of a serialized command in the log
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    modified_at: u64,
+    // the length of the value in bytes, missing in snapshots written before it was tracked
+    #[serde(default)]
+    value_len: Option<u64>,
+}
+
+impl CommandPos {
+    /// Returns whether the record is still live at `now`, in milliseconds since the Unix epoch
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|deadline| deadline > now)
+    }
+}
+
+/// A merge of generations in progress, copied a batch of records at a time
+struct MergeJob {
+    compaction_gen: u64,
+    merged_gens: Vec<u64>,
+    merges_all: bool,
+    // the size of the merged generation files
+    merged_bytes: u64,
+    // the bytes of the live records in the merged generations when the merge started
+    merged_live: u64,
+    uncompacted_at_start: u64,
+    writer: BufWriterWithPos<LogFile>,
+    buf: Vec<u8>,
+    // the key the next batch starts from
+    resume_at: Option<IndexKey>,
+    // the generation and position of every record copied, with its copy
+    compacted: Vec<(u64, u64, CommandPos)>,
+    new_pos: u64,
+    started: Instant,
+    _compacting: CompactionGuard,
+}
+
+impl MergeJob {
+    /// Returns how far the copy got ahead of `bytes_per_sec`
+    fn lead(&self, bytes_per_sec: Option<u64>) -> Duration {
+        let Some(bytes_per_sec) = bytes_per_sec else {
+            return Duration::ZERO;
+        };
+        let due = Duration::from_secs_f64(self.new_pos as f64 / bytes_per_sec as f64);
+        due.saturating_sub(self.started.elapsed())
+    }
+}
+
+/// A persisted copy of the index
+///
+/// Records appended after `replay_pos` in generation `replay_gen`, and all records
+/// of later generations, are not covered by the snapshot and are replayed on open.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot<I> {
+    replay_gen: u64,
+    replay_pos: u64,
+    uncompacted: u64,
+    index: I,
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -59,6 +524,111 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            modified_at: 0,
+            value_len: None,
+        }
+    }
+}
+
+/// The readers of the generation files of a store
+///
+/// Every generation is tracked, but with a cache size only that many readers are
+/// kept open: the one used least recently is closed to make room, and closed
+/// readers are reopened when needed.
+struct Readers {
+    path: PathBuf,
+    naming: LogNaming,
+    cache_size: Option<usize>,
+    // every generation, with its reader unless it was closed
+    gens: BTreeMap<u64, Option<BufReaderWithPos<File>>>,
+    // the tick at which each open reader was last used
+    last_used: HashMap<u64, u64>,
+    tick: u64,
+}
+
+impl Readers {
+    fn new(path: &Path, naming: LogNaming, cache_size: Option<usize>) -> Self {
+        Readers {
+            path: path.to_owned(),
+            naming,
+            cache_size,
+            gens: BTreeMap::new(),
+            last_used: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Tracks generation `gen` along with its open reader
+    fn insert(&mut self, gen: u64, reader: BufReaderWithPos<File>) {
+        self.make_room();
+        self.gens.insert(gen, Some(reader));
+        self.touch(gen);
+    }
+
+    /// Returns the reader of generation `gen`, reopening it if it was closed
+    ///
+    /// Panics if the generation is not tracked
+    fn get_mut(&mut self, gen: u64) -> Result<&mut BufReaderWithPos<File>> {
+        if self
+            .gens
+            .get(&gen)
+            .expect("Cannot find log reader")
+            .is_none()
+        {
+            self.make_room();
+            let file = File::open(log_path(&self.path, gen, &self.naming))?;
+            self.gens.insert(gen, Some(BufReaderWithPos::new(file)?));
+        }
+        self.touch(gen);
+        Ok(self
+            .gens
+            .get_mut(&gen)
+            .and_then(Option::as_mut)
+            .expect("Cannot find log reader"))
+    }
+
+    /// Stops tracking generation `gen`, closing its reader
+    fn remove(&mut self, gen: u64) {
+        self.gens.remove(&gen);
+        self.last_used.remove(&gen);
+    }
+
+    /// Returns the tracked generation numbers in ascending order
+    fn gens(&self) -> impl Iterator<Item = u64> + '_ {
+        self.gens.keys().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.gens.len()
+    }
+
+    /// Returns the number of generations whose reader is open
+    fn open_count(&self) -> usize {
+        self.gens.values().filter(|reader| reader.is_some()).count()
+    }
+
+    fn touch(&mut self, gen: u64) {
+        self.tick += 1;
+        self.last_used.insert(gen, self.tick);
+    }
+
+    /// Closes the least recently used readers until one more fits in the cache
+    fn make_room(&mut self) {
+        let Some(cache_size) = self.cache_size else {
+            return;
+        };
+        while self.last_used.len() >= cache_size.max(1) {
+            let lru = *self
+                .last_used
+                .iter()
+                .min_by_key(|(_, &tick)| tick)
+                .expect("Cache is not empty")
+                .0;
+            self.last_used.remove(&lru);
+            if let Some(reader) = self.gens.get_mut(&lru) {
+                *reader = None;
+            }
         }
     }
 }
@@ -104,6 +674,13 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
+    // the position up to which the data is known to be on disk
+    synced_pos: u64,
+    // the records written and the checksum of their bytes, for the footer; only
+    // complete if the writer started at the beginning of the file
+    records: u64,
+    checksum: u64,
+    from_start: bool,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
@@ -112,14 +689,92 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
+            synced_pos: pos,
+            records: 0,
+            checksum: FNV_OFFSET,
+            from_start: pos == 0,
+        })
+    }
+}
+
+/// A generation file written by the store, whose writes and syncs go through the
+/// file hook of the store if it has one
+struct LogFile {
+    file: File,
+    path: PathBuf,
+    hook: Option<SharedFileHook>,
+}
+
+impl LogFile {
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()?;
+        if let Some(hook) = &self.hook {
+            hook.0.synced(&self.path);
+        }
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if let Some(hook) = &self.hook {
+            hook.0.synced(&self.path);
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<LogFile> {
+        Ok(LogFile {
+            file: self.file.try_clone()?,
+            path: self.path.clone(),
+            hook: self.hook.clone(),
         })
     }
 }
 
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match &self.hook {
+            Some(hook) => hook.0.write(&self.path, buf.len())?.min(buf.len()),
+            None => buf.len(),
+        };
+        self.file.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl BufWriterWithPos<LogFile> {
+    /// Flushes the buffer and makes sure the written data reaches the disk
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.synced_pos = self.pos;
+        Ok(())
+    }
+
+    /// Flushes the buffer and makes sure the written data and the metadata of the
+    /// file reach the disk
+    fn sync_all(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.synced_pos = self.pos;
+        Ok(())
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
         self.pos += len as u64;
+        self.checksum = fnv1a(self.checksum, &buf[..len]);
         Ok(len)
     }
 
@@ -138,11 +793,13 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
 impl KvsEngine for KvStore {
     /// Sets the value of a string key to a string
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// If the key already exists, the previous value will be overwritten and the key
+    /// made permanent, dropping any TTL it had. [`KvStore::update_value`] keeps it.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
     ///
     /// ```
     /// # use crate::kvs::KvsEngine;
@@ -157,27 +814,7 @@ impl KvsEngine for KvStore {
     /// # }
     /// ```
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let logline = KvsLogLine::Set {
-            key: key.clone(),
-            value: value.clone(),
-        };
-
-        let start_pos = self.writer.pos;
-        serialize_to_log(&mut self.writer, logline)?;
-
-        // place the element in the index
-        if let Some(old_cmd) = self
-            .index
-            .insert(key, (self.current_gen, start_pos..self.writer.pos).into())
-        {
-            self.uncompacted += old_cmd.len;
-        }
-
-        // check for defragmentation
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compaction()?;
-        }
-        Ok(())
+        self.set_typed(key, value, ValueType::Str)
     }
 
     /// Gets the string value of a given string key
@@ -187,7 +824,8 @@ impl KvsEngine for KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during log replay.
-    /// Also returns `KvsError::UnexpectedCommandType` if the given command type is unexpected
+    /// Also returns `KvsError::UnexpectedCommandType` with the key and record location
+    /// if the indexed record is not a `Set`
     ///
     /// ```
     /// # use crate::kvs::KvsEngine;
@@ -202,19 +840,17 @@ impl KvsEngine for KvStore {
     /// # }
     /// ```
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            if let KvsLogLine::Set { key: _, value } = deserialize_from_log(reader)? {
+        let span = OpSpan::get(key.len());
+        check_not_empty(&key)?;
+        let key = self.index_key(key);
+        match self.live_entry(&key) {
+            Some(cmd_pos) => {
+                span.record_gen(cmd_pos.gen);
+                let value = read_value(&mut self.readers, &key.key, &cmd_pos, self.options.codec)?;
+                span.record_value_size(value.len());
                 Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
             }
-        } else {
-            Ok(None)
+            None => Ok(None),
         }
     }
 
@@ -232,18 +868,106 @@ impl KvsEngine for KvStore {
     /// # }
     /// ```
     fn remove(&mut self, key: String) -> Result<()> {
+        let span = OpSpan::remove(key.len());
+        check_not_empty(&key)?;
         // Assert the key is in the index
-        if !self.index.contains_key(&key) {
+        let key = self.index_key(key);
+        if self.live_entry(&key).is_none() {
             return Err(KvsError::KeyDoesNotExist);
         }
-        let logline = KvsLogLine::Rm { key: key.clone() };
-        serialize_to_log(&mut self.writer, logline)?;
-        // remove the element from the index
-        if let Some(old_cmd) = self.index.remove(&key) {
-            self.uncompacted += old_cmd.len;
-        }
+        span.record_gen(self.current_gen);
+        self.append_rm(&key)?;
+        self.commit()?;
+        self.emit(Some(StoreEvent::Removed { key: key.key }));
         Ok(())
     }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, in byte order of the keys
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.get_prefixed(prefix)?.into_iter().collect())
+    }
+
+    /// Returns the key/value pairs live when it is called, in collation order
+    ///
+    /// The values are read through file handles of the iterator as it advances, so
+    /// a compaction meanwhile may remove a generation it still needs, failing the
+    /// pairs read from there.
+    ///
+    /// # Errors
+    ///
+    /// An I/O or deserialization error while reading a value is yielded in place of its pair
+    fn iter(&self) -> Result<PairIter> {
+        let now = self.now_millis();
+        let entries: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_live(now))
+            .map(|(key, cmd_pos)| (key.key.clone(), *cmd_pos))
+            .collect();
+        Ok(Box::new(StoreIter {
+            path: self.path.clone(),
+            naming: self.naming.clone(),
+            codec: self.options.codec,
+            entries: entries.into_iter(),
+            reader: None,
+        }))
+    }
+
+    /// Syncs the current log to disk and persists the index if `index_snapshot` is enabled
+    ///
+    /// Returns the number of bytes which were still buffered
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing
+    fn flush(&mut self) -> Result<u64> {
+        let buffered = self
+            .writer
+            .as_ref()
+            .map_or(0, |writer| writer.writer.buffer().len() as u64);
+        self.sync_log()?;
+        if self.options.index_snapshot && !self.options.read_only {
+            self.write_index_snapshot()?;
+        }
+        Ok(buffered)
+    }
+
+    /// Returns the total size of the generation files
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the file sizes
+    fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for gen in self.readers.gens() {
+            size += fs::metadata(log_path(&self.path, gen, &self.naming))?.len();
+        }
+        Ok(size)
+    }
+
+    /// Counts the keys of the index and sums the sizes of the generation files
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the file sizes
+    fn stats(&self) -> Result<EngineStats> {
+        let now = self.now_millis();
+        Ok(EngineStats {
+            key_count: self
+                .index
+                .values()
+                .filter(|cmd_pos| cmd_pos.is_live(now))
+                .count() as u64,
+            disk_bytes: self.size_on_disk()?,
+            flush_count: self.sync_count + self.group_commit.syncs(),
+            compacting: self.is_compacting(),
+            open_readers: self.open_reader_count() as u64,
+        })
+    }
 }
 
 impl KvStore {
@@ -263,166 +987,2761 @@ impl KvStore {
     /// # }
     /// ```
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens an existing `KvStore` in the given path without writing to it
+    ///
+    /// See [`KvStoreOptions::read_only`].
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during log replay, including
+    /// when the directory does not exist
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::open_with_options(path, KvStoreOptions::default().read_only(true))
+    }
+
+    /// Opens a `KvStore` to be shared between threads behind a lock
+    ///
+    /// With [`KvStoreOptions::compaction_interval`] set, a background thread compacts
+    /// the store at every interval with [`KvStore::compact_shared`]. With
+    /// [`KvStoreOptions::compaction_bytes_per_sec`] set, the compactions writes call
+    /// for are handed to that thread too, so the throttle never sleeps with the lock
+    /// held. With [`KvStoreOptions::flush_idle_after`] set, another thread syncs the
+    /// writes once the store is idle. The threads stop once every handle to the store
+    /// is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It propagates the errors of [`KvStore::open_with_options`]
+    pub fn open_shared(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<Arc<Mutex<KvStore>>> {
+        let interval = options.compaction_interval;
+        let throttled = options.compaction_bytes_per_sec.is_some();
+        let idle_after = options.flush_idle_after;
+        let mut store = KvStore::open_with_options(path, options)?;
+        let (requests, requested) = mpsc::channel();
+        if throttled {
+            store.compaction_requests = Some(requests);
+        }
+        let store = Arc::new(Mutex::new(store));
+        if interval.is_some() || throttled {
+            let store = Arc::downgrade(&store);
+            thread::spawn(move || schedule_compaction(store, interval, requested));
+        }
+        if let Some(idle_after) = idle_after {
+            let store = Arc::downgrade(&store);
+            thread::spawn(move || schedule_idle_flush(store, idle_after));
+        }
+        Ok(store)
+    }
+
+    /// Opens a `KvStore` with the given path and options
+    ///
+    /// This will create a new directory if the given one does not exist, unless
+    /// the store is opened read-only
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during log replay.
+    /// Also returns `KvsError::Config` if an option has an invalid value
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        options.validate()?;
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let naming = LogNaming::new(&options);
+        let mut unknown_files = Vec::new();
+        if !options.read_only {
+            fs::create_dir_all(&path)?;
+            unknown_files = remove_orphaned_files(&path, &naming)?;
+        }
 
         let mut index = BTreeMap::new();
-        let mut readers = HashMap::new();
+        let mut readers = Readers::new(&path, naming.clone(), options.reader_cache_size);
 
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = sorted_gen_list(&path, &naming)?;
+        // generation numbers are never reused, even those of removed empty generations
+        let current_gen = gen_after(*gen_list.last().unwrap_or(&0), 1)?;
+        let gen_list = if options.read_only {
+            gen_list
+        } else {
+            remove_empty_generations(&path, gen_list, &naming)?
+        };
         let mut uncompacted = 0;
 
+        // start from the index snapshot when there is a usable one
+        let mut replay_from = (0, 0);
+        if options.index_snapshot {
+            if let Some(snapshot) = read_index_snapshot(&path, &gen_list, &naming) {
+                replay_from = (snapshot.replay_gen, snapshot.replay_pos);
+                uncompacted = snapshot.uncompacted;
+                index = snapshot
+                    .index
+                    .into_iter()
+                    .map(|(key, cmd_pos)| (IndexKey::new(key, options.collation), cmd_pos))
+                    .collect();
+            }
+        }
+
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
+            if options.open_mode == OpenMode::Strict {
+                let data = fs::read(log_path(&path, gen, &naming))?;
+                if let Err(reason) = verify_footer(&data, options.codec) {
+                    return Err(KvsError::CorruptGeneration { gen, reason });
+                }
+            }
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen, &naming))?)?;
+            if gen >= replay_from.0 {
+                let start = if gen == replay_from.0 {
+                    replay_from.1
+                } else {
+                    0
+                };
+                uncompacted += load(
+                    gen,
+                    start,
+                    &mut reader,
+                    &mut index,
+                    options.collation,
+                    options.open_mode,
+                    options.codec,
+                )?;
+            }
             readers.insert(gen, reader);
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let writer = if options.read_only {
+            None
+        } else {
+            Some(new_log_file(
+                &path,
+                current_gen,
+                &naming,
+                &mut readers,
+                options.file_hook.as_ref(),
+            )?)
+        };
+        let live = index.values().map(|cmd_pos| cmd_pos.len).sum();
 
-        Ok(KvStore {
+        let mut store = KvStore {
             path,
             readers,
             writer,
             current_gen,
             index,
             uncompacted,
-        })
+            live,
+            last_sync: Instant::now(),
+            sync_count: 0,
+            clock_anchor: (unix_millis(options.clock.wall()), options.clock.monotonic()),
+            last_write_wall: unix_millis(options.clock.wall()),
+            last_write: Instant::now(),
+            readers_near_fd_limit: false,
+            closed: false,
+            compaction_status: CompactionStatus::default(),
+            merge_job: None,
+            compaction_requests: None,
+            compaction_requested: false,
+            group_commit: Arc::default(),
+            defer_sync: false,
+            sync_deferred: false,
+            sync_file: None,
+            naming,
+            options,
+        };
+        for path in unknown_files {
+            store.emit(Some(StoreEvent::UnknownFile { path }));
+        }
+        store.check_open_readers();
+        Ok(store)
     }
 
-    /// Clears stale entries in the log
-    fn compaction(&mut self) -> Result<()> {
-        // Increase current gen by 2. Current gen + 1 is for the compaction file.
-
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
-
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
-
-        let mut new_pos = 0;
+    /// Sets the values of several keys, committing them to the log together
+    ///
+    /// The records are committed once for the whole batch, so with the `OnCommitBatch`
+    /// flush policy the batch costs a single fsync instead of one per key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` without writing anything if any key is
+    /// rejected by the `key_validator`
+    pub fn set_many(&mut self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        let pairs: Vec<_> = pairs.into_iter().collect();
+        for (key, _) in &pairs {
+            self.validate_key(key)?;
+        }
 
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut events = Vec::new();
+        let modified_at = unix_millis(self.options.clock.wall());
+        for (key, value) in pairs {
+            if self.wants_events() {
+                events.push(StoreEvent::Set { key: key.clone() });
+            }
+            self.append_set(key, value, ValueType::Str, None, modified_at)?;
+            if self.options.flush_policy == FlushPolicy::Always {
+                self.sync_log()?;
             }
-
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
         }
-
-        // remove stale log files
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+        self.commit()?;
+        for event in events {
+            self.emit(Some(event));
         }
+        self.maybe_compact()
+    }
 
-        self.uncompacted = 0;
+    /// Removes several keys, committing the removals to the log together
+    ///
+    /// Keys which do not exist are skipped. Returns whether each key was removed, in
+    /// the order of `keys`, so a key given twice is only removed the first time.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` without writing anything if any key is empty
+    pub fn remove_many(&mut self, keys: &[String]) -> Result<Vec<bool>> {
+        for key in keys {
+            check_not_empty(key)?;
+        }
+
+        let mut removed = Vec::with_capacity(keys.len());
+        let mut events = Vec::new();
+        for key in keys {
+            let key = self.index_key(key.clone());
+            let present = self.live_entry(&key).is_some();
+            if present {
+                self.append_rm(&key)?;
+                if self.options.flush_policy == FlushPolicy::Always {
+                    self.sync_log()?;
+                }
+                if self.wants_events() {
+                    events.push(StoreEvent::Removed { key: key.key });
+                }
+            }
+            removed.push(present);
+        }
+        self.commit()?;
+        for event in events {
+            self.emit(Some(event));
+        }
+        self.maybe_compact()?;
+        Ok(removed)
+    }
+
+    /// Loads a large number of key/value pairs, building their index in one pass at the end
+    ///
+    /// The records are appended to a fresh generation without touching the index or
+    /// checking for compaction per key, which makes this much faster than repeated
+    /// `set` calls for an initial load. It assumes no concurrent access to the store.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing or replaying the log.
+    /// Also returns `KvsError::InvalidKey` if a key is rejected by the `key_validator`.
+    /// On error the records written so far only become visible after reopening the store
+    pub fn bulk_load(&mut self, pairs: impl Iterator<Item = (String, String)>) -> Result<()> {
+        let gen = gen_after(self.current_gen, 1)?;
+        self.seal()?;
+        self.current_gen = gen;
+        self.writer = Some(self.new_log_file(gen)?);
+
+        let mut events = Vec::new();
+        let modified_at = unix_millis(self.options.clock.wall());
+        for (key, value) in pairs {
+            self.validate_key(&key)?;
+            if self.wants_events() {
+                events.push(StoreEvent::Set { key: key.clone() });
+            }
+            let logline = KvsLogLine::Set {
+                key,
+                value,
+                value_type: ValueType::Str,
+                expires_at: None,
+                modified_at,
+            };
+            let codec = self.options.codec;
+            serialize_to_log(self.writer()?, logline, codec)?;
+            if self.options.flush_policy == FlushPolicy::Always {
+                self.sync_log()?;
+            }
+        }
+        self.commit()?;
+
+        let reader = self.readers.get_mut(gen)?;
+        let (collation, codec) = (self.options.collation, self.options.codec);
+        self.uncompacted += load(
+            gen,
+            0,
+            reader,
+            &mut self.index,
+            collation,
+            OpenMode::Strict,
+            codec,
+        )?;
+        self.live = self.index.values().map(|cmd_pos| cmd_pos.len).sum();
+
+        for event in events {
+            self.emit(Some(event));
+        }
+        self.maybe_compact()
+    }
+
+    /// Returns the live key/value pairs whose keys fall within `range`, in collation order
+    ///
+    /// The bounds are compared using the collation the store was opened with.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values
+    pub fn range(&mut self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let positions: Vec<_> = self
+            .index_range(range)
+            .map(|(key, cmd_pos)| (key.key.clone(), *cmd_pos))
+            .collect();
+
+        let mut pairs = Vec::with_capacity(positions.len());
+        for (key, cmd_pos) in positions {
+            let value = read_value(&mut self.readers, &key, &cmd_pos, self.options.codec)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    /// Returns the live key/value pairs whose keys start with `prefix`
+    ///
+    /// An empty prefix returns every pair of the store.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values
+    pub fn get_prefixed(&mut self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        let collation = self.options.collation;
+        let positions: Vec<_> = self
+            .index_range(prefix.to_owned()..)
+            .take_while(|(key, _)| collation.matches_prefix(&key.key, prefix))
+            .filter(|(key, _)| key.key.starts_with(prefix))
+            .map(|(key, cmd_pos)| (key.key.clone(), *cmd_pos))
+            .collect();
+
+        positions
+            .into_iter()
+            .map(|(key, cmd_pos)| {
+                let value = read_value(&mut self.readers, &key, &cmd_pos, self.options.codec)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` key/value pairs whose keys start with `prefix` and come
+    /// after the key `after`, in collation order
+    ///
+    /// Passing the last key of a page as `after` returns the next page, so a large
+    /// prefix can be walked without holding all of its values at once.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values
+    pub fn scan_page(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let collation = self.options.collation;
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_owned()),
+            None => Bound::Included(prefix.to_owned()),
+        };
+        let positions: Vec<_> = self
+            .index_range((start, Bound::Unbounded))
+            .take_while(|(key, _)| collation.matches_prefix(&key.key, prefix))
+            .filter(|(key, _)| key.key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, cmd_pos)| (key.key.clone(), *cmd_pos))
+            .collect();
+
+        positions
+            .into_iter()
+            .map(|(key, cmd_pos)| {
+                let value = read_value(&mut self.readers, &key, &cmd_pos, self.options.codec)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` live keys starting with `prefix` which come after the key
+    /// `after`, in collation order
+    ///
+    /// Like [`KvStore::keys`] this only consults the in-memory index. Passing the last
+    /// key of a page as `after` returns the next page.
+    pub fn keys_page(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        let collation = self.options.collation;
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_owned()),
+            None => Bound::Included(prefix.to_owned()),
+        };
+        self.index_range((start, Bound::Unbounded))
+            .take_while(|(key, _)| collation.matches_prefix(&key.key, prefix))
+            .filter(|(key, _)| key.key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, _)| key.key.clone())
+            .collect()
+    }
+
+    /// Returns a reader streaming the value of `key` straight from the log
+    ///
+    /// Only the few bytes locating the value within its record are read up front, so
+    /// large values can be copied to a file or socket without holding them in memory.
+    /// The reader keeps its own handle on the log file and is unaffected by later
+    /// writes or compactions. Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while opening the log or locating the value, and
+    /// returns an error if the record is malformed.
+    /// Also returns `KvsError::UnexpectedCommandType` with the key and record location
+    /// if the indexed record is not a `Set`
+    pub fn get_reader(&self, key: String) -> Result<Option<impl Read>> {
+        check_not_empty(&key)?;
+        let key = self.index_key(key);
+        let Some(cmd_pos) = self.live_entry(&key) else {
+            return Ok(None);
+        };
+        let mut file = File::open(log_path(&self.path, cmd_pos.gen, &self.naming))?;
+        let unexpected = || KvsError::UnexpectedCommandType {
+            key: key.key.clone(),
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+        };
+        if self.options.codec != CodecKind::Flexbuffers {
+            // only flexbuffers records let the value be located without decoding them
+            file.seek(SeekFrom::Start(cmd_pos.pos))?;
+            let (_, value) =
+                value_from_log(&mut file, self.options.codec)?.ok_or_else(unexpected)?;
+            return Ok(Some(ValueReader::Decoded(io::Cursor::new(
+                value.into_bytes(),
+            ))));
+        }
+        let mut record = RecordCursor::new(file, &cmd_pos);
+        let span = record.value_span()?.ok_or_else(unexpected)?;
+        let mut file = record.file;
+        file.seek(SeekFrom::Start(span.start))?;
+        Ok(Some(ValueReader::Log(file.take(span.end - span.start))))
+    }
+
+    /// Returns where the record holding the value of `key` lives, or `None` if
+    /// the key is not set
+    ///
+    /// This only consults the in-memory index and never reads the value.
+    pub fn get_meta(&self, key: &str) -> Option<ValueMeta> {
+        let cmd_pos = self.live_entry(&self.index_key(key.to_owned()))?;
+        Some(ValueMeta {
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+        })
+    }
+
+    /// Returns the live keys within `range`, in collation order
+    ///
+    /// Unlike [`KvStore::range`] this only consults the in-memory index.
+    pub fn keys(&self, range: impl RangeBounds<String>) -> Vec<String> {
+        self.index_range(range)
+            .map(|(key, _)| key.key.clone())
+            .collect()
+    }
+
+    /// Returns the number of stale bytes in the log which compaction would reclaim
+    pub fn uncompacted_bytes(&self) -> u64 {
+        self.uncompacted
+    }
+
+    /// Returns the number of generation files of the store
+    pub fn generation_count(&self) -> usize {
+        self.readers.len()
+    }
+
+    /// Returns the number of generation files held open for reading
+    ///
+    /// Every generation keeps its file open unless
+    /// [`KvStoreOptions::reader_cache_size`] bounds them.
+    pub fn open_reader_count(&self) -> usize {
+        self.readers.open_count()
+    }
+
+    /// Writes every live key/value pair of the store into `writer`
+    ///
+    /// The export is a sequence of length-prefixed `Set` records in the same
+    /// format and codec as the log, so it can be loaded back with [`KvStore::import`].
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the log or writing the export
+    pub fn export(&mut self, mut writer: impl Write) -> Result<()> {
+        let now = self.now_millis();
+        for cmd_pos in self.index.values().filter(|cmd_pos| cmd_pos.is_live(now)) {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            io::copy(&mut reader.take(cmd_pos.len), &mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the export of [`KvStore::export`] into `writer` compressed on the fly
+    ///
+    /// The plain export is streamed through a zstd encoder, so it is never held in
+    /// memory or on disk uncompressed. A higher `level`, from 1 to 22, spends more
+    /// time looking for repeated bytes; levels out of that range are clamped. It is
+    /// loaded back with [`KvStore::import_compressed`].
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the log or writing the export
+    pub fn export_compressed(&mut self, writer: impl Write, level: i32) -> Result<()> {
+        let mut encoder = zstd::Encoder::new(writer, level.clamp(1, 22))?;
+        self.export(&mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Writes every live key/value pair of the store into `writer` as a JSON object
+    ///
+    /// The keys follow in collation order. `Int` and `Float` values are written as
+    /// numbers, except for non-finite floats which become `null`, `Str` values as
+    /// strings and `Bytes` values as strings of lowercase hex.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the log or writing the export
+    pub fn export_json(&mut self, mut writer: impl Write) -> Result<()> {
+        write!(writer, "{{")?;
+        let now = self.now_millis();
+        let live = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_live(now));
+        for (i, (key, cmd_pos)) in live.enumerate() {
+            let (value_type, value) =
+                read_typed_value(&mut self.readers, &key.key, cmd_pos, self.options.codec)?;
+            let value = match value_type {
+                ValueType::Str | ValueType::Bytes => json_string(&value),
+                ValueType::Int => value,
+                ValueType::Float => match value.parse::<f64>() {
+                    Ok(float) if float.is_finite() => format!("{:?}", float),
+                    Ok(_) => "null".to_string(),
+                    Err(_) => return Err(malformed_record()),
+                },
+            };
+            let separator = if i == 0 { "" } else { "," };
+            write!(writer, "{}{}:{}", separator, json_string(&key.key), value)?;
+        }
+        write!(writer, "}}")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets the value of a key which expires after `ttl`
+    ///
+    /// Once its deadline passes, the key is left out of every read as if it was
+    /// removed, see [`TtlClock`] for the time the deadline is compared against. The
+    /// deadline is stored with the value, so it holds across reopening, and a later
+    /// `set` of the key without a TTL makes it permanent again.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = self.now_millis().saturating_add(ttl);
+        self.set_record(key, value, ValueType::Str, Some(deadline))
+    }
+
+    /// Replaces the value of an existing key, keeping its deadline
+    ///
+    /// Unlike `set`, which makes the key permanent, a key with a TTL still expires
+    /// when it was due to. Returns `false` without writing anything if the key does
+    /// not exist or expired.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
+    pub fn update_value(&mut self, key: String, value: String) -> Result<bool> {
+        let Some(cmd_pos) = self.live_entry(&self.index_key(key.clone())) else {
+            return Ok(false);
+        };
+        self.set_record(key, value, ValueType::Str, cmd_pos.expires_at)?;
+        Ok(true)
+    }
+
+    /// Returns the time left before `key` expires
+    ///
+    /// Returns `None` if the key does not exist, and `Some(None)` if it never expires.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let cmd_pos = self.live_entry(&self.index_key(key.to_owned()))?;
+        let now = self.now_millis();
+        Some(
+            cmd_pos
+                .expires_at
+                .map(|deadline| Duration::from_millis(deadline.saturating_sub(now))),
+        )
+    }
+
+    /// Returns when the value of `key` was last written, in milliseconds since the
+    /// Unix epoch
+    ///
+    /// Returns `None` if the key does not exist, and `Some(0)` if its value was
+    /// written before the store tracked modification times.
+    pub fn modified_at(&self, key: &str) -> Option<u64> {
+        self.live_entry(&self.index_key(key.to_owned()))
+            .map(|cmd_pos| cmd_pos.modified_at)
+    }
+
+    /// Returns the length of the value of `key` in bytes, without reading the value
+    ///
+    /// The length is that of the value alone, as `get` would return it, not of the
+    /// record holding it in the log. Values of other types than strings count the
+    /// bytes of their text form. Returns `None` if the key does not exist.
+    pub fn value_len(&self, key: &str) -> Option<u64> {
+        self.live_entry(&self.index_key(key.to_owned()))
+            .and_then(|cmd_pos| cmd_pos.value_len)
+    }
+
+    /// Gets the values of `keys`, reading different generations concurrently
+    ///
+    /// The keys are grouped by the generation holding their value, and the groups
+    /// are shared among at most [`KvStoreOptions::multi_get_threads`] threads, each
+    /// reading through its own file handles. The values come in the order of `keys`,
+    /// `None` for a key which does not exist, as a `get` of each key would return.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values.
+    /// Also returns `KvsError::InvalidKey` if a key is empty
+    pub fn multi_get_parallel(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut gens: BTreeMap<u64, Vec<(usize, CommandPos)>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            check_not_empty(key)?;
+            if let Some(cmd_pos) = self.live_entry(&self.index_key(key.clone())) {
+                gens.entry(cmd_pos.gen).or_default().push((i, cmd_pos));
+            }
+        }
+
+        // deal the generations out to the threads
+        let threads = self.options.multi_get_threads_or_default().min(gens.len());
+        let mut shares = vec![Vec::new(); threads];
+        for (share, gen) in (0..threads).cycle().zip(gens) {
+            shares[share].push(gen);
+        }
+
+        let codec = self.options.codec;
+        let read_share = |share: Vec<(u64, Vec<(usize, CommandPos)>)>| -> Result<_> {
+            let mut values = Vec::new();
+            for (gen, entries) in share {
+                let file = File::open(log_path(&self.path, gen, &self.naming))?;
+                let mut reader = BufReaderWithPos::new(file)?;
+                for (i, cmd_pos) in entries {
+                    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                    let value = value_from_log(&mut reader, codec)?.ok_or_else(|| {
+                        KvsError::UnexpectedCommandType {
+                            key: keys[i].clone(),
+                            gen,
+                            pos: cmd_pos.pos,
+                        }
+                    })?;
+                    values.push((i, value.1));
+                }
+            }
+            Ok(values)
+        };
+        let mut values = vec![None; keys.len()];
+        thread::scope(|scope| {
+            let handles: Vec<_> = shares
+                .into_iter()
+                .map(|share| scope.spawn(move || read_share(share)))
+                .collect();
+            for handle in handles {
+                let read = handle.join().expect("multi-get reader panicked")?;
+                for (i, value) in read {
+                    values[i] = Some(value);
+                }
+            }
+            Ok(values)
+        })
+    }
+
+    /// Gets the value of a key only if it was written after `since`, in milliseconds
+    /// since the Unix epoch
+    ///
+    /// Returns `None` if the value was not written since, and `Some(None)` if the key
+    /// does not exist. A value written before the store tracked modification times is
+    /// always returned, as it may have changed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the value
+    pub fn get_if_modified_since(
+        &mut self,
+        key: String,
+        since: u64,
+    ) -> Result<Option<Option<String>>> {
+        match self.modified_at(&key) {
+            Some(modified_at) if modified_at != 0 && modified_at <= since => Ok(None),
+            _ => self.get(key).map(Some),
+        }
+    }
+
+    /// Sets the value of a key to an integer
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
+    pub fn set_int(&mut self, key: String, value: i64) -> Result<()> {
+        self.set_typed(key, value.to_string(), ValueType::Int)
+    }
+
+    /// Gets the integer value of a key
+    ///
+    /// Returns `None` if the given key does not exist
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the value.
+    /// Also returns `KvsError::WrongValueType` if the value is not an `Int`
+    pub fn get_int(&mut self, key: String) -> Result<Option<i64>> {
+        self.get_expecting(key, ValueType::Int)?
+            .map(|value| value.parse().map_err(|_| malformed_record()))
+            .transpose()
+    }
+
+    /// Sets the value of a key to a float
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
+    pub fn set_float(&mut self, key: String, value: f64) -> Result<()> {
+        self.set_typed(key, value.to_string(), ValueType::Float)
+    }
+
+    /// Gets the float value of a key
+    ///
+    /// Returns `None` if the given key does not exist
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the value.
+    /// Also returns `KvsError::WrongValueType` if the value is not a `Float`
+    pub fn get_float(&mut self, key: String) -> Result<Option<f64>> {
+        self.get_expecting(key, ValueType::Float)?
+            .map(|value| value.parse().map_err(|_| malformed_record()))
+            .transpose()
+    }
+
+    /// Sets the value of a key to a byte string
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    /// Also returns `KvsError::InvalidKey` if the key is rejected by the `key_validator`
+    pub fn set_bytes(&mut self, key: String, value: &[u8]) -> Result<()> {
+        self.set_typed(key, hex_encode(value), ValueType::Bytes)
+    }
+
+    /// Gets the byte string value of a key
+    ///
+    /// Returns `None` if the given key does not exist
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the value.
+    /// Also returns `KvsError::WrongValueType` if the value is not `Bytes`
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        self.get_expecting(key, ValueType::Bytes)?
+            .map(|value| hex_decode(&value).ok_or_else(malformed_record))
+            .transpose()
+    }
+
+    /// Returns the type of the value of a key, or `None` if the key does not exist
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the value
+    pub fn value_type(&mut self, key: String) -> Result<Option<ValueType>> {
+        Ok(self.get_typed(key)?.map(|(value_type, _)| value_type))
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value
+    ///
+    /// A missing key is treated as zero and set to `delta`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or (de)serialization errors while reading or writing the log.
+    /// Also returns `KvsError::WrongValueType` if the value is not an `Int`, even a `Str`
+    /// holding a number, and `KvsError::IntegerOverflow` if the sum overflows
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = self
+            .get_int(key.clone())?
+            .unwrap_or(0)
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        self.set_int(key, value)?;
+        Ok(value)
+    }
+
+    /// Loads key/value pairs previously written by [`KvStore::export`] into the store
+    ///
+    /// Existing keys are overwritten by the imported values.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the export.
+    /// Also returns `KvsError::UnexpectedCommandType` if the export contains an `Rm`
+    /// record, with generation 0 and the offset of the record in the export
+    pub fn import(&mut self, reader: impl Read) -> Result<()> {
+        self.apply_records(reader, false)
+    }
+
+    /// Applies the records of a generation file, as returned by
+    /// [`KvStore::read_sealed_generation`], to the store
+    ///
+    /// Unlike [`KvStore::import`] the removals are applied too, a removal of a key
+    /// the store does not hold is ignored.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the generation
+    pub fn replay_generation(&mut self, reader: impl Read) -> Result<()> {
+        self.apply_records(reader, true)
+    }
+
+    /// Applies the records read from `reader`, failing on an `Rm` record unless
+    /// `removals` is set
+    fn apply_records(&mut self, reader: impl Read, removals: bool) -> Result<()> {
+        let mut reader = BufReader::new(reader);
+        let mut scratch = LogScratch::new(self.options.codec);
+        let mut pos = 0;
+        while !reader.fill_buf()?.is_empty() {
+            let logline = deserialize_into(&mut reader, &mut scratch)?;
+            let record_pos = pos;
+            pos += 4 + scratch.logline.len() as u64;
+            match logline {
+                // the time of the original write is kept, if it was recorded
+                KvsLogLine::Set {
+                    key,
+                    value,
+                    value_type,
+                    expires_at,
+                    modified_at,
+                } => {
+                    let modified_at = Some(modified_at).filter(|&modified_at| modified_at > 0);
+                    self.set_record_at(key, value, value_type, expires_at, modified_at)?
+                }
+                KvsLogLine::Rm { key } if removals => match self.remove(key) {
+                    Ok(()) | Err(KvsError::KeyDoesNotExist) => (),
+                    Err(err) => return Err(err),
+                },
+                KvsLogLine::Rm { key } => {
+                    return Err(KvsError::UnexpectedCommandType {
+                        key,
+                        gen: 0,
+                        pos: record_pos,
+                    })
+                }
+                KvsLogLine::Footer { .. } => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads an export written by [`KvStore::export_compressed`] into the store
+    ///
+    /// The compressed format is recognized by the magic number of zstd frames, and an
+    /// export without it is loaded as a plain one like [`KvStore::import`] does.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the export,
+    /// including an I/O error if the compressed data is corrupted or truncated
+    pub fn import_compressed(&mut self, reader: impl Read) -> Result<()> {
+        let mut reader = BufReader::new(reader);
+        if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+            self.import(zstd::Decoder::with_buffer(reader)?)
+        } else {
+            self.import(reader)
+        }
+    }
+
+    /// Decodes every record of a generation file or export, in log order
+    ///
+    /// This only reads `reader` and never touches a store, so it can be used to
+    /// inspect the history held by any log file written with the default codec.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the records
+    pub fn decode_log(reader: impl Read + Seek) -> Result<Vec<LogRecord>> {
+        KvStore::decode_log_with(reader, CodecKind::default())
+    }
+
+    /// Decodes every record of a generation file or export written with `codec`,
+    /// in log order
+    ///
+    /// The footer ending a sealed generation is left out.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the records
+    pub fn decode_log_with(reader: impl Read + Seek, codec: CodecKind) -> Result<Vec<LogRecord>> {
+        let mut reader = BufReaderWithPos::new(reader)?;
+        let mut records = Vec::new();
+        let mut scratch = LogScratch::new(codec);
+        while !reader.is_empty()? {
+            let offset = reader.pos;
+            let (key, value) = match deserialize_into(&mut reader, &mut scratch)? {
+                KvsLogLine::Set { key, value, .. } => (key, Some(value)),
+                KvsLogLine::Rm { key } => (key, None),
+                KvsLogLine::Footer { .. } => continue,
+            };
+            records.push(LogRecord {
+                offset,
+                len: reader.pos - offset,
+                key,
+                value,
+            });
+        }
+        Ok(records)
+    }
+
+    /// Returns the number and contents of the first sealed generation numbered `gen` or later
+    ///
+    /// A generation is sealed once the store stopped writing to it, so its contents
+    /// never change. Returns `None` if there is no such generation.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while reading the generation file
+    pub fn read_sealed_generation(&mut self, gen: u64) -> Result<Option<(u64, Vec<u8>)>> {
+        // the generation of a merge still being copied has no reader until it is sealed
+        let sealed_gen = self
+            .readers
+            .gens()
+            .filter(|&sealed_gen| sealed_gen >= gen && sealed_gen != self.current_gen)
+            .min();
+        match sealed_gen {
+            Some(sealed_gen) => {
+                let data = fs::read(log_path(&self.path, sealed_gen, &self.naming))?;
+                Ok(Some((sealed_gen, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Removes every key of the store, keeping it open in the same directory
+    ///
+    /// All generation files are deleted and writing continues in a fresh generation,
+    /// which is cheaper than dropping the store, clearing the directory and reopening it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while deleting or creating log files
+    pub fn reset(&mut self) -> Result<()> {
+        // the merged generations go away with the others
+        self.merge_job = None;
+        self.current_gen = gen_after(self.current_gen, 1)?;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        self.remove_generations_before(self.current_gen, false)?;
+
+        self.index.clear();
+        self.uncompacted = 0;
+        self.live = 0;
+
+        if self.options.index_snapshot {
+            self.write_index_snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the log and closes the store, persisting the index first if
+    /// `index_snapshot` is enabled
+    ///
+    /// The snapshot records the position in the log to replay from, so the next
+    /// `open` only replays the records appended after it. Dropping the store does the
+    /// same but cannot report errors; after a crash without a snapshot the next
+    /// `open` replays the whole log.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors while syncing the log or writing
+    /// the snapshot
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        self.closed = true;
+        Ok(())
+    }
+
+    /// Seals the current generation and continues writing in a new one
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while creating the log file, or while merging
+    /// generations if this exceeds `max_generations`
+    pub fn rotate(&mut self) -> Result<()> {
+        self.commit()?;
+        self.seal()?;
+        self.current_gen = gen_after(self.current_gen, 1)?;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        self.maybe_compact()
+    }
+
+    /// Checks the integrity of every generation of the store
+    ///
+    /// A generation sealed by rotation or compaction ends with a footer holding a
+    /// checksum of its contents, which is checked without decoding the records, so
+    /// damage that still decodes, e.g. in a value, is caught too. The active
+    /// generation, and generations sealed by a crash or written before footers
+    /// existed, are replayed record by record instead. With `deep`, every generation
+    /// is replayed, and the record count of the footers is checked as well.
+    ///
+    /// Returns how each generation was checked, in generation order
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::CorruptGeneration` for the first generation failing its
+    /// check, and propagates I/O errors while reading the generations
+    pub fn verify(&mut self, deep: bool) -> Result<Vec<(u64, Verification)>> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        let mut gens: Vec<_> = self.readers.gens().collect();
+        gens.sort_unstable();
+        let mut checks = Vec::with_capacity(gens.len());
+        for gen in gens {
+            let data = fs::read(log_path(&self.path, gen, &self.naming))?;
+            match verify_generation(&data, self.options.codec, deep) {
+                Ok(verification) => checks.push((gen, verification)),
+                Err(reason) => return Err(KvsError::CorruptGeneration { gen, reason }),
+            }
+        }
+        Ok(checks)
+    }
+
+    /// Compacts the log right away, whatever the compaction strategy
+    ///
+    /// The compacted generation holds exactly the latest committed record of every
+    /// live key as of the start of the compaction. A write issued meanwhile, e.g. by
+    /// another thread sharing the store behind a lock, waits for the compaction to
+    /// finish and lands in the active generation. That generation is numbered after
+    /// the compacted one, so its records take precedence when the log is replayed.
+    ///
+    /// Returns the number of stale bytes dropped from the log
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while rewriting the log
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compaction()
+    }
+
+    /// Sets the value of a key of a store shared behind a lock, sharing the sync of
+    /// the write with the other writers of the store
+    ///
+    /// When the flush policy syncs the commit, e.g. with
+    /// [`KvStoreOptions::sync_on_write`], the record is appended under the lock, which
+    /// is released before waiting for the sync. The first writer waiting while no sync
+    /// runs syncs the log for every writer which appended before, so concurrent durable
+    /// writes cost far fewer syncs than writes. It returns once the write is durable.
+    ///
+    /// # Errors
+    ///
+    /// It propagates the errors of [`KvsEngine::set`] and I/O errors while syncing
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned
+    pub fn set_shared(store: &Mutex<KvStore>, key: String, value: String) -> Result<()> {
+        KvStore::commit_shared(store, |store| store.set(key, value))
+    }
+
+    /// Removes a key of a store shared behind a lock, sharing the sync of the removal
+    /// with the other writers of the store, see [`KvStore::set_shared`]
+    ///
+    /// # Errors
+    ///
+    /// It propagates the errors of [`KvsEngine::remove`] and I/O errors while syncing
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned
+    pub fn remove_shared(store: &Mutex<KvStore>, key: String) -> Result<()> {
+        KvStore::commit_shared(store, |store| store.remove(key))
+    }
+
+    /// Makes `write` under the lock of `store` and waits for its sync with the lock
+    /// released
+    fn commit_shared(
+        store: &Mutex<KvStore>,
+        write: impl FnOnce(&mut KvStore) -> Result<()>,
+    ) -> Result<()> {
+        let ticket = {
+            let mut store = store.lock().unwrap();
+            store.defer_sync = true;
+            let written = write(&mut store);
+            store.defer_sync = false;
+            if let Err(err) = written {
+                store.sync_deferred = false;
+                return Err(err);
+            }
+            store.sync_ticket()?
+        };
+        match ticket {
+            Some(ticket) => ticket.group_commit.wait(ticket.pos, &ticket.file),
+            None => Ok(()),
+        }
+    }
+
+    /// Compacts a store shared behind a lock, releasing the lock between batches
+    ///
+    /// The lock is only held while a buffer of records is copied, see
+    /// [`KvStoreOptions::compaction_buffer_bytes`], and
+    /// [`KvStoreOptions::compaction_bytes_per_sec`] is kept by sleeping with the lock
+    /// released. Writes issued meanwhile land in the active generation, numbered after
+    /// the compacted one; the copies of the keys they overwrite are left stale.
+    ///
+    /// Returns the number of stale bytes dropped from the log, 0 if a compaction of
+    /// the store holding it mutably completed the merge meanwhile
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors while rewriting the log
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned
+    pub fn compact_shared(store: &Mutex<KvStore>) -> Result<u64> {
+        KvStore::merge_shared(store, usize::MAX)
+    }
+
+    /// Merges the `count` oldest generations of a store shared behind a lock, see
+    /// [`KvStore::compact_shared`]
+    fn merge_shared(store: &Mutex<KvStore>, count: usize) -> Result<u64> {
+        let span = OpSpan::compaction();
+        let mut guard = store.lock().unwrap();
+        if let Some(job) = guard.merge_job.take() {
+            guard.run_merge(job)?;
+        }
+        let job = guard.start_merge(count)?;
+        span.record_gen(job.compaction_gen);
+        guard.merge_job = Some(job);
+        loop {
+            let Some(mut job) = guard.merge_job.take() else {
+                return Ok(0);
+            };
+            if guard.copy_merge_batch(&mut job)? {
+                // finished before releasing the lock, as a merge started meanwhile would
+                // remove the generations the copied job still points the index away from
+                let lead = job.lead(guard.options.compaction_bytes_per_sec);
+                let dropped = guard.finish_merge(job)?;
+                drop(guard);
+                thread::sleep(lead);
+                return Ok(dropped);
+            }
+            let lead = job.lead(guard.options.compaction_bytes_per_sec);
+            guard.merge_job = Some(job);
+            drop(guard);
+            thread::sleep(lead);
+            guard = store.lock().unwrap();
+        }
+    }
+
+    /// Returns whether a compaction of the store is running
+    ///
+    /// A compaction other than [`KvStore::compact_shared`] holds the store mutably, so
+    /// other threads sharing the store behind a lock should query a
+    /// [`KvStore::compaction_status`] taken beforehand.
+    pub fn is_compacting(&self) -> bool {
+        self.compaction_status.is_compacting()
+    }
+
+    /// Returns a handle telling whether a compaction is running, which can be
+    /// queried without access to the store
+    pub fn compaction_status(&self) -> CompactionStatus {
+        self.compaction_status.clone()
+    }
+
+    /// Sets the value of a key along with its type
+    fn set_typed(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        self.set_record(key, value, value_type, None)
+    }
+
+    /// Sets the value of a key along with its type and deadline
+    fn set_record(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.set_record_at(key, value, value_type, expires_at, None)
+    }
+
+    /// Sets the value of a key along with its type and deadline, as modified at
+    /// `modified_at` or now if it is `None`
+    fn set_record_at(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        modified_at: Option<u64>,
+    ) -> Result<()> {
+        let span = OpSpan::set(key.len(), value.len());
+        self.validate_key(&key)?;
+        span.record_gen(self.current_gen);
+        let event = self
+            .wants_events()
+            .then(|| StoreEvent::Set { key: key.clone() });
+        let modified_at = modified_at.unwrap_or_else(|| unix_millis(self.options.clock.wall()));
+        self.append_set(key, value, value_type, expires_at, modified_at)?;
+        self.commit()?;
+        self.emit(event);
+        self.maybe_compact()
+    }
+
+    /// Gets the value of a key along with its type
+    fn get_typed(&mut self, key: String) -> Result<Option<(ValueType, String)>> {
+        check_not_empty(&key)?;
+        let key = self.index_key(key);
+        match self.live_entry(&key) {
+            Some(cmd_pos) => {
+                read_typed_value(&mut self.readers, &key.key, &cmd_pos, self.options.codec)
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a key, which must be of type `expected`
+    fn get_expecting(&mut self, key: String, expected: ValueType) -> Result<Option<String>> {
+        match self.get_typed(key.clone())? {
+            Some((value_type, value)) if value_type == expected => Ok(Some(value)),
+            Some((value_type, _)) => Err(KvsError::WrongValueType { key, value_type }),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends a `Set` record to the log and places it in the index without flushing
+    fn append_set(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        modified_at: u64,
+    ) -> Result<()> {
+        let value_len = value.len() as u64;
+        let logline = KvsLogLine::Set {
+            key: key.clone(),
+            value,
+            value_type,
+            expires_at,
+            modified_at,
+        };
+
+        let codec = self.options.codec;
+        let writer = self.writer()?;
+        let start_pos = writer.pos;
+        serialize_to_log(writer, logline, codec)?;
+        let end_pos = writer.pos;
+
+        // place the element in the index
+        self.live += end_pos - start_pos;
+        let key = self.index_key(key);
+        let cmd_pos = CommandPos {
+            expires_at,
+            modified_at,
+            value_len: Some(value_len),
+            ..(self.current_gen, start_pos..end_pos).into()
+        };
+        if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+            self.uncompacted += old_cmd.len;
+            self.live -= old_cmd.len;
+        }
+        Ok(())
+    }
+
+    /// Appends an `Rm` record for `key` to the log and drops the key from the index
+    ///
+    /// Both the record of the removed value and the `Rm` record itself are stale.
+    fn append_rm(&mut self, key: &IndexKey) -> Result<()> {
+        let logline = KvsLogLine::Rm {
+            key: key.key.clone(),
+        };
+        let codec = self.options.codec;
+        let writer = self.writer()?;
+        let start_pos = writer.pos;
+        serialize_to_log(writer, logline, codec)?;
+        self.uncompacted += writer.pos - start_pos;
+
+        if let Some(old_cmd) = self.index.remove(key) {
+            self.uncompacted += old_cmd.len;
+            self.live -= old_cmd.len;
+        }
+        Ok(())
+    }
+
+    /// Flushes the appended records, syncing them to disk if the flush policy asks for it
+    fn commit(&mut self) -> Result<()> {
+        self.check_clock();
+        self.writer()?.flush()?;
+        self.last_write = Instant::now();
+        if self
+            .options
+            .flush_policy
+            .syncs_commit(self.last_sync.elapsed())
+        {
+            if self.defer_sync {
+                self.sync_deferred = true;
+                self.last_sync = Instant::now();
+            } else {
+                self.sync_log()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs the current log to disk if it holds records which are not synced yet
+    fn sync_log(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        if writer.synced_pos < writer.pos {
+            writer.sync()?;
+            self.last_sync = Instant::now();
+            self.sync_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Compacts the log if the compaction strategy or the generation limit asks for it
+    ///
+    /// A compaction asked for by the strategy waits until it would reclaim the
+    /// minimum reclaim ratio of the log.
+    ///
+    /// A shared store with a throttled compaction hands the compaction to its
+    /// background thread instead, see [`KvStore::open_shared`].
+    fn maybe_compact(&mut self) -> Result<()> {
+        // check for defragmentation
+        let count = if self
+            .options
+            .compaction_strategy
+            .should_compact(self.uncompacted, self.uncompacted + self.live)
+            && self.uncompacted > self.options.min_reclaim_bytes(self.live)
+        {
+            usize::MAX
+        } else {
+            match self.options.max_generations {
+                // the merged and the fresh generation take the place of those merged
+                Some(max_generations) if self.readers.len() > max_generations => {
+                    self.readers.len() + 2 - max_generations
+                }
+                _ => return Ok(()),
+            }
+        };
+        if self.merge_job.is_some() || self.compaction_requested {
+            return Ok(());
+        }
+        if let Some(requests) = &self.compaction_requests {
+            if requests.send(count).is_ok() {
+                self.compaction_requested = true;
+                return Ok(());
+            }
+        }
+        self.merge_generations(count)?;
+        Ok(())
+    }
+
+    /// Clears stale entries in the log
+    ///
+    /// Returns the number of stale bytes dropped
+    fn compaction(&mut self) -> Result<u64> {
+        self.merge_generations(usize::MAX)
+    }
+
+    /// Copies the live records of the `count` oldest generations into a new generation,
+    /// which replaces them, and continues writing in a fresh generation
+    ///
+    /// Records of the merged generations are only live if no later generation holds a
+    /// record of their key, so the copies may follow later generations in the log.
+    /// Writes after the merge go to a generation numbered after the copies, which
+    /// therefore never shadow them. A merge left running by
+    /// [`KvStore::compact_shared`] is completed first.
+    /// Returns the number of stale bytes dropped
+    fn merge_generations(&mut self, count: usize) -> Result<u64> {
+        let span = OpSpan::compaction();
+        if let Some(job) = self.merge_job.take() {
+            self.run_merge(job)?;
+        }
+        let job = self.start_merge(count)?;
+        span.record_gen(job.compaction_gen);
+        self.run_merge(job)
+    }
+
+    /// Copies every batch of `job` and swaps the index over to the copies
+    fn run_merge(&mut self, mut job: MergeJob) -> Result<u64> {
+        loop {
+            let done = self.copy_merge_batch(&mut job)?;
+            // sleep off any lead over the allowed copy rate
+            thread::sleep(job.lead(self.options.compaction_bytes_per_sec));
+            if done {
+                return self.finish_merge(job);
+            }
+        }
+    }
+
+    /// Starts writing the live records of the `count` oldest generations into a new
+    /// generation, see [`KvStore::merge_generations`]
+    fn start_merge(&mut self, count: usize) -> Result<MergeJob> {
+        let compacting = self.compaction_status.start();
+        self.compaction_requested = false;
+        // Increase current gen by 2. Current gen + 1 is for the compaction file.
+
+        let compaction_gen = gen_after(self.current_gen, 1)?;
+        let current_gen = gen_after(self.current_gen, 2)?;
+        self.seal()?;
+        self.current_gen = current_gen;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+
+        let mut merged_gens: Vec<_> = self
+            .readers
+            .gens()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        merged_gens.sort_unstable();
+        let merges_all = count >= merged_gens.len();
+        merged_gens.truncate(count);
+        let is_merged = |gen: u64| merged_gens.contains(&gen);
+        let mut merged_bytes = 0;
+        for &gen in &merged_gens {
+            merged_bytes += fs::metadata(log_path(&self.path, gen, &self.naming))?.len();
+        }
+
+        // expired records are stale, any older record of their key is merged too
+        let now = self.now_millis();
+        let mut expired_bytes = 0;
+        self.index.retain(|_, cmd_pos| {
+            let expired = is_merged(cmd_pos.gen) && !cmd_pos.is_live(now);
+            if expired {
+                expired_bytes += cmd_pos.len;
+            }
+            !expired
+        });
+        self.live -= expired_bytes;
+        self.uncompacted += expired_bytes;
+        let merged_live = self
+            .index
+            .values()
+            .filter(|cmd_pos| is_merged(cmd_pos.gen))
+            .map(|cmd_pos| cmd_pos.len)
+            .sum();
+
+        // the copy is written under a temporary name until it is complete, so a
+        // compaction interrupted halfway never leaves a partial generation behind
+        let temp_path = self.naming.compaction_temp(&self.path, compaction_gen);
+        let writer = open_log_writer(temp_path, true, self.options.file_hook.as_ref())?;
+        Ok(MergeJob {
+            compaction_gen,
+            merged_gens,
+            merges_all,
+            merged_bytes,
+            merged_live,
+            uncompacted_at_start: self.uncompacted,
+            writer,
+            buf: vec![0; self.options.compaction_buffer_bytes_or_default()],
+            resume_at: None,
+            compacted: Vec::new(),
+            new_pos: 0,
+            started: Instant::now(),
+            _compacting: compacting,
+        })
+    }
+
+    /// Copies the live records of the next keys of `job`, about a buffer of bytes
+    ///
+    /// Returns whether every key has been copied
+    fn copy_merge_batch(&mut self, job: &mut MergeJob) -> Result<bool> {
+        let batch_bytes = job.buf.len() as u64;
+        let start = match job.resume_at.take() {
+            Some(key) => Bound::Included(key),
+            None => Bound::Unbounded,
+        };
+        let mut copied = 0;
+        for (key, cmd_pos) in self.index.range((start, Bound::Unbounded)) {
+            if !job.merged_gens.contains(&cmd_pos.gen) {
+                continue;
+            }
+            if copied >= batch_bytes {
+                job.resume_at = Some(key.clone());
+                return Ok(false);
+            }
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            if reader.pos != cmd_pos.pos {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            }
+
+            let len = copy_chunked(reader, &mut job.writer, cmd_pos.len, &mut job.buf)?;
+
+            let new_pos = job.new_pos;
+            job.compacted.push((
+                cmd_pos.gen,
+                cmd_pos.pos,
+                CommandPos {
+                    expires_at: cmd_pos.expires_at,
+                    modified_at: cmd_pos.modified_at,
+                    value_len: cmd_pos.value_len,
+                    ..(job.compaction_gen, new_pos..new_pos + len).into()
+                },
+            ));
+            job.new_pos += len;
+            copied += len;
+        }
+        Ok(true)
+    }
+
+    /// Seals the generation written by `job` and points the index at the copies
+    ///
+    /// Returns the number of stale bytes dropped
+    fn finish_merge(&mut self, job: MergeJob) -> Result<u64> {
+        let MergeJob {
+            merged_gens,
+            mut writer,
+            compacted,
+            ..
+        } = job;
+        write_footer(&mut writer, self.options.codec)?;
+        let sync = self.options.sync_compaction_or_default();
+        if sync {
+            writer.sync_all()?;
+            self.sync_count += 1;
+        } else {
+            writer.flush()?;
+        }
+        let compacted_path = log_path(&self.path, job.compaction_gen, &self.naming);
+        let temp_path = &writer.writer.get_ref().path;
+        fs::rename(temp_path, &compacted_path)?;
+        if let Some(hook) = &self.options.file_hook {
+            hook.0.renamed(temp_path, &compacted_path);
+        }
+        if sync {
+            sync_dir(
+                compacted_path.parent().unwrap(),
+                self.options.file_hook.as_ref(),
+            )?;
+        }
+        self.readers.insert(
+            job.compaction_gen,
+            BufReaderWithPos::new(File::open(&compacted_path)?)?,
+        );
+        self.check_open_readers();
+
+        // swap every entry over to the compacted copy before the old files go away;
+        // the copies of keys written since are stale
+        let mut copies = compacted.into_iter();
+        let merged_positions = self
+            .index
+            .values_mut()
+            .filter(|cmd_pos| merged_gens.contains(&cmd_pos.gen));
+        for cmd_pos in merged_positions {
+            let copy = copies.find(|&(gen, pos, _)| (gen, pos) == (cmd_pos.gen, cmd_pos.pos));
+            if let Some((_, _, new_cmd_pos)) = copy {
+                *cmd_pos = new_cmd_pos;
+            }
+        }
+        self.remove_generations(&merged_gens, self.options.retain_compacted)?;
+        if sync {
+            // the removed generations must not come back after a crash
+            let mut dirs = vec![self.path.clone()];
+            for &gen in &merged_gens {
+                let merged_path = log_path(&self.path, gen, &self.naming);
+                dirs.extend(merged_path.parent().map(Path::to_path_buf));
+            }
+            if self.options.retain_compacted {
+                dirs.push(self.path.join(ARCHIVE_DIR));
+            }
+            dirs.sort_unstable();
+            dirs.dedup();
+            for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+                sync_dir(dir, self.options.file_hook.as_ref())?;
+            }
+        }
+
+        // stale bytes are only tracked for the whole log; the records of the merged
+        // generations overwritten before they were copied were stale too
+        let reclaimed = if job.merges_all {
+            job.uncompacted_at_start + job.merged_live - job.new_pos
+        } else {
+            job.merged_bytes - job.new_pos
+        }
+        .min(self.uncompacted);
+        self.uncompacted -= reclaimed;
+
+        if self.options.index_snapshot {
+            self.write_index_snapshot()?;
+        }
+
+        self.emit(Some(StoreEvent::Compacted { reclaimed }));
+        Ok(reclaimed)
+    }
+
+    /// Removes the log files of the generations older than `gen`, moving them into
+    /// the archive directory instead if `archive` is set
+    fn remove_generations_before(&mut self, gen: u64, archive: bool) -> Result<()> {
+        let stale_gens: Vec<_> = self
+            .readers
+            .gens()
+            .filter(|&stale_gen| stale_gen < gen)
+            .collect();
+        self.remove_generations(&stale_gens, archive)
+    }
+
+    /// Removes the log files of `stale_gens`, moving them into the archive directory
+    /// instead if `archive` is set
+    fn remove_generations(&mut self, stale_gens: &[u64], archive: bool) -> Result<()> {
+        for &stale_gen in stale_gens {
+            self.readers.remove(stale_gen);
+            let stale_path = log_path(&self.path, stale_gen, &self.naming);
+            if archive {
+                let archive_dir = self.path.join(ARCHIVE_DIR);
+                fs::create_dir_all(&archive_dir)?;
+                fs::rename(
+                    &stale_path,
+                    archive_dir.join(self.naming.file_name(stale_gen)),
+                )?;
+            } else {
+                fs::remove_file(&stale_path)?;
+            }
+            if self.options.layout != DirLayout::Flat {
+                // only succeeds once the subdirectory is empty
+                let _ = fs::remove_dir(stale_path.parent().unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the index entries whose keys fall within `range`
+    fn index_range(
+        &self,
+        range: impl RangeBounds<String>,
+    ) -> impl Iterator<Item = (&IndexKey, &CommandPos)> {
+        let bound = |bound: Bound<&String>| bound.map(|key| self.index_key(key.clone()));
+        let (start, end) = (bound(range.start_bound()), bound(range.end_bound()));
+
+        // `BTreeMap::range` panics on inverted bounds
+        let is_empty = match (&start, &end) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Included(start), Bound::Excluded(end)) => start > end,
+            _ => false,
+        };
+        let now = self.now_millis();
+        (!is_empty)
+            .then(|| self.index.range((start, end)))
+            .into_iter()
+            .flatten()
+            .filter(move |(_, cmd_pos)| cmd_pos.is_live(now))
+    }
+
+    /// Returns the index entry of `key` unless it expired
+    fn live_entry(&self, key: &IndexKey) -> Option<CommandPos> {
+        let now = self.now_millis();
+        self.index
+            .get(key)
+            .filter(|cmd_pos| cmd_pos.is_live(now))
+            .copied()
+    }
+
+    /// Returns the time deadlines are compared against, in milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        let clock = &self.options.clock;
+        match self.options.ttl_clock {
+            TtlClock::Wall => unix_millis(clock.wall()),
+            TtlClock::Monotonic => {
+                let (wall, monotonic) = self.clock_anchor;
+                let elapsed = clock.monotonic().saturating_duration_since(monotonic);
+                wall.saturating_add(u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX))
+            }
+        }
+    }
+
+    /// Reports the wall clock moving backward since the last write by more than the threshold
+    fn check_clock(&mut self) {
+        let wall = unix_millis(self.options.clock.wall());
+        let threshold = self.options.clock_skew_threshold_or_default();
+        let by = Duration::from_millis(self.last_write_wall.saturating_sub(wall));
+        if by > threshold {
+            self.emit(Some(StoreEvent::ClockMovedBackward { by }));
+        }
+        self.last_write_wall = wall;
+    }
+
+    /// Checks that `key` is not empty and passes the `key_validator`, if any
+    fn validate_key(&self, key: &str) -> Result<()> {
+        check_not_empty(key)?;
+        match self.options.key_validator {
+            Some(is_valid) if !is_valid(key) => Err(KvsError::InvalidKey(key.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wraps `key` for lookups in the index
+    fn index_key(&self, key: String) -> IndexKey {
+        IndexKey::new(key, self.options.collation)
+    }
+
+    /// Returns whether an event callback is registered
+    fn wants_events(&self) -> bool {
+        self.options.on_event.is_some()
+    }
+
+    /// Passes a committed event to the registered callback, if any
+    fn emit(&self, event: Option<StoreEvent>) {
+        if let (Some(on_event), Some(event)) = (&self.options.on_event, event) {
+            on_event.call(&event);
+        }
+    }
 
+    /// Persists the index along with the position from which later records must be replayed
+    fn write_index_snapshot(&mut self) -> Result<()> {
+        let writer = self.writer()?;
+        writer.flush()?;
+        let replay_pos = writer.pos;
+        let snapshot = IndexSnapshot {
+            replay_gen: self.current_gen,
+            replay_pos,
+            uncompacted: self.uncompacted,
+            index: &self.index,
+        };
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        snapshot.serialize(&mut s)?;
+
+        // write to a temporary file first so a crash never leaves a torn snapshot
+        let snapshot_path = self.naming.index_snapshot(&self.path);
+        let temp_path = snapshot_path.with_extension("tmp");
+        fs::write(&temp_path, s.view())?;
+        fs::rename(temp_path, snapshot_path)?;
         Ok(())
     }
 
     /// Create a new log file with given generation number and add the reader to the readers map
     ///
     /// Returns the writer to the log
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<LogFile>> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let writer = new_log_file(
+            &self.path,
+            gen,
+            &self.naming,
+            &mut self.readers,
+            self.options.file_hook.as_ref(),
+        )?;
+        self.check_open_readers();
+        Ok(writer)
+    }
+
+    /// Reports the open readers reaching the warning ratio of the limit on open files
+    fn check_open_readers(&mut self) {
+        let Some(limit) = fd_soft_limit() else {
+            return;
+        };
+        let open = self.open_reader_count();
+        let near = open as f64 >= limit as f64 * self.options.reader_fd_warning_ratio_or_default();
+        if near && !self.readers_near_fd_limit {
+            self.emit(Some(StoreEvent::ReadersNearFdLimit { open, limit }));
+        }
+        self.readers_near_fd_limit = near;
+    }
+
+    /// Ends the current generation with its footer before the store stops writing to it
+    ///
+    /// Writers waiting on a group commit only get the next generation synced, so the
+    /// generation is synced here if any of them appended to it, or is about to wait.
+    fn seal(&mut self) -> Result<()> {
+        let codec = self.options.codec;
+        let group_committed = self
+            .sync_file
+            .take()
+            .is_some_and(|(gen, _)| gen == self.current_gen)
+            || self.sync_deferred;
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        write_footer(writer, codec)?;
+        if group_committed {
+            writer.sync()?;
+            self.sync_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the ticket to wait on for the sync a commit left to the group commit
+    fn sync_ticket(&mut self) -> Result<Option<SyncTicket>> {
+        if !std::mem::take(&mut self.sync_deferred) {
+            return Ok(None);
+        }
+        let gen = self.current_gen;
+        let file = match &self.sync_file {
+            Some((file_gen, file)) if *file_gen == gen => Arc::clone(file),
+            _ => {
+                let file = Arc::new(self.writer()?.writer.get_ref().try_clone()?);
+                self.sync_file = Some((gen, Arc::clone(&file)));
+                file
+            }
+        };
+        Ok(Some(SyncTicket {
+            group_commit: Arc::clone(&self.group_commit),
+            pos: (gen, self.writer()?.pos),
+            file,
+        }))
+    }
+
+    /// Returns the writer of the current log
+    ///
+    /// Returns `KvsError::ReadOnly` if the store was opened read-only
+    fn writer(&mut self) -> Result<&mut BufWriterWithPos<LogFile>> {
+        self.writer.as_mut().ok_or(KvsError::ReadOnly)
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.options.index_snapshot && !self.options.read_only && !self.closed {
+            // errors cannot be reported here, the next open falls back to a full replay
+            let _ = self.write_index_snapshot();
+        }
+    }
+}
+
+/// Compacts the store every `interval`, and whenever a write asks for it through
+/// `requested`, until it is dropped
+///
+/// Nothing is rewritten at an interval while the log holds no stale bytes. A failed
+/// compaction is retried at the next interval.
+fn schedule_compaction(
+    store: Weak<Mutex<KvStore>>,
+    interval: Option<Duration>,
+    requested: mpsc::Receiver<usize>,
+) {
+    loop {
+        let request = match interval {
+            Some(interval) => requested.recv_timeout(interval),
+            None => requested.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let count = match request {
+            Ok(count) => Some(count),
+            Err(RecvTimeoutError::Timeout) => None,
+            // without a throttle, writes never hand compactions over
+            Err(RecvTimeoutError::Disconnected) => match interval {
+                Some(interval) => {
+                    thread::sleep(interval);
+                    None
+                }
+                None => return,
+            },
+        };
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        let count = match count {
+            Some(count) => count,
+            None => match store.lock() {
+                Ok(store) if store.uncompacted > 0 && store.writer.is_some() => usize::MAX,
+                Ok(_) => continue,
+                Err(_) => return,
+            },
+        };
+        let _ = KvStore::merge_shared(&store, count);
+    }
+}
+
+/// Syncs the writes of `store` once no write was committed for `idle_after`, until
+/// the store is dropped
+fn schedule_idle_flush(store: Weak<Mutex<KvStore>>, idle_after: Duration) {
+    let mut wait = idle_after;
+    loop {
+        thread::sleep(wait);
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        let mut store = match store.lock() {
+            Ok(store) => store,
+            Err(_) => return,
+        };
+        // a write since the last check pushes the sync back
+        let idle = store.last_write.elapsed();
+        wait = match idle_after.checked_sub(idle) {
+            Some(left) if !left.is_zero() => left,
+            _ => {
+                let _ = store.sync_log();
+                idle_after
+            }
+        };
+    }
+}
+
+/// Copies the next `len` bytes of `reader` to `writer` through `buf`, one chunk at a time
+fn copy_chunked(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    len: u64,
+    buf: &mut [u8],
+) -> Result<u64> {
+    let mut left = len;
+    while left > 0 {
+        let chunk_len = left.min(buf.len() as u64) as usize;
+        let chunk = &mut buf[..chunk_len];
+        reader.read_exact(chunk)?;
+        writer.write_all(chunk)?;
+        left -= chunk.len() as u64;
     }
+    Ok(len)
+}
+
+/// Reads the index snapshot in `path`
+///
+/// Returns `None` if the snapshot is missing, unreadable, or refers to generations
+/// which no longer exist
+fn read_index_snapshot(
+    path: &Path,
+    gen_list: &[u64],
+    naming: &LogNaming,
+) -> Option<IndexSnapshot<BTreeMap<String, CommandPos>>> {
+    let buf = fs::read(naming.index_snapshot(path)).ok()?;
+    let r = flexbuffers::Reader::get_root(buf.as_slice()).ok()?;
+    let snapshot = IndexSnapshot::<BTreeMap<String, CommandPos>>::deserialize(r).ok()?;
+
+    // an empty replay generation may have been removed on open, losing no record
+    let replay_gen_is_fresh = if gen_list.contains(&snapshot.replay_gen) {
+        let replay_len = fs::metadata(log_path(path, snapshot.replay_gen, naming))
+            .ok()?
+            .len();
+        replay_len >= snapshot.replay_pos
+    } else {
+        snapshot.replay_pos == 0
+    };
+    // snapshots without value lengths are replaced by a replay which finds them
+    let is_fresh = replay_gen_is_fresh
+        && snapshot
+            .index
+            .values()
+            .all(|cmd_pos| gen_list.contains(&cmd_pos.gen) && cmd_pos.value_len.is_some());
+    is_fresh.then_some(snapshot)
 }
 
 fn new_log_file(
     path: &Path,
     gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(path, gen);
-    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    naming: &LogNaming,
+    readers: &mut Readers,
+    hook: Option<&SharedFileHook>,
+) -> Result<BufWriterWithPos<LogFile>> {
+    let path = log_path(path, gen, naming);
+    let writer = open_log_writer(path.clone(), false, hook)?;
     readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
     Ok(writer)
 }
 
-fn serialize_to_log(write_handle: &mut BufWriterWithPos<File>, logline: KvsLogLine) -> Result<()> {
-    let mut s = flexbuffers::FlexbufferSerializer::new();
-    logline.serialize(&mut s)?;
+/// Opens the log file at `path` for appending, emptying it first if `truncate` is set
+fn open_log_writer(
+    path: PathBuf,
+    truncate: bool,
+    hook: Option<&SharedFileHook>,
+) -> Result<BufWriterWithPos<LogFile>> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut open_options = OpenOptions::new();
+    if truncate {
+        open_options.create(true).write(true).truncate(true);
+    } else {
+        open_options.create(true).append(true);
+    }
+    let file = open_options.open(&path)?;
+    BufWriterWithPos::new(LogFile {
+        file,
+        path,
+        hook: hook.cloned(),
+    })
+}
+
+/// Makes the entries of the directory at `path` durable, such as the files created,
+/// renamed or removed in it
+fn sync_dir(path: &Path, hook: Option<&SharedFileHook>) -> Result<()> {
+    // directories cannot be opened as files everywhere
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    if let Some(hook) = hook {
+        hook.0.dir_synced(path);
+    }
+    Ok(())
+}
+
+/// Returns the soft limit on open files of the process, `None` if unlimited or unknown
+#[cfg(unix)]
+// `rlim_t` is not 64 bits wide on every platform
+#[allow(clippy::unnecessary_cast)]
+fn fd_soft_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for the call to fill in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn fd_soft_limit() -> Option<u64> {
+    None
+}
+
+/// Rejects the empty key, which no engine accepts
+pub(crate) fn check_not_empty(key: &str) -> Result<()> {
+    if key.is_empty() {
+        return Err(KvsError::InvalidKey(String::new()));
+    }
+    Ok(())
+}
+
+fn serialize_to_log(
+    write_handle: &mut BufWriterWithPos<LogFile>,
+    logline: KvsLogLine,
+    codec: CodecKind,
+) -> Result<()> {
+    let buf = encode_logline(logline, codec)?;
     // serialize to the log
-    let size: u32 = s.view().len().try_into().unwrap();
+    let size: u32 = buf.len().try_into().unwrap();
     write_handle.write_all(&(size.to_le_bytes()))?;
-    write_handle.write_all(s.take_buffer().as_slice())?;
-    write_handle.flush()?;
+    write_handle.write_all(buf.as_slice())?;
+    write_handle.records += 1;
     Ok(())
 }
 
-fn deserialize_from_log(reader: &mut BufReaderWithPos<File>) -> Result<KvsLogLine> {
+/// Ends a generation the store stopped writing to with its footer
+///
+/// Nothing is written for an empty generation, nor if the writer did not start at
+/// the beginning of the file, since the checksum would miss the bytes before it.
+fn write_footer(writer: &mut BufWriterWithPos<LogFile>, codec: CodecKind) -> Result<()> {
+    if writer.records == 0 || !writer.from_start {
+        return Ok(());
+    }
+    let footer = KvsLogLine::Footer {
+        records: writer.records,
+        checksum: writer.checksum,
+    };
+    serialize_to_log(writer, footer, codec)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Finds the footer ending the contents of a generation
+///
+/// Returns where the footer starts, along with the record count and the checksum it
+/// holds. As the last record of the generation, the footer is found by trying every
+/// length prefix it can have from the end of the data.
+fn find_footer(data: &[u8], codec: CodecKind) -> Option<(usize, u64, u64)> {
+    let max_len = MAX_FOOTER_LEN.min(data.len().saturating_sub(4));
+    (1..=max_len).find_map(|len| {
+        let start = data.len() - len - 4;
+        let prefix = u32::from_le_bytes(data[start..start + 4].try_into().ok()?);
+        if prefix as usize != len {
+            return None;
+        }
+        match decode_logline(&data[start + 4..], codec) {
+            Ok(KvsLogLine::Footer { records, checksum }) => Some((start, records, checksum)),
+            _ => None,
+        }
+    })
+}
+
+/// Checks the contents of a generation against the checksum of its footer, if it
+/// was sealed with one
+///
+/// Returns the footer found, or a description of the damage if the check fails.
+fn verify_footer(
+    data: &[u8],
+    codec: CodecKind,
+) -> result::Result<Option<(usize, u64, u64)>, String> {
+    let footer = find_footer(data, codec);
+    if let Some((start, _, checksum)) = footer {
+        if fnv1a(FNV_OFFSET, &data[..start]) != checksum {
+            return Err("the contents do not match the checksum of the footer".to_owned());
+        }
+    }
+    Ok(footer)
+}
+
+/// Checks the contents of a generation against its footer, or by decoding every record
+///
+/// Returns a description of the damage if the check fails.
+fn verify_generation(
+    data: &[u8],
+    codec: CodecKind,
+    deep: bool,
+) -> result::Result<Verification, String> {
+    let footer = verify_footer(data, codec)?;
+    if footer.is_some() && !deep {
+        return Ok(Verification::Footer);
+    }
+    let records =
+        KvStore::decode_log_with(io::Cursor::new(data), codec).map_err(|err| err.to_string())?;
+    if let Some((_, count, _)) = footer {
+        if records.len() as u64 != count {
+            return Err(format!(
+                "{} records where the footer counts {}",
+                records.len(),
+                count
+            ));
+        }
+    }
+    Ok(Verification::Replay)
+}
+
+/// Folds `bytes` into the FNV-1a hash `hash`
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Encodes a record with `codec`, keeping every field unless the codec is self-describing
+fn encode_logline(logline: KvsLogLine, codec: CodecKind) -> Result<Vec<u8>> {
+    if codec.is_self_describing() {
+        codec.encode(&logline)
+    } else {
+        codec.encode(&DenseLogLine::from(logline))
+    }
+}
+
+/// Decodes a record written by [`encode_logline`]
+fn decode_logline(buf: &[u8], codec: CodecKind) -> Result<KvsLogLine> {
+    if codec.is_self_describing() {
+        codec.decode(buf)
+    } else {
+        codec.decode::<DenseLogLine>(buf).map(KvsLogLine::from)
+    }
+}
+
+/// Reads the length-prefixed serialized record at the current position of the reader
+fn read_record(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut logline = Vec::new();
+    read_record_into(reader, &mut logline)?;
+    Ok(logline)
+}
+
+/// Reads the next record into `logline`, replacing its contents
+fn read_record_into(reader: &mut impl Read, logline: &mut Vec<u8>) -> Result<()> {
     let mut buffer = [0u8; 4];
     reader.read_exact(&mut buffer)?;
-    let size = u32::from_le_bytes(buffer).try_into()?;
+    let size: usize = u32::from_le_bytes(buffer).try_into()?;
 
-    let mut logline = vec![0u8; size];
-    reader.read_exact(&mut logline)?;
-    let r = flexbuffers::Reader::get_root(logline.as_slice())?;
+    // a corrupted length must not trigger a huge allocation
+    logline.clear();
+    reader.take(size as u64).read_to_end(logline)?;
+    if logline.len() != size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Buffers reused while decoding consecutive records, so they only grow as needed
+#[derive(Default)]
+struct LogScratch {
+    codec: CodecKind,
+    logline: Vec<u8>,
+    encoded: flexbuffers::FlexbufferSerializer,
+}
+
+impl LogScratch {
+    fn new(codec: CodecKind) -> Self {
+        LogScratch {
+            codec,
+            ..LogScratch::default()
+        }
+    }
+}
+
+/// Reads and decodes the next record using the buffers of `scratch`
+///
+/// Returns `KvsError::MisalignedRecord` if the record does not span exactly its
+/// length prefix, since the reader would otherwise silently lose track of where
+/// the following records start.
+fn deserialize_into(reader: &mut impl Read, scratch: &mut LogScratch) -> Result<KvsLogLine> {
+    read_record_into(reader, &mut scratch.logline)?;
+    let codec = scratch.codec;
+    if codec != CodecKind::Flexbuffers {
+        // other codecs may ignore trailing bytes, so the same check applies to them
+        let (kvslogline, encoded) = if codec.is_self_describing() {
+            let logline: KvsLogLine = codec.decode(&scratch.logline)?;
+            let encoded = codec.encode(&logline)?;
+            (logline, encoded)
+        } else {
+            let logline: DenseLogLine = codec.decode(&scratch.logline)?;
+            let encoded = codec.encode(&logline)?;
+            (logline.into(), encoded)
+        };
+        if encoded != scratch.logline {
+            return Err(KvsError::MisalignedRecord(scratch.logline.len()));
+        }
+        return Ok(kvslogline);
+    }
+    let r = flexbuffers::Reader::get_root(scratch.logline.as_slice())?;
     let kvslogline = KvsLogLine::deserialize(r)?;
+
+    // flexbuffers decode from the end of the buffer, so a wrong length prefix can still
+    // decode, e.g. when it covers the following record too; encoding is deterministic
+    // so a well-formed record encodes back to exactly its bytes
+    scratch.encoded.reset();
+    kvslogline.serialize(&mut scratch.encoded)?;
+    if scratch.encoded.view() != scratch.logline.as_slice() {
+        return Err(KvsError::MisalignedRecord(scratch.logline.len()));
+    }
     Ok(kvslogline)
 }
 
+/// Reads the value of `key` from the record at `cmd_pos`
+///
+/// Returns `KvsError::UnexpectedCommandType` if the record is not a `Set`
+fn read_value(
+    readers: &mut Readers,
+    key: &str,
+    cmd_pos: &CommandPos,
+    codec: CodecKind,
+) -> Result<String> {
+    read_typed_value(readers, key, cmd_pos, codec).map(|(_, value)| value)
+}
+
+/// Reads the value of `key` from the record at `cmd_pos` along with its type
+///
+/// Returns `KvsError::UnexpectedCommandType` if the record is not a `Set`
+fn read_typed_value(
+    readers: &mut Readers,
+    key: &str,
+    cmd_pos: &CommandPos,
+    codec: CodecKind,
+) -> Result<(ValueType, String)> {
+    let reader = readers.get_mut(cmd_pos.gen)?;
+    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    value_from_log(reader, codec)?.ok_or_else(|| KvsError::UnexpectedCommandType {
+        key: key.to_owned(),
+        gen: cmd_pos.gen,
+        pos: cmd_pos.pos,
+    })
+}
+
+/// Reads the value of a `Set` record and its type without deserializing its key
+///
+/// Returns `None` if the record is not a `Set`. Only flexbuffers records can be read
+/// partially, records of other codecs are decoded whole.
+fn value_from_log(reader: &mut impl Read, codec: CodecKind) -> Result<Option<(ValueType, String)>> {
+    let logline = read_record(reader)?;
+    if codec != CodecKind::Flexbuffers {
+        return Ok(match decode_logline(&logline, codec)? {
+            KvsLogLine::Set {
+                value, value_type, ..
+            } => Some((value_type, value)),
+            KvsLogLine::Rm { .. } | KvsLogLine::Footer { .. } => None,
+        });
+    }
+    let r = flexbuffers::Reader::get_root(logline.as_slice())?;
+    let Ok(set) = r.as_map().index("Set") else {
+        return Ok(None);
+    };
+    let set = set.as_map();
+    let value_type = match set.index("value_type") {
+        Ok(value_type) => ValueType::deserialize(value_type)?,
+        Err(_) => ValueType::Str,
+    };
+    Ok(Some((
+        value_type,
+        set.index("value")?.get_str()?.to_owned(),
+    )))
+}
+
+/// Returns the milliseconds from the Unix epoch to `time`, or 0 for earlier times
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// Quotes and escapes a string for JSON
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Reads a value straight from its log record, or from memory once decoded
+enum ValueReader {
+    Log(io::Take<File>),
+    Decoded(io::Cursor<Vec<u8>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Log(reader) => reader.read(buf),
+            ValueReader::Decoded(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// The flexbuffer type of maps, see `flexbuffers::FlexBufferType`
+const FLEXBUFFER_MAP: u8 = 9;
+/// The flexbuffer type of strings, see `flexbuffers::FlexBufferType`
+const FLEXBUFFER_STRING: u8 = 5;
+
+/// Follows the offsets of the flexbuffer in a log record by reading single fields
+///
+/// A flexbuffer is decoded from its root at the end of the buffer, where every
+/// value is either inline or an offset back to its contents. Positions are absolute
+/// offsets in the log file and each read is checked to stay within the record.
+struct RecordCursor {
+    file: File,
+    start: u64,
+    end: u64,
+}
+
+impl RecordCursor {
+    fn new(file: File, cmd_pos: &CommandPos) -> Self {
+        RecordCursor {
+            file,
+            // skip the length prefix
+            start: cmd_pos.pos + 4,
+            end: cmd_pos.pos + cmd_pos.len,
+        }
+    }
+
+    /// Returns the position of the value of a `Set` record, or `None` for other records
+    fn value_span(&mut self) -> Result<Option<Range<u64>>> {
+        let root_width = self.read_width(self.end.saturating_sub(1))?;
+        let root_type = self.read_uint(self.end.saturating_sub(2), 1)? as u8;
+        let root_pos = self.sub(self.end - 2, root_width)?;
+        let root = self.deref(root_pos, root_width)?;
+
+        let Some((set, set_type)) = self.map_entry(root, root_type, "Set")? else {
+            return Ok(None);
+        };
+        let inner = self.deref(set, packed_width(root_type))?;
+        let (value, value_type) = self
+            .map_entry(inner, set_type, "value")?
+            .ok_or_else(malformed_record)?;
+        if value_type >> 2 != FLEXBUFFER_STRING {
+            return Err(malformed_record());
+        }
+        let value = self.deref(value, packed_width(set_type))?;
+        let len_width = packed_width(value_type);
+        let len = self.read_uint(self.sub(value, len_width)?, len_width)?;
+        if value
+            .checked_add(len)
+            .is_none_or(|value_end| value_end > self.end)
+        {
+            return Err(malformed_record());
+        }
+        Ok(Some(value..value + len))
+    }
+
+    /// Returns the position and packed type of the entry `key` of the map at `map`
+    fn map_entry(&mut self, map: u64, map_type: u8, key: &str) -> Result<Option<(u64, u8)>> {
+        if map_type >> 2 != FLEXBUFFER_MAP {
+            return Err(malformed_record());
+        }
+        let width = packed_width(map_type);
+        let len = self.read_uint(self.sub(map, width)?, width)?;
+        let keys_width = self.read_width(self.sub(map, 2 * width)?)?;
+        let keys = self.deref(self.sub(map, 3 * width)?, width)?;
+        for i in 0..len {
+            let key_pos = self.deref(keys + i * keys_width, keys_width)?;
+            if self.key_is(key_pos, key)? {
+                let types = map.saturating_add(len.saturating_mul(width));
+                let entry_type = self.read_uint(types.saturating_add(i), 1)? as u8;
+                return Ok(Some((map + i * width, entry_type)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns whether the nul-terminated key at `pos` is `key`
+    fn key_is(&mut self, pos: u64, key: &str) -> Result<bool> {
+        for (i, byte) in key.bytes().chain([0]).enumerate() {
+            if self.read_uint(pos + i as u64, 1)? != u64::from(byte) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the position the offset of `width` bytes at `pos` points to
+    fn deref(&mut self, pos: u64, width: u64) -> Result<u64> {
+        let offset = self.read_uint(pos, width)?;
+        self.sub(pos, offset)
+    }
+
+    /// Reads a byte width, which must be a power of two up to 8
+    fn read_width(&mut self, pos: u64) -> Result<u64> {
+        match self.read_uint(pos, 1)? {
+            width @ (1 | 2 | 4 | 8) => Ok(width),
+            _ => Err(malformed_record()),
+        }
+    }
+
+    /// Reads an unsigned little-endian integer of `width` bytes at `pos`
+    fn read_uint(&mut self, pos: u64, width: u64) -> Result<u64> {
+        if pos < self.start || pos.saturating_add(width) > self.end {
+            return Err(malformed_record());
+        }
+        let mut bytes = [0u8; 8];
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read_exact(&mut bytes[..width as usize])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Subtracts an offset from a position, failing if it leaves the record
+    fn sub(&self, pos: u64, offset: u64) -> Result<u64> {
+        pos.checked_sub(offset)
+            .filter(|&pos| pos >= self.start)
+            .ok_or_else(malformed_record)
+    }
+}
+
+/// Returns the byte width stored in the low bits of a packed flexbuffer type
+fn packed_width(packed_type: u8) -> u64 {
+    1 << (packed_type & 3)
+}
+
+fn malformed_record() -> KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed log record").into()
+}
+
+/// Replays the records of generation `gen` starting at offset `start` into the index
+///
+/// Returns the number of stale bytes found. With `OpenMode::Fast` a corrupted record
+/// is skipped by its length prefix and counted as stale instead of failing, while a
+/// record cut short by the end of the generation ends the replay.
 fn load(
     gen: u64,
+    start: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
+    index: &mut BTreeMap<IndexKey, CommandPos>,
+    collation: Collation,
+    open_mode: OpenMode,
+    codec: CodecKind,
 ) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
     let mut uncompacted = 0;
+    let mut scratch = LogScratch::new(codec);
     while !reader.is_empty()? {
-        let kvslogline = deserialize_from_log(reader)?;
+        let kvslogline = match deserialize_into(reader, &mut scratch) {
+            Ok(kvslogline) => kvslogline,
+            // a record cut short by the end of the generation ends it
+            Err(KvsError::Io(err))
+                if open_mode == OpenMode::Fast && err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            // any other corrupted record was read whole, so the replay goes on after it
+            Err(err) if open_mode == OpenMode::Fast && is_corruption(&err) => {
+                uncompacted += reader.pos - pos;
+                pos = reader.pos;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
         let new_pos = reader.pos;
         match kvslogline {
-            KvsLogLine::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
+            KvsLogLine::Set {
+                key,
+                value,
+                expires_at,
+                modified_at,
+                ..
+            } => {
+                let key = IndexKey::new(key, collation);
+                let cmd_pos = CommandPos {
+                    expires_at,
+                    modified_at,
+                    value_len: Some(value.len() as u64),
+                    ..(gen, pos..new_pos).into()
+                };
+                if let Some(old_cmd) = index.insert(key, cmd_pos) {
                     uncompacted += old_cmd.len;
                 }
             }
             KvsLogLine::Rm { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
+                if let Some(old_cmd) = index.remove(&IndexKey::new(key, collation)) {
                     uncompacted += old_cmd.len;
                 }
                 uncompacted += new_pos - pos;
             }
+            // the footer is dropped along with its generation, it is never stale
+            KvsLogLine::Footer { .. } => (),
         }
         pos = new_pos;
     }
     Ok(uncompacted)
 }
 
-fn log_path(path: &Path, gen: u64) -> PathBuf {
-    path.join(format!("{}.log", gen))
+/// Returns whether `err` comes from a malformed record rather than a failing read
+fn is_corruption(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Deserializer(_)
+        | KvsError::Reader(_)
+        | KvsError::Bincode(_)
+        | KvsError::Json(_)
+        | KvsError::TryFromInt(_)
+        | KvsError::MisalignedRecord(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the generation number `step` after `gen`
+///
+/// Returns `KvsError::GenerationOverflow` rather than wrapping, which would reuse
+/// the numbers of existing generations.
+fn gen_after(gen: u64, step: u64) -> Result<u64> {
+    gen.checked_add(step)
+        .ok_or(KvsError::GenerationOverflow(gen))
 }
 
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
+/// The pairs of a `KvStore` listed by [`KvsEngine::iter`]
+struct StoreIter {
+    path: PathBuf,
+    naming: LogNaming,
+    codec: CodecKind,
+    entries: std::vec::IntoIter<(String, CommandPos)>,
+    // the generation read last, and its reader
+    reader: Option<(u64, BufReaderWithPos<File>)>,
+}
+
+impl StoreIter {
+    fn read(&mut self, key: String, cmd_pos: CommandPos) -> Result<(String, String)> {
+        let reader = match &mut self.reader {
+            Some((gen, reader)) if *gen == cmd_pos.gen => reader,
+            reader => {
+                let file = File::open(log_path(&self.path, cmd_pos.gen, &self.naming))?;
+                &mut reader.insert((cmd_pos.gen, BufReaderWithPos::new(file)?)).1
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        match value_from_log(reader, self.codec)? {
+            Some((_, value)) => Ok((key, value)),
+            None => Err(KvsError::UnexpectedCommandType {
+                key,
+                gen: cmd_pos.gen,
+                pos: cmd_pos.pos,
+            }),
+        }
+    }
+}
+
+impl Iterator for StoreIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = self.entries.next()?;
+        Some(self.read(key, cmd_pos))
+    }
+}
+
+/// Decides where the generation files of a store live and how they are named
+///
+/// Generation files are named `<prefix><gen>.<extension>`, by default `<gen>.log`.
+/// The index snapshot shares the prefix, so stores with different prefixes can
+/// share a directory.
+#[derive(Clone)]
+struct LogNaming {
+    layout: DirLayout,
+    prefix: String,
+    extension: String,
+}
+
+impl LogNaming {
+    fn new(options: &KvStoreOptions) -> Self {
+        LogNaming {
+            layout: options.layout,
+            prefix: options.log_prefix.clone(),
+            extension: options.log_extension_or_default().to_owned(),
+        }
+    }
+
+    /// Returns the file name of generation `gen`
+    fn file_name(&self, gen: u64) -> String {
+        format!("{}{}.{}", self.prefix, gen, self.extension)
+    }
+
+    /// Returns the part of a generation file name between the prefix and the
+    /// extension, or `None` if `path` is not named like a generation file
+    fn stem<'a>(&self, path: &'a Path) -> Option<&'a str> {
+        path.file_name()
+            .and_then(OsStr::to_str)?
+            .strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.extension.as_str())?
+            .strip_suffix('.')
+    }
+
+    /// Returns the path the copy of a compaction into generation `gen` is written to
+    /// until it is complete
+    fn compaction_temp(&self, path: &Path, gen: u64) -> PathBuf {
+        let file_name = format!(
+            "{}{}.{}",
+            self.prefix, COMPACTION_TEMP_STEMS[0], self.extension
+        );
+        log_path(path, gen, self).with_file_name(file_name)
+    }
+
+    /// Returns the path of the index snapshot
+    fn index_snapshot(&self, path: &Path) -> PathBuf {
+        path.join(format!("{}{}", self.prefix, INDEX_SNAPSHOT))
+    }
+}
+
+fn log_path(path: &Path, gen: u64, naming: &LogNaming) -> PathBuf {
+    match naming.layout {
+        DirLayout::Flat => path.join(naming.file_name(gen)),
+        DirLayout::Nested { gens_per_dir } => path
+            .join(format!("{:03}", gen / gens_per_dir))
+            .join(naming.file_name(gen)),
+    }
+}
+
+/// Returns the directories which may hold generation files
+fn log_dirs(path: &Path, naming: &LogNaming) -> Result<Vec<PathBuf>> {
+    if naming.layout == DirLayout::Flat {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let dir = entry?.path();
+        let is_group = dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()));
+        if dir.is_dir() && is_group {
+            dirs.push(dir);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Parses the generation number out of a generation file name
+fn parse_gen(path: &Path, naming: &LogNaming) -> Option<u64> {
+    if !path.is_file() {
+        return None;
+    }
+    naming.stem(path).and_then(|s| s.parse::<u64>().ok())
+}
+
+/// Removes the empty files of `gen_list`, returning the remaining generations
+fn remove_empty_generations(
+    path: &Path,
+    gen_list: Vec<u64>,
+    naming: &LogNaming,
+) -> Result<Vec<u64>> {
+    let mut remaining = Vec::with_capacity(gen_list.len());
+    for gen in gen_list {
+        let gen_path = log_path(path, gen, naming);
+        if fs::metadata(&gen_path)?.len() == 0 {
+            fs::remove_file(gen_path)?;
+        } else {
+            remaining.push(gen);
+        }
+    }
+    Ok(remaining)
+}
+
+/// Removes files left behind by an interrupted write: the temporary index snapshot
+/// and the output of an interrupted compaction
+///
+/// Other files with the prefix and extension of generation files which are not named
+/// after a generation number are left alone, and returned to be reported
+fn remove_orphaned_files(path: &Path, naming: &LogNaming) -> Result<Vec<PathBuf>> {
+    let mut dirs = log_dirs(path, naming)?;
+    if naming.layout != DirLayout::Flat {
+        dirs.push(path.to_path_buf());
+    }
+    let temp_snapshot = naming.index_snapshot(path).with_extension("tmp");
+    let mut unknown = Vec::new();
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let file_path = entry?.path();
+            if !file_path.is_file() {
+                continue;
+            }
+            let is_orphan = match naming.stem(&file_path) {
+                Some(stem) if COMPACTION_TEMP_STEMS.contains(&stem) => true,
+                Some(_) => {
+                    if parse_gen(&file_path, naming).is_none() {
+                        unknown.push(file_path);
+                    }
+                    continue;
+                }
+                None => file_path.file_name() == temp_snapshot.file_name(),
+            };
+            if is_orphan {
+                fs::remove_file(file_path)?;
+            }
+        }
+    }
+    Ok(unknown)
+}
+
+fn sorted_gen_list(path: &Path, naming: &LogNaming) -> Result<Vec<u64>> {
+    let mut gen_list = Vec::new();
+    for dir in log_dirs(path, naming)? {
+        gen_list.extend(
+            fs::read_dir(dir)?
+                .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+                .filter_map(|path| parse_gen(&path, naming)),
+        );
+    }
     gen_list.sort_unstable();
     Ok(gen_list)
 }
@@ -1,12 +1,31 @@
 #![warn(missing_docs)]
 
 //! Implemtation for the kvs crate
-pub use common::{get_current_engine,log_engine};
+pub use client::{GenerationFollower, KeysIter, KvsClient, ScanStream};
+pub use codec::{BincodeCodec, Codec, CodecKind, FlexbuffersCodec, JsonCodec};
+pub use common::{
+    configure_stream, db_key, get_current_engine, is_compatible_version, key_in_db, log_engine,
+    set_buffer_pool_size, ttl_seconds, DEFAULT_BUFFER_POOL_SIZE,
+};
 pub use common::{Commands, NetworkConnection};
-pub use error::KvsError;
-pub use kvs::{KvStore, KvsEngine, Result};
+pub use engine::SledKvsEngine;
+pub use error::{ErrorKind, KvsError};
+pub use kvs::{
+    CompactionStatus, EngineStats, KvStore, KvsEngine, LogRecord, PairIter, Result, ValueMeta,
+    ValueType, Verification,
+};
+pub use options::{
+    Clock, Collation, CompactionStrategy, DirLayout, FileHook, FlushPolicy, KvStoreOptions,
+    OpenMode, StoreEvent, SystemClock, TtlClock,
+};
+pub use sharded::ShardedKvStore;
 
+mod client;
+mod codec;
 mod common;
 mod engine;
 mod error;
 mod kvs;
+mod options;
+mod sharded;
+mod trace;
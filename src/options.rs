@@ -0,0 +1,705 @@
+use crate::codec::CodecKind;
+use crate::error::KvsError;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const CLOCK_SKEW_THRESHOLD: Duration = Duration::from_secs(1);
+const COMPACTION_BUFFER_BYTES: usize = 64 * 1024;
+const READER_FD_WARNING_RATIO: f64 = 0.8;
+
+/// Decides when the log of a `KvStore` gets compacted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// Compact once the stale bytes in the log exceed the given size
+    SizeThreshold(u64),
+    /// Compact once the stale bytes make up more than the given fraction of the log
+    Ratio(f64),
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::SizeThreshold(COMPACTION_THRESHOLD)
+    }
+}
+
+impl CompactionStrategy {
+    /// Returns whether a log with `uncompacted` stale bytes out of `total` bytes should be compacted
+    pub(crate) fn should_compact(&self, uncompacted: u64, total: u64) -> bool {
+        match *self {
+            CompactionStrategy::SizeThreshold(threshold) => uncompacted > threshold,
+            CompactionStrategy::Ratio(ratio) => {
+                total > 0 && uncompacted as f64 / total as f64 > ratio
+            }
+        }
+    }
+}
+
+/// Describes how generation files are laid out in the data directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DirLayout {
+    /// All `<gen>.log` files live directly in the data directory
+    #[default]
+    Flat,
+    /// `<gen>.log` files are grouped into subdirectories named after
+    /// `gen / gens_per_dir`, e.g. `000/`, `001/`
+    Nested {
+        /// The number of generations sharing a subdirectory, must not be zero
+        gens_per_dir: u64,
+    },
+}
+
+/// Decides when an engine makes its writes durable
+///
+/// Writes are always visible to reads right away; the policy only decides when
+/// they are synced to disk. [`KvsEngine::flush`](crate::KvsEngine::flush) syncs
+/// under any policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Sync after every record, including each record of a batch
+    Always,
+    /// Sync once per committed operation, so a batch costs a single sync
+    OnCommitBatch,
+    /// Sync on a commit once the given time has passed since the last sync
+    Interval(Duration),
+    /// Only sync on an explicit flush
+    #[default]
+    Manual,
+}
+
+impl FlushPolicy {
+    /// Returns whether a commit made `since_sync` after the last sync should sync
+    pub(crate) fn syncs_commit(&self, since_sync: Duration) -> bool {
+        match *self {
+            FlushPolicy::Always | FlushPolicy::OnCommitBatch => true,
+            FlushPolicy::Interval(interval) => since_sync >= interval,
+            FlushPolicy::Manual => false,
+        }
+    }
+}
+
+/// Parses `always`, `on-commit-batch`, `manual` or `interval:<MILLIS>`
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "always" => Ok(FlushPolicy::Always),
+            "on-commit-batch" => Ok(FlushPolicy::OnCommitBatch),
+            "manual" => Ok(FlushPolicy::Manual),
+            _ => policy
+                .strip_prefix("interval:")
+                .and_then(|millis| millis.parse().ok())
+                .map(|millis| FlushPolicy::Interval(Duration::from_millis(millis)))
+                .ok_or_else(|| format!("unknown flush policy: {}", policy)),
+        }
+    }
+}
+
+/// Decides how a `KvStore` treats corrupted records while replaying its log on open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenMode {
+    /// Skip corrupted records, keeping the others
+    ///
+    /// A record which fails to decode is skipped by its length prefix. A record cut
+    /// short by the end of a generation, as a torn write leaves it, ends the replay
+    /// of the generation.
+    Fast,
+    /// Fail to open on any corrupted record
+    ///
+    /// Records are verified by their length prefix and encoding, and generations sealed
+    /// with a footer by its checksum.
+    #[default]
+    Strict,
+}
+
+/// Decides the order of keys in the index of a `KvStore`
+///
+/// The collation only affects ordering; keys are always identified by their exact bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// Keys are ordered by their bytes
+    #[default]
+    Binary,
+    /// Keys are ordered ignoring case, with keys differing only in case ordered by their bytes
+    CaseInsensitive,
+}
+
+impl Collation {
+    /// Compares two keys according to the collation
+    pub(crate) fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::CaseInsensitive => a
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(b.chars().flat_map(char::to_lowercase))
+                .then_with(|| a.cmp(b)),
+        }
+    }
+
+    /// Returns whether `key` starts with `prefix` when compared according to the collation
+    ///
+    /// The keys matching a prefix this way follow each other in collation order.
+    pub(crate) fn matches_prefix(&self, key: &str, prefix: &str) -> bool {
+        match self {
+            Collation::Binary => key.starts_with(prefix),
+            Collation::CaseInsensitive => {
+                let mut key = key.chars().flat_map(char::to_lowercase);
+                prefix
+                    .chars()
+                    .flat_map(char::to_lowercase)
+                    .all(|c| key.next() == Some(c))
+            }
+        }
+    }
+}
+
+/// A source of the current time for the expiry of keys, replaceable in tests
+pub trait Clock: Send + Sync {
+    /// Returns the wall-clock time, which may jump in either direction
+    fn wall(&self) -> SystemTime;
+    /// Returns a monotonic time, which never goes backward
+    fn monotonic(&self) -> Instant;
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Observes and alters the writes and syncs of the generation files of a `KvStore`,
+/// to simulate failing disks and crashes in tests
+pub trait FileHook: Send + Sync {
+    /// Called before `len` bytes are written to the file at `path`
+    ///
+    /// Returns how many of them reach the file, or an error failing the write instead.
+    fn write(&self, path: &Path, len: usize) -> io::Result<usize> {
+        let _ = path;
+        Ok(len)
+    }
+    /// Called once the data written to the file at `path` reached the disk
+    fn synced(&self, _path: &Path) {}
+    /// Called once the entries of the directory at `path` reached the disk
+    fn dir_synced(&self, _path: &Path) {}
+    /// Called once the file at `from` was renamed to `to`
+    fn renamed(&self, _from: &Path, _to: &Path) {}
+}
+
+/// The file hook of a `KvStore`
+#[derive(Clone)]
+pub(crate) struct SharedFileHook(pub(crate) Arc<dyn FileHook>);
+
+impl fmt::Debug for SharedFileHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedFileHook")
+    }
+}
+
+/// Decides which time the deadlines of keys with a TTL are compared against
+///
+/// Deadlines are stored in the log as wall-clock times, so they hold across reopening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TtlClock {
+    /// The wall clock, so a backward jump of the clock makes expired keys readable again
+    #[default]
+    Wall,
+    /// The wall clock as of opening the store, advanced by the monotonic clock
+    ///
+    /// Jumps of the wall clock while the store is open are ignored, at the cost of
+    /// drifting from the wall clock if it is corrected.
+    Monotonic,
+}
+
+/// The clock used by a `KvStore`
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub(crate) fn wall(&self) -> SystemTime {
+        self.0.wall()
+    }
+
+    pub(crate) fn monotonic(&self) -> Instant {
+        self.0.monotonic()
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock(Arc::new(SystemClock))
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedClock")
+    }
+}
+
+/// An event emitted by a `KvStore` after an operation is committed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// A key was set
+    Set {
+        /// The key that was set
+        key: String,
+    },
+    /// A key was removed
+    Removed {
+        /// The key that was removed
+        key: String,
+    },
+    /// The log was compacted
+    Compacted {
+        /// The number of stale bytes dropped from the log
+        reclaimed: u64,
+    },
+    /// The wall clock moved backward between two writes by more than the threshold
+    ///
+    /// With `TtlClock::Wall`, keys which expired in the meantime are readable again
+    /// until the clock catches up.
+    ClockMovedBackward {
+        /// How far the clock moved backward
+        by: Duration,
+    },
+    /// The open generation readers reached the warning ratio of the soft limit on open
+    /// files of the process
+    ///
+    /// It is reported again only after the count dropped below the ratio.
+    ReadersNearFdLimit {
+        /// The number of open generation readers
+        open: usize,
+        /// The soft limit on open files
+        limit: u64,
+    },
+    /// A file named like a generation file but not after a generation number was found
+    /// on open
+    ///
+    /// The store does not know the file, so it is left in place.
+    UnknownFile {
+        /// The path of the file
+        path: PathBuf,
+    },
+}
+
+/// A callback observing store events
+#[derive(Clone)]
+pub(crate) struct EventCallback(Arc<dyn Fn(&StoreEvent) + Send + Sync>);
+
+impl EventCallback {
+    pub(crate) fn call(&self, event: &StoreEvent) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventCallback")
+    }
+}
+
+/// Options used to open a `KvStore`
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    pub(crate) compaction_strategy: CompactionStrategy,
+    pub(crate) flush_policy: FlushPolicy,
+    pub(crate) index_snapshot: bool,
+    pub(crate) layout: DirLayout,
+    pub(crate) collation: Collation,
+    pub(crate) open_mode: OpenMode,
+    pub(crate) compaction_bytes_per_sec: Option<u64>,
+    pub(crate) compaction_buffer_bytes: Option<usize>,
+    pub(crate) compaction_interval: Option<Duration>,
+    pub(crate) flush_idle_after: Option<Duration>,
+    pub(crate) min_reclaim_ratio: Option<f64>,
+    pub(crate) log_prefix: String,
+    pub(crate) log_extension: Option<String>,
+    pub(crate) multi_get_threads: Option<usize>,
+    pub(crate) on_event: Option<EventCallback>,
+    pub(crate) key_validator: Option<fn(&str) -> bool>,
+    pub(crate) retain_compacted: bool,
+    pub(crate) max_generations: Option<usize>,
+    pub(crate) reader_cache_size: Option<usize>,
+    pub(crate) reader_fd_warning_ratio: Option<f64>,
+    pub(crate) ttl_clock: TtlClock,
+    pub(crate) clock: SharedClock,
+    pub(crate) clock_skew_threshold: Option<Duration>,
+    pub(crate) codec: CodecKind,
+    pub(crate) read_only: bool,
+    pub(crate) sync_compaction: Option<bool>,
+    pub(crate) file_hook: Option<SharedFileHook>,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Sets the strategy deciding when the log is compacted
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction_strategy = strategy;
+        self
+    }
+
+    /// Sets whether every committed write is synced to disk before returning
+    ///
+    /// This is a shorthand for the `OnCommitBatch` flush policy when enabled and
+    /// the `Manual` one otherwise. Writers sharing a store through
+    /// [`KvStore::set_shared`](crate::KvStore::set_shared) and
+    /// [`KvStore::remove_shared`](crate::KvStore::remove_shared) wait for a
+    /// single sync covering all of their writes.
+    pub fn sync_on_write(self, sync_on_write: bool) -> Self {
+        self.flush_policy(if sync_on_write {
+            FlushPolicy::OnCommitBatch
+        } else {
+            FlushPolicy::Manual
+        })
+    }
+
+    /// Sets when writes are synced to disk, `Manual` by default
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Sets whether the index is persisted on clean shutdown and compaction
+    ///
+    /// When enabled, `open` loads the persisted index and only replays the
+    /// records appended after it, falling back to a full replay if the
+    /// snapshot is missing or stale.
+    pub fn index_snapshot(mut self, index_snapshot: bool) -> Self {
+        self.index_snapshot = index_snapshot;
+        self
+    }
+
+    /// Sets how generation files are laid out in the data directory
+    ///
+    /// A store must always be reopened with the layout it was created with.
+    pub fn layout(mut self, layout: DirLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Sets the codec encoding the records of the log, flexbuffers by default
+    ///
+    /// A store must always be reopened with the codec it was created with, and
+    /// exports can only be imported into stores using the same codec. Only flexbuffers
+    /// records can be read without decoding them whole.
+    pub fn codec(mut self, codec: CodecKind) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets whether the store is opened without ever writing to its directory
+    ///
+    /// A read-only store only replays the log, so it can be opened from a read-only
+    /// mount such as a filesystem snapshot. The directory is neither created nor
+    /// cleaned of leftover files, and every write returns `KvsError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the order in which keys are returned by range scans and exports
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self
+    }
+
+    /// Limits how many bytes of live records compaction copies per second
+    ///
+    /// Compaction sleeps after every batch of records copied whenever it gets ahead of
+    /// the limit, spreading its I/O over time instead of saturating the disk.
+    /// [`KvStore::compact_shared`](crate::KvStore::compact_shared) sleeps with the lock
+    /// of the store released. The limit must not be zero.
+    pub fn compaction_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.compaction_bytes_per_sec = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets the size of the buffer compaction copies live records through, 64 KiB
+    /// by default
+    ///
+    /// Records are copied a buffer at a time rather than read whole, so the memory
+    /// used by a compaction stays bounded however large the values are. A size of
+    /// zero is treated as one byte.
+    pub fn compaction_buffer_bytes(mut self, buffer_bytes: usize) -> Self {
+        self.compaction_buffer_bytes = Some(buffer_bytes);
+        self
+    }
+
+    /// Compacts the log on a schedule, whatever the compaction strategy says
+    ///
+    /// Only stores opened with [`KvStore::open_shared`](crate::KvStore::open_shared)
+    /// are compacted in the background, and only when the log holds stale bytes.
+    /// Compactions triggered by the strategy keep happening on writes.
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction_interval = Some(interval);
+        self
+    }
+
+    /// Syncs the writes to disk once the store has been idle for `idle_after`
+    ///
+    /// Writes reach the operating system on every commit, but with a flush policy
+    /// syncing rarely a burst followed by silence could stay unsynced indefinitely,
+    /// and be lost if the machine crashes. The sync bounds that window without
+    /// syncing every write. Only stores opened with
+    /// [`KvStore::open_shared`](crate::KvStore::open_shared) are synced in the
+    /// background, while holding the lock of the store. The duration must not be zero.
+    pub fn flush_idle_after(mut self, idle_after: Duration) -> Self {
+        self.flush_idle_after = Some(idle_after);
+        self
+    }
+
+    /// Returns the size of the compaction copy buffer
+    pub(crate) fn compaction_buffer_bytes_or_default(&self) -> usize {
+        self.compaction_buffer_bytes
+            .unwrap_or(COMPACTION_BUFFER_BYTES)
+            .max(1)
+    }
+
+    /// Caps the threads [`KvStore::multi_get_parallel`](crate::KvStore::multi_get_parallel)
+    /// reads with, the available parallelism of the machine by default
+    ///
+    /// A cap of zero is treated as one thread.
+    pub fn multi_get_threads(mut self, threads: usize) -> Self {
+        self.multi_get_threads = Some(threads);
+        self
+    }
+
+    /// Returns the most threads a parallel multi-get reads with
+    pub(crate) fn multi_get_threads_or_default(&self) -> usize {
+        self.multi_get_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from))
+            .max(1)
+    }
+
+    /// Sets whether compaction keeps the generations it replaces
+    ///
+    /// When enabled, compacted generation files are moved into an `archive/`
+    /// subdirectory of the data directory instead of being deleted, keeping the full
+    /// history of writes for recovery. The archive is never read by the store and
+    /// never shrinks, so it grows by the size of the log at every compaction and
+    /// has to be pruned by hand.
+    pub fn retain_compacted(mut self, retain_compacted: bool) -> Self {
+        self.retain_compacted = retain_compacted;
+        self
+    }
+
+    /// Limits the number of generation files, whatever the stale bytes in the log
+    ///
+    /// Once a write leaves more than `max_generations` files, the oldest generations
+    /// are merged into one, copying only their live records, so that the limit holds
+    /// again. Since the merge continues writing in a fresh generation, a limit below
+    /// 2 compacts the whole log every time.
+    pub fn max_generations(mut self, max_generations: usize) -> Self {
+        self.max_generations = Some(max_generations);
+        self
+    }
+
+    /// Limits the number of generation files kept open for reading
+    ///
+    /// By default every generation keeps its reader open. With a limit, the reader
+    /// used least recently is closed to make room, and generations whose reader was
+    /// closed are reopened when a read needs them. At least one reader stays open.
+    pub fn reader_cache_size(mut self, reader_cache_size: usize) -> Self {
+        self.reader_cache_size = Some(reader_cache_size);
+        self
+    }
+
+    /// Sets the fraction of the soft limit on open files, 0.8 by default, at which
+    /// open generation readers are reported with `StoreEvent::ReadersNearFdLimit`
+    ///
+    /// The ratio must be in (0, 1]. Nothing is reported where the limit is unknown
+    /// or unlimited.
+    pub fn reader_fd_warning_ratio(mut self, ratio: f64) -> Self {
+        self.reader_fd_warning_ratio = Some(ratio);
+        self
+    }
+
+    /// Returns the fraction of the soft limit on open files at which readers are reported
+    pub(crate) fn reader_fd_warning_ratio_or_default(&self) -> f64 {
+        self.reader_fd_warning_ratio
+            .unwrap_or(READER_FD_WARNING_RATIO)
+    }
+
+    /// Sets which time the deadlines of keys with a TTL are compared against
+    pub fn ttl_clock(mut self, ttl_clock: TtlClock) -> Self {
+        self.ttl_clock = ttl_clock;
+        self
+    }
+
+    /// Replaces the clock of the operating system, e.g. to simulate clock jumps
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock(Arc::new(clock));
+        self
+    }
+
+    /// Sets how far the wall clock may move backward between writes before a
+    /// `StoreEvent::ClockMovedBackward` is emitted, 1 second by default
+    pub fn clock_skew_threshold(mut self, threshold: Duration) -> Self {
+        self.clock_skew_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets the extension of generation files, `log` by default
+    ///
+    /// A leading `.` is ignored, and the extension must not be empty or contain a
+    /// path separator. Generation files are found by their extension
+    /// when the store is opened, so a store must always be opened with the same one.
+    pub fn log_extension(mut self, extension: &str) -> Self {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        self.log_extension = Some(extension.to_owned());
+        self
+    }
+
+    /// Sets a prefix of the names of generation files and of the index snapshot,
+    /// empty by default
+    ///
+    /// Stores whose file names set them apart can share a directory. On open a
+    /// store removes the files with its prefix and extension which are not named
+    /// after a generation, so the prefix of one store must not start with the
+    /// prefix of another using the same extension. The prefix must not contain
+    /// `.` or a path separator.
+    pub fn log_prefix(mut self, prefix: &str) -> Self {
+        self.log_prefix = prefix.to_owned();
+        self
+    }
+
+    /// Returns the extension of generation files
+    pub(crate) fn log_extension_or_default(&self) -> &str {
+        self.log_extension.as_deref().unwrap_or("log")
+    }
+
+    /// Defers the compactions asked for by the compaction strategy until the stale
+    /// bytes make up more than `ratio` of the log
+    ///
+    /// A store that is mostly live can cross a size threshold while a compaction
+    /// would copy far more than it reclaims. Such a compaction waits for enough more
+    /// stale bytes, which raises the effective threshold with the size of the live
+    /// records. Explicit calls to [`KvStore::compact`](crate::KvStore::compact) are
+    /// not deferred. The ratio must be at least 0 and below 1.
+    pub fn min_reclaim_ratio(mut self, ratio: f64) -> Self {
+        self.min_reclaim_ratio = Some(ratio);
+        self
+    }
+
+    /// Returns the stale bytes a compaction must reclaim next to `live` bytes of
+    /// live records to meet the minimum reclaim ratio
+    pub(crate) fn min_reclaim_bytes(&self, live: u64) -> u64 {
+        match self.min_reclaim_ratio {
+            Some(ratio) if ratio > 0.0 => (live as f64 * ratio / (1.0 - ratio)) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Sets whether a compaction syncs the generation it writes, data and metadata,
+    /// before removing the generations it replaces, on by default
+    ///
+    /// Without the sync a crash shortly after a compaction can lose the compacted
+    /// records along with the generations they were copied from. The directories
+    /// holding the generations are synced as well, so the removals of the replaced
+    /// generations last. Each sync of a generation counts towards
+    /// [`EngineStats::flush_count`](crate::EngineStats::flush_count).
+    pub fn sync_compaction(mut self, sync_compaction: bool) -> Self {
+        self.sync_compaction = Some(sync_compaction);
+        self
+    }
+
+    /// Sets a hook the writes and syncs of the generation files go through
+    pub fn file_hook(mut self, file_hook: impl FileHook + 'static) -> Self {
+        self.file_hook = Some(SharedFileHook(Arc::new(file_hook)));
+        self
+    }
+
+    /// Checks the options which cannot take every value of their type
+    pub(crate) fn validate(&self) -> Result<(), KvsError> {
+        if self.compaction_bytes_per_sec == Some(0) {
+            let error = "compaction_bytes_per_sec must not be zero".to_string();
+            return Err(KvsError::Config(error));
+        }
+        if self.layout == (DirLayout::Nested { gens_per_dir: 0 }) {
+            let error = "gens_per_dir must not be zero".to_string();
+            return Err(KvsError::Config(error));
+        }
+        let extension = self.log_extension_or_default();
+        if extension.is_empty() || extension.contains(['/', '\\']) {
+            let error = format!("invalid log extension {:?}", extension);
+            return Err(KvsError::Config(error));
+        }
+        if self.log_prefix.contains(['/', '\\', '.']) {
+            let error = format!("invalid log prefix {:?}", self.log_prefix);
+            return Err(KvsError::Config(error));
+        }
+        if self.compaction_interval == Some(Duration::ZERO) {
+            let error = "compaction_interval must not be zero".to_string();
+            return Err(KvsError::Config(error));
+        }
+        if self.flush_idle_after == Some(Duration::ZERO) {
+            let error = "flush_idle_after must not be zero".to_string();
+            return Err(KvsError::Config(error));
+        }
+        if let Some(ratio) = self.min_reclaim_ratio {
+            if !(0.0..1.0).contains(&ratio) {
+                let error = format!("min_reclaim_ratio must be in [0, 1), got {}", ratio);
+                return Err(KvsError::Config(error));
+            }
+        }
+        if let Some(ratio) = self.reader_fd_warning_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                let error = format!("reader_fd_warning_ratio must be in (0, 1], got {}", ratio);
+                return Err(KvsError::Config(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether compactions sync the generation they write
+    pub(crate) fn sync_compaction_or_default(&self) -> bool {
+        self.sync_compaction.unwrap_or(true)
+    }
+
+    /// Returns the backward move of the wall clock that is reported
+    pub(crate) fn clock_skew_threshold_or_default(&self) -> Duration {
+        self.clock_skew_threshold.unwrap_or(CLOCK_SKEW_THRESHOLD)
+    }
+
+    /// Sets how corrupted records found while opening the store are treated
+    pub fn open_mode(mut self, open_mode: OpenMode) -> Self {
+        self.open_mode = open_mode;
+        self
+    }
+
+    /// Sets a check every key must pass to be written
+    ///
+    /// Writes of keys for which `key_validator` returns `false` fail with
+    /// `KvsError::InvalidKey`, e.g. to reject keys containing a separator that
+    /// would make them ambiguous in prefix scans.
+    pub fn key_validator(mut self, key_validator: fn(&str) -> bool) -> Self {
+        self.key_validator = Some(key_validator);
+        self
+    }
+
+    /// Registers a callback invoked with every event after the operation is committed
+    pub fn on_event(mut self, on_event: impl Fn(&StoreEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(EventCallback(Arc::new(on_event)));
+        self
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::KvsError;
+use crate::kvs::{fnv1a, EngineStats, IndexKey, KvStore, KvsEngine, PairIter, Result, FNV_OFFSET};
+use crate::options::{Collation, KvStoreOptions};
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+const SHARD_COUNT: &str = "SHARD_COUNT";
+
+/// A store partitioning keys across independent `KvStore` shards
+///
+/// Every shard lives in its own subdirectory with its own log and writer,
+/// and a key always belongs to the shard picked by a stable hash of the key.
+/// Each shard has its own lock, so writers of keys in different shards don't wait
+/// for each other.
+pub struct ShardedKvStore {
+    shards: Vec<Mutex<KvStore>>,
+    collation: Collation,
+}
+
+impl KvStore {
+    /// Opens a `ShardedKvStore` with `shards` shards in the given path
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while opening the shards.
+    /// Also returns `KvsError::WrongShardCount` if the directory was created with a different number of shards
+    pub fn open_sharded(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open_with_options(path, shards, KvStoreOptions::default())
+    }
+}
+
+impl ShardedKvStore {
+    /// Opens a `ShardedKvStore` with `shards` shards, each opened with `options`
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while opening the shards.
+    /// Also returns `KvsError::WrongShardCount` if the directory was created with a different number of shards,
+    /// and `KvsError::Config` if `shards` is zero or an option has an invalid value
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        if shards == 0 {
+            return Err(KvsError::Config(
+                "a sharded store needs a shard".to_string(),
+            ));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        // the shard count decides where keys live, so it may never change
+        let count_path = path.join(SHARD_COUNT);
+        if count_path.is_file() {
+            let count = fs::read_to_string(&count_path)?;
+            if count.trim().parse::<usize>().ok() != Some(shards) {
+                return Err(KvsError::WrongShardCount(count));
+            }
+        } else {
+            fs::write(&count_path, shards.to_string())?;
+        }
+
+        let collation = options.collation;
+        let shards = (0..shards)
+            .map(|shard| {
+                KvStore::open_with_options(path.join(format!("shard-{}", shard)), options.clone())
+                    .map(Mutex::new)
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards, collation })
+    }
+
+    /// Returns the live key/value pairs whose keys fall within `range` across all shards,
+    /// in collation order
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the values
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock of a shard is poisoned
+    pub fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut pairs = Vec::new();
+        for shard in &self.shards {
+            pairs.extend(shard.lock().unwrap().range(bounds.clone())?);
+        }
+        Ok(self.collate(pairs))
+    }
+
+    /// Sets the value of a string key to a string in the shard owning the key
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors while writing to the log
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock of the shard is poisoned
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    /// Gets the string value of a string key from the shard owning the key
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the log
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock of the shard is poisoned
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    /// Removes a given key from the shard owning it
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It propagates I/O or serialization errors while writing to the log
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock of the shard is poisoned
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Locks the shard owning `key`
+    fn shard(&self, key: &str) -> MutexGuard<'_, KvStore> {
+        let shard = (fnv1a(FNV_OFFSET, key.as_bytes()) % self.shards.len() as u64) as usize;
+        self.shards[shard].lock().unwrap()
+    }
+
+    /// Sorts pairs gathered from several shards in collation order
+    fn collate(&self, pairs: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut pairs: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| (IndexKey::new(key, self.collation), value))
+            .collect();
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs
+            .into_iter()
+            .map(|(key, value)| (key.into_key(), value))
+            .collect()
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedKvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedKvStore::remove(self, key)
+    }
+
+    fn flush(&mut self) -> Result<u64> {
+        let mut flushed = 0;
+        for shard in &self.shards {
+            flushed += shard.lock().unwrap().flush()?;
+        }
+        Ok(flushed)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for shard in &self.shards {
+            size += shard.lock().unwrap().size_on_disk()?;
+        }
+        Ok(size)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            key_count: 0,
+            disk_bytes: 0,
+            flush_count: 0,
+            compacting: false,
+            open_readers: 0,
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap().stats()?;
+            stats.key_count += shard.key_count;
+            stats.disk_bytes += shard.disk_bytes;
+            stats.flush_count += shard.flush_count;
+            stats.compacting |= shard.compacting;
+            stats.open_readers += shard.open_readers;
+        }
+        Ok(stats)
+    }
+
+    /// Returns the key/value pairs of each shard in turn, in collation order within a shard
+    fn iter(&self) -> Result<PairIter> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().iter())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(shards.into_iter().flatten()))
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in &self.shards {
+            pairs.extend(shard.lock().unwrap().scan(prefix)?);
+        }
+        Ok(self.collate(pairs))
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// A span around a store operation, entered until it is dropped
+///
+/// With the `tracing` feature the span records the size of the key and value, the
+/// generation touched and, once dropped, the duration of the operation in
+/// microseconds. Without it the span is empty and every method does nothing.
+#[cfg(feature = "tracing")]
+pub(crate) struct OpSpan {
+    span: tracing::span::EnteredSpan,
+    start: Instant,
+}
+
+#[cfg(feature = "tracing")]
+impl OpSpan {
+    fn enter(span: tracing::Span) -> Self {
+        OpSpan {
+            span: span.entered(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Starts the span of a get of a key of `key_size` bytes
+    pub(crate) fn get(key_size: usize) -> Self {
+        OpSpan::enter(tracing::info_span!(
+            "get",
+            key_size,
+            value_size = tracing::field::Empty,
+            gen = tracing::field::Empty,
+            duration_micros = tracing::field::Empty,
+        ))
+    }
+
+    /// Starts the span of a set of a key of `key_size` bytes to a value of `value_size` bytes
+    pub(crate) fn set(key_size: usize, value_size: usize) -> Self {
+        OpSpan::enter(tracing::info_span!(
+            "set",
+            key_size,
+            value_size,
+            gen = tracing::field::Empty,
+            duration_micros = tracing::field::Empty,
+        ))
+    }
+
+    /// Starts the span of a removal of a key of `key_size` bytes
+    pub(crate) fn remove(key_size: usize) -> Self {
+        OpSpan::enter(tracing::info_span!(
+            "remove",
+            key_size,
+            gen = tracing::field::Empty,
+            duration_micros = tracing::field::Empty,
+        ))
+    }
+
+    /// Starts the span of a compaction
+    pub(crate) fn compaction() -> Self {
+        OpSpan::enter(tracing::info_span!(
+            "compaction",
+            gen = tracing::field::Empty,
+            duration_micros = tracing::field::Empty,
+        ))
+    }
+
+    /// Records the size of the value read
+    pub(crate) fn record_value_size(&self, value_size: usize) {
+        self.span.record("value_size", value_size);
+    }
+
+    /// Records the generation read, written or compacted into
+    pub(crate) fn record_gen(&self, gen: u64) {
+        self.span.record("gen", gen);
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for OpSpan {
+    fn drop(&mut self) {
+        let micros = u64::try_from(self.start.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.span.record("duration_micros", micros);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct OpSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl OpSpan {
+    #[inline]
+    pub(crate) fn get(_key_size: usize) -> Self {
+        OpSpan
+    }
+
+    #[inline]
+    pub(crate) fn set(_key_size: usize, _value_size: usize) -> Self {
+        OpSpan
+    }
+
+    #[inline]
+    pub(crate) fn remove(_key_size: usize) -> Self {
+        OpSpan
+    }
+
+    #[inline]
+    pub(crate) fn compaction() -> Self {
+        OpSpan
+    }
+
+    #[inline]
+    pub(crate) fn record_value_size(&self, _value_size: usize) {}
+
+    #[inline]
+    pub(crate) fn record_gen(&self, _gen: u64) {}
+}
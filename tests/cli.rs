@@ -1,10 +1,22 @@
+// the original tests pass argument arrays by reference and leave killed servers to
+// the test harness, which newer clippy lints flag
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{
+    configure_stream, get_current_engine, is_compatible_version, log_engine, CodecKind, Commands,
+    GenerationFollower, KvStore, KvsClient, KvsEngine, KvsError, NetworkConnection, SledKvsEngine,
+};
 use predicates::str::{contains, is_empty};
+use rand::Rng;
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -212,6 +224,122 @@ fn cli_wrong_engine() {
     }
 }
 
+// Invalid options are reported as configuration errors before anything starts
+#[test]
+fn cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    for (args, error) in [
+        (&["--engine", "redis"][..], "unknown engine \"redis\""),
+        (&["--rate-limit", "0.5"], "--rate-limit must be at least 1"),
+        (&["--max-keys", "0"], "--max-keys must not be zero"),
+        (
+            &["--addr", "nowhere"],
+            "could not resolve --addr \"nowhere\"",
+        ),
+        (
+            &["--replicate-to", "nowhere"],
+            "could not resolve --replicate-to",
+        ),
+    ] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4037"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(format!("Configuration error: {}", error)));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "invalid-addr"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Configuration error: could not resolve host \"invalid-addr\"",
+        ));
+}
+
+// The server listens on a host name, here with a port picked by the system
+#[test]
+fn cli_server_host_name() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "localhost:0"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = BufReader::new(server.stderr.take().unwrap())
+        .lines()
+        .find_map(|line| {
+            let line = line.unwrap();
+            line.split_once("Ip Address and Port: ")
+                .map(|(_, addr)| addr.trim().to_owned())
+        })
+        .expect("the server logs the address it listens on");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+// The client reaches the server by host name as well as by IP
+#[test]
+fn cli_client_host_name() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4038"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "localhost:4038"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "localhost:4038"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+// Opening a data directory that cannot be written exits with a clear error
+#[test]
+fn cli_unopenable_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    // a directory can't be created below a regular file, even by root
+    fs::write(temp_dir.path().join("file"), "").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--data-dir", "file/data", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("could not open the store in file/data"));
+
+    log_engine(temp_dir.path(), "sled".to_string()).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("used by a different engine than kvs"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +463,2301 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `--flush-policy` decides when the server syncs its writes, with either engine
+#[test]
+fn cli_flush_policy() {
+    for engine in ["kvs", "sled"] {
+        for (policy, flush_count) in [("always", 2), ("manual", 0)] {
+            let temp_dir = TempDir::new().unwrap();
+            let server = spawn_server(
+                &temp_dir,
+                &[
+                    "--engine",
+                    engine,
+                    "--flush-policy",
+                    policy,
+                    "--addr",
+                    "127.0.0.1:4050",
+                ],
+            );
+            for id in 0..2 {
+                Command::cargo_bin("kvs-client")
+                    .unwrap()
+                    .args(["set", &format!("key{}", id), "value"])
+                    .args(["--addr", "127.0.0.1:4050"])
+                    .assert()
+                    .success();
+            }
+
+            let client = KvsClient::connect("127.0.0.1:4050").unwrap();
+            match client.request(Commands::Stats) {
+                Ok(NetworkConnection::Stats { stats }) => {
+                    assert_eq!(stats.flush_count, flush_count, "{} {}", engine, policy);
+                }
+                response => panic!("unexpected response {:?}", response),
+            }
+            stop_server(server);
+        }
+    }
+}
+
+// With the sled engine the server keeps its data in sled, and refuses the commands
+// on the generations of the log of a `KvStore`
+#[test]
+fn cli_sled_engine_served() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &[
+            "--engine",
+            "sled",
+            "--addr",
+            "127.0.0.1:4049",
+            "--allow-admin",
+        ],
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", "127.0.0.1:4049"]);
+        cmd
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["ttl", "key1"]).assert().success().stdout("-1\n");
+    client(&["str-len", "key1"])
+        .assert()
+        .success()
+        .stdout("6\n");
+    client(&["compact"])
+        .assert()
+        .failure()
+        .stderr(contains("not supported by the sled engine"));
+    stop_server(server);
+
+    let mut sled = SledKvsEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        sled.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_export_import_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let export_path = temp_dir.path().join("export.bin");
+
+    let source_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key3", "value3")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&source_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key3"])
+        .current_dir(&source_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", export_path.to_str().unwrap()])
+        .current_dir(&source_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // Import into a fresh store
+    let target_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", export_path.to_str().unwrap()])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key3"])
+        .current_dir(&target_dir)
+        .assert()
+        .code(2)
+        .stdout(contains("Key not found"));
+}
+
+// Exporting to `-` writes to stdout and importing from `-` reads from stdin
+#[test]
+fn cli_export_import_stdio() {
+    let source_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&source_dir)
+            .assert()
+            .success();
+    }
+    let export = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "-"])
+        .current_dir(&source_dir)
+        .output()
+        .unwrap();
+    assert!(export.status.success());
+    assert!(!export.stdout.is_empty());
+
+    let target_dir = TempDir::new().unwrap();
+    let mut import = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "-"])
+        .current_dir(&target_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    import
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&export.stdout)
+        .unwrap();
+    let import = import.wait_with_output().unwrap();
+    assert!(import.status.success());
+    assert!(import.stdout.is_empty());
+    for (key, value) in [("key1", "value1\n"), ("key2", "value2\n")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", key])
+            .current_dir(&target_dir)
+            .assert()
+            .success()
+            .stdout(value);
+    }
+}
+
+// A `get` miss exits with the miss exit code, distinct from errors
+#[test]
+fn cli_get_miss_exit_code() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--miss-exit-code", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1", "--miss-exit-code", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1);
+}
+
+// `--quiet` prints nothing but keeps the exit codes, `--verbose` describes the command on stderr
+#[test]
+fn cli_quiet_and_verbose() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--verbose"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("Setting key1 to a value of 6 bytes"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n")
+        .stderr(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--quiet", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty())
+        .stderr(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "-q"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key2", "--quiet"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--quiet", "--verbose"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs remove-prefix --dry-run` reports the affected keys and leaves the store unchanged
+#[test]
+fn cli_remove_prefix_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    for key in ["user:1", "user:2", "user:3", "usage", "visit:1"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, "value"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["remove-prefix", "user:", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Would remove 3 keys\nuser:1\nuser:2\nuser:3\n");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.keys(..).len(), 5);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["remove-prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.keys(..), ["usage", "visit:1"]);
+}
+
+// `--encoding base64` stores decoded values and prints encoded ones
+#[test]
+fn cli_base64_encoding() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    // "line1\nline2" and "a"
+    kvs(&["set", "key1", "bGluZTEKbGluZTI=", "--encoding", "base64"])
+        .assert()
+        .success();
+    kvs(&["set", "key2", "YQ==", "--encoding", "base64"])
+        .assert()
+        .success();
+    kvs(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("line1\nline2\n");
+    kvs(&["get", "key1", "--encoding", "base64"])
+        .assert()
+        .success()
+        .stdout("bGluZTEKbGluZTI=\n");
+    kvs(&["get", "key2", "--encoding", "base64"])
+        .assert()
+        .success()
+        .stdout("YQ==\n");
+
+    kvs(&["set", "key3", "not base64!", "--encoding", "base64"])
+        .assert()
+        .failure();
+    kvs(&["get", "key3"]).assert().code(2);
+}
+
+// A fake server announcing an incompatible major version. Only the first
+// `answered` connections get a reply to their request.
+fn spawn_fake_server(
+    listener: TcpListener,
+    connections: usize,
+    answered: usize,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().take(connections).enumerate() {
+            let mut stream = stream.unwrap();
+            let buf = NetworkConnection::receive_network_message(&mut stream).unwrap();
+            let message = NetworkConnection::deserialize_message(buf).unwrap();
+            assert!(matches!(message, NetworkConnection::Handshake { .. }));
+            NetworkConnection::send_network_message(
+                NetworkConnection::Handshake {
+                    version: "99.0.0".to_string(),
+                },
+                &mut stream,
+            )
+            .unwrap();
+            if i < answered {
+                NetworkConnection::receive_network_message(&mut stream).unwrap();
+                NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream)
+                    .unwrap();
+            }
+        }
+    })
+}
+
+#[test]
+fn cli_version_mismatch() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = spawn_fake_server(listener, 2, 1);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stderr(contains("99.0.0"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--strict-version", "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("Incompatible peer version: 99.0.0"));
+
+    handle.join().unwrap();
+}
+
+// A server predating the version exchange drops the connection on the handshake,
+// so the client connects again without it
+#[test]
+fn cli_legacy_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        let mut incoming = listener.incoming();
+        let mut stream = incoming.next().unwrap().unwrap();
+        NetworkConnection::receive_network_message(&mut stream).unwrap();
+        drop(stream);
+
+        let mut stream = incoming.next().unwrap().unwrap();
+        let buf = NetworkConnection::receive_network_message(&mut stream).unwrap();
+        let message = NetworkConnection::deserialize_message(buf).unwrap();
+        assert!(matches!(message, NetworkConnection::Request { .. }));
+        NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream).unwrap();
+    });
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stderr(contains("server version unknown"));
+
+    handle.join().unwrap();
+}
+
+// Any reply to the handshake other than a handshake is an error
+#[test]
+fn cli_unexpected_handshake_reply() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        let mut stream = listener.incoming().next().unwrap().unwrap();
+        NetworkConnection::receive_network_message(&mut stream).unwrap();
+        NetworkConnection::send_network_message(NetworkConnection::Ok, &mut stream).unwrap();
+    });
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("unexpected response to Handshake"));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn compatible_versions() {
+    assert!(is_compatible_version("1.2.3", "1.0.0"));
+    assert!(!is_compatible_version("1.2.3", "2.2.3"));
+    assert!(is_compatible_version("0.1.0", "0.1.5"));
+    assert!(!is_compatible_version("0.1.0", "0.2.0"));
+}
+
+fn spawn_server(temp_dir: &TempDir, args: &[&str]) -> std::process::Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn stop_server(mut child: std::process::Child) {
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_replicate_to_follower() {
+    let follower_dir = TempDir::new().unwrap();
+    let primary_dir = TempDir::new().unwrap();
+    let follower = spawn_server(&follower_dir, &["--addr", "127.0.0.1:4006"]);
+    let primary = spawn_server(
+        &primary_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4007",
+            "--replicate-to",
+            "127.0.0.1:4006",
+        ],
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", "127.0.0.1:4007"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "127.0.0.1:4007"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    stop_server(primary);
+    stop_server(follower);
+}
+
+#[test]
+fn cli_replicate_buffers_while_follower_down() {
+    let follower_dir = TempDir::new().unwrap();
+    let primary_dir = TempDir::new().unwrap();
+    let primary = spawn_server(
+        &primary_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4009",
+            "--replicate-to",
+            "127.0.0.1:4008",
+            "--replica-down",
+            "buffer",
+        ],
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4009"])
+        .assert()
+        .success();
+
+    let follower = spawn_server(&follower_dir, &["--addr", "127.0.0.1:4008"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", "127.0.0.1:4009"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+
+    for (key, value) in [("key1", "value1\n"), ("key2", "value2\n")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", key, "--addr", "127.0.0.1:4008"])
+            .assert()
+            .success()
+            .stdout(value);
+    }
+
+    stop_server(primary);
+    stop_server(follower);
+}
+
+// A follower which accepts connections but never answers does not hold up the primary
+#[test]
+fn cli_replicate_to_unresponsive_follower() {
+    let follower = TcpListener::bind("127.0.0.1:4047").unwrap();
+    let primary_dir = TempDir::new().unwrap();
+    let primary = spawn_server(
+        &primary_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4048",
+            "--replicate-to",
+            "127.0.0.1:4047",
+            "--replica-down",
+            "buffer",
+        ],
+    );
+
+    let start = Instant::now();
+    for id in 0..3 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args([
+                "set",
+                &format!("key{}", id),
+                "value",
+                "--addr",
+                "127.0.0.1:4048",
+            ])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", "127.0.0.1:4048"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    stop_server(primary);
+    drop(follower);
+}
+
+#[test]
+fn cli_follow_generations() {
+    // Populate the primary so its first generation is sealed once the server starts
+    let primary_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(primary_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    drop(store);
+    let primary = spawn_server(&primary_dir, &["--addr", "127.0.0.1:4010"]);
+
+    // Bootstrap an empty store from the primary
+    let follower_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(follower_dir.path()).unwrap();
+    let mut follower = GenerationFollower::new("127.0.0.1:4010".parse().unwrap());
+    assert_eq!(follower.poll(&mut store).unwrap(), 1);
+    assert_eq!(follower.next_gen(), 2);
+    assert_eq!(follower.poll(&mut store).unwrap(), 0);
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    // A follower server polls the primary on its own
+    let follower_dir = TempDir::new().unwrap();
+    let follower = spawn_server(
+        &follower_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4011",
+            "--follow",
+            "127.0.0.1:4010",
+            "--follow-interval",
+            "1",
+        ],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", "127.0.0.1:4011"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    stop_server(follower);
+    stop_server(primary);
+}
+
+// Requests over the rate limit of a client IP are refused without affecting other clients
+#[test]
+fn cli_rate_limit_per_ip() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "[::]:4012", "--rate-limit", "5"]);
+
+    let get = |addr: &str| {
+        KvsClient::connect(addr)
+            .and_then(|client| {
+                client.request(Commands::Get {
+                    key: "key1".to_owned(),
+                })
+            })
+            .unwrap()
+    };
+    let limited = (0..20)
+        .filter(|_| {
+            matches!(
+                get("127.0.0.1:4012"),
+                NetworkConnection::Error { error } if error == "rate limited"
+            )
+        })
+        .count();
+    assert!(limited > 0);
+    assert!(limited < 20);
+
+    assert!(matches!(
+        get("[::1]:4012"),
+        NetworkConnection::Response { .. }
+    ));
+
+    stop_server(server);
+}
+
+// With `--auth-token`, the server only serves clients presenting the same token
+#[test]
+fn cli_auth_token() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4013", "--auth-token", "secret"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4013"])
+        .args(["--auth-token", "secret"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4013"])
+        .args(["--auth-token", "secret"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4013"])
+        .args(["--auth-token", "wrong"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid auth token"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4013"])
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    stop_server(server);
+
+    // without a configured token the server stays open, even to clients sending one
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4014"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4014"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4014"])
+        .args(["--auth-token", "anything"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+// Options missing from the command line are read from the environment
+#[test]
+fn cli_server_env_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4035")
+        .env("KVS_AUTH_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4035"])
+        .args(["--auth-token", "secret"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4035"])
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    stop_server(server);
+
+    // flags take precedence, the variables they override are not even parsed
+    let temp_dir = TempDir::new().unwrap();
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4036"])
+        .env("KVS_ADDR", "nowhere")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .success();
+    stop_server(server);
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_MAX_KEYS", "lots")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid KVS_MAX_KEYS \"lots\""));
+}
+
+// Clients in different namespaces cannot see each other's keys
+#[test]
+fn cli_namespace_isolation() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4015", "--require-namespace"],
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", "127.0.0.1:4015"]);
+        cmd
+    };
+
+    client(&["set", "key1", "alpha", "--namespace", "a"])
+        .assert()
+        .success();
+    client(&["set", "key1", "beta", "--namespace", "b"])
+        .assert()
+        .success();
+    client(&["get", "key1", "--namespace", "a"])
+        .assert()
+        .success()
+        .stdout("alpha\n");
+    client(&["get", "key1", "--namespace", "b"])
+        .assert()
+        .success()
+        .stdout("beta\n");
+    client(&["rm", "key1", "--namespace", "a"])
+        .assert()
+        .success();
+    client(&["get", "key1", "--namespace", "a"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key1", "--namespace", "b"])
+        .assert()
+        .success()
+        .stdout("beta\n");
+
+    // a namespace cannot name a key of another one
+    client(&["get", "key1", "--namespace", "a:b"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid namespace"));
+    client(&["get", "b:key1"]).assert().failure();
+    stop_server(server);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.keys(..), ["b:key1"]);
+}
+
+// A tenant token only reaches the namespace it is bound to
+#[test]
+fn cli_tenant_isolation() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4045",
+            "--tenant",
+            "a=token-a",
+            "--tenant",
+            "b=token-b",
+        ],
+    );
+    let client = |token: &str, args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4045", "--auth-token", token]);
+        cmd
+    };
+
+    client("token-a", &["set", "key1", "alpha"])
+        .assert()
+        .success();
+    client("token-b", &["set", "key1", "beta", "--namespace", "b"])
+        .assert()
+        .success();
+    client("token-a", &["get", "key1"])
+        .assert()
+        .success()
+        .stdout("alpha\n");
+    client("token-b", &["get", "key1"])
+        .assert()
+        .success()
+        .stdout("beta\n");
+
+    // neither another namespace nor the keys of the other tenant are reachable
+    client("token-a", &["get", "key1", "--namespace", "b"])
+        .assert()
+        .failure()
+        .stderr(contains("namespace not allowed for this token"));
+    client("token-a", &["get", "b:key1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client("token-a", &["rm", "key1"]).assert().success();
+    client("token-b", &["get", "key1"])
+        .assert()
+        .success()
+        .stdout("beta\n");
+    client("token-c", &["get", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid auth token"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4045"])
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    stop_server(server);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.keys(..), ["b:key1"]);
+}
+
+// Keys written before databases existed stay in database 0, even those looking
+// like the keys of other databases used to be stored
+#[test]
+fn cli_db_keys_written_before_databases() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set(":foo".to_owned(), "colon".to_owned()).unwrap();
+    store.set(":1:key1".to_owned(), "old".to_owned()).unwrap();
+    drop(store);
+
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4046"]);
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", "127.0.0.1:4046"]);
+        cmd
+    };
+    client(&["get", ":foo"])
+        .assert()
+        .success()
+        .stdout("colon\n");
+    client(&["get", ":1:key1"])
+        .assert()
+        .success()
+        .stdout("old\n");
+    client(&["keys"])
+        .assert()
+        .success()
+        .stdout(":1:key1\n:foo\n");
+    client(&["get", "key1", "--db", "1"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["set", "key1", "one", "--db", "1"])
+        .assert()
+        .success();
+    client(&["get", ":1:key1"])
+        .assert()
+        .success()
+        .stdout("old\n");
+    stop_server(server);
+}
+
+// The same key in two databases holds two values, no key of database 0 reaches
+// another database, and flushing a database leaves the others alone, with either engine
+#[test]
+fn cli_db_isolation() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let args = [
+            "--engine",
+            engine,
+            "--addr",
+            "127.0.0.1:4043",
+            "--allow-admin",
+        ];
+        let server = spawn_server(&temp_dir, &args);
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(["--addr", "127.0.0.1:4043"]);
+            cmd
+        };
+
+        client(&["set", "key1", "zero"]).assert().success();
+        client(&["set", ":1:key1", "escaped"]).assert().success();
+        client(&["set", "key1", "one", "--db", "1"])
+            .assert()
+            .success();
+        client(&["set", "key1", "two", "--db", "2"])
+            .assert()
+            .success();
+        client(&["get", "key1"]).assert().success().stdout("zero\n");
+        client(&["get", "key1", "--db", "0"])
+            .assert()
+            .success()
+            .stdout("zero\n");
+        client(&["get", "key1", "--db", "1"])
+            .assert()
+            .success()
+            .stdout("one\n");
+        client(&["get", ":1:key1"])
+            .assert()
+            .success()
+            .stdout("escaped\n");
+        client(&["keys", "--db", "2"])
+            .assert()
+            .success()
+            .stdout("key1\n");
+
+        // database 0 only lists and counts its own keys
+        client(&["keys"])
+            .assert()
+            .success()
+            .stdout(":1:key1\nkey1\n");
+        client(&["scan", ""])
+            .assert()
+            .success()
+            .stdout(":1:key1 escaped\nkey1 zero\n");
+        client(&["stats"])
+            .assert()
+            .success()
+            .stdout(contains("key_count: 2\n"));
+
+        client(&["flush-db", "--db", "1"]).assert().success();
+        client(&["set", "key2", "three", "--db", "3"])
+            .assert()
+            .success();
+        client(&["stats"])
+            .assert()
+            .success()
+            .stdout(contains("key_count: 2\n"));
+        client(&["get", "key1", "--db", "1"])
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+        client(&["get", "key1", "--db", "2"])
+            .assert()
+            .success()
+            .stdout("two\n");
+        client(&["get", ":1:key1"])
+            .assert()
+            .success()
+            .stdout("escaped\n");
+        // database 0 is flushed without the keys of the other databases
+        client(&["flush-db"]).assert().success();
+        client(&["get", "key1"])
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+
+        // clients select the database ahead of their request
+        let mut db_client = KvsClient::connect("127.0.0.1:4043").unwrap();
+        db_client.select_db(2).unwrap();
+        match db_client.request(Commands::Get {
+            key: "key1".to_owned(),
+        }) {
+            Ok(NetworkConnection::Response { value }) => assert_eq!(value, "two"),
+            response => panic!("unexpected response {:?}", response),
+        }
+        stop_server(server);
+
+        let keys = match engine {
+            "kvs" => KvStore::open(temp_dir.path()).unwrap().keys(..),
+            _ => SledKvsEngine::open(temp_dir.path())
+                .unwrap()
+                .scan("")
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect(),
+        };
+        assert_eq!(keys, ["\u{0}2\u{0}key1", "\u{0}3\u{0}key2"]);
+    }
+}
+
+// Flushing a database outside of a namespace also removes the keys of its
+// namespaces, so it is an admin command
+#[test]
+fn cli_flush_db_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4044"]);
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4044", "--db", "3"]);
+        cmd
+    };
+
+    for namespace in ["a", "b"] {
+        client(&["set", "key1", namespace, "--namespace", namespace])
+            .assert()
+            .success();
+    }
+    client(&["flush-db"])
+        .assert()
+        .failure()
+        .stderr(contains("admin commands are disabled"));
+    client(&["flush-db", "--namespace", "a"]).assert().success();
+    client(&["get", "key1", "--namespace", "a"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client(&["get", "key1", "--namespace", "b"])
+        .assert()
+        .success()
+        .stdout("b\n");
+    stop_server(server);
+}
+
+// `compact` is refused unless the server allows admin commands, and then reports
+// the bytes it reclaimed
+#[test]
+fn cli_compact_admin_command() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4016", "--allow-admin"]);
+    for id in 0..100 {
+        let command = Commands::Set {
+            key: "key1".to_owned(),
+            value: format!("value{}", id),
+        };
+        let response = KvsClient::connect("127.0.0.1:4016")
+            .and_then(|client| client.request(command))
+            .unwrap();
+        assert!(matches!(response, NetworkConnection::Ok));
+    }
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", "127.0.0.1:4016"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let reclaimed: u64 = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(reclaimed > 0);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4016"])
+        .assert()
+        .success()
+        .stdout("value99\n");
+    stop_server(server);
+
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4017"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", "127.0.0.1:4017"])
+        .assert()
+        .failure()
+        .stderr(contains("admin commands are disabled"));
+    stop_server(server);
+}
+
+// SIGTERM stops the server cleanly, keeping the writes it acknowledged
+#[test]
+fn cli_server_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4018"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4018"])
+        .assert()
+        .success();
+
+    unsafe {
+        libc::kill(server.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let mut status = None;
+    for _ in 0..50 {
+        status = server.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let Some(status) = status else {
+        stop_server(server);
+        panic!("server did not stop on SIGTERM");
+    };
+    assert!(status.success());
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Connections are configured with TCP_NODELAY, with or without keepalive
+#[test]
+fn cli_tcp_nodelay_and_keepalive() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    configure_stream(&accepted, true, Some(Duration::from_secs(60))).unwrap();
+    assert!(accepted.nodelay().unwrap());
+    configure_stream(&accepted, false, None).unwrap();
+    assert!(!accepted.nodelay().unwrap());
+
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4019", "--tcp-keepalive", "30"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            "127.0.0.1:4019",
+            "--nagle",
+        ])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4019"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+// `--timing` reports the time the server spent on the request
+#[test]
+fn cli_server_timing() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4020"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4020"])
+        .assert()
+        .success()
+        .stderr(is_empty());
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4020", "--timing"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"value1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let micros: u64 = stderr
+        .trim()
+        .strip_prefix("Server time: ")
+        .and_then(|time| time.strip_suffix(" us"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(micros < 10_000_000);
+    stop_server(server);
+}
+
+// The engine marker lives in the directory it describes, including `--data-dir`
+#[test]
+fn cli_engine_marker_in_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let custom_dir = temp_dir.path().join("custom");
+    log_engine(&custom_dir, "sled".to_owned()).unwrap();
+    assert_eq!(
+        get_current_engine(&custom_dir).unwrap().as_deref(),
+        Some("sled")
+    );
+    assert_eq!(get_current_engine(temp_dir.path()).unwrap(), None);
+
+    let data_dir = temp_dir.path().join("data");
+    let server = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4021",
+            "--engine",
+            "kvs",
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+        ],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4021"])
+        .assert()
+        .success();
+    stop_server(server);
+
+    assert_eq!(
+        get_current_engine(&data_dir).unwrap().as_deref(),
+        Some("kvs")
+    );
+    assert_eq!(get_current_engine(temp_dir.path()).unwrap(), None);
+    let mut store = KvStore::open(&data_dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Malformed or hostile bytes never make the protocol layer panic
+#[test]
+fn protocol_adversarial_messages() {
+    let valid = NetworkConnection::Request {
+        command: Commands::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    }
+    .serialize_message()
+    .unwrap();
+
+    let mut messages = vec![vec![], vec![0], vec![0xff], vec![0x0a; 9], vec![0xff; 64]];
+    messages.extend((0..valid.len()).map(|len| valid[..len].to_vec()));
+    for i in 0..valid.len() {
+        for flip in [0x01, 0x80, 0xff] {
+            let mut message = valid.clone();
+            message[i] ^= flip;
+            messages.push(message);
+        }
+    }
+    let mut rng = rand::thread_rng();
+    for _ in 0..2000 {
+        let len = rng.gen_range(0, 64);
+        messages.push((0..len).map(|_| rng.gen()).collect());
+    }
+    for message in messages {
+        let _ = NetworkConnection::deserialize_message(message);
+    }
+
+    // framings which must be rejected without waiting for a huge body
+    let framings: [&[u8]; 4] = [
+        b"\x05\x00\x00",
+        b"\x05\x00\x00\x00\x00\x00\x00\x00X",
+        b"\xff\xff\xff\xff\xff\xff\xff\xff\n",
+        b"\x10\x00\x00\x00\x00\x00\x00\x00\nshort",
+    ];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    for framing in framings {
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut receiver, _) = listener.accept().unwrap();
+        sender.write_all(framing).unwrap();
+        drop(sender);
+        assert!(NetworkConnection::receive_network_message(&mut receiver).is_err());
+    }
+
+    // an empty body is framed correctly but is not a message
+    let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut receiver, _) = listener.accept().unwrap();
+    sender
+        .write_all(b"\x00\x00\x00\x00\x00\x00\x00\x00\n")
+        .unwrap();
+    let body = NetworkConnection::receive_network_message(&mut receiver).unwrap();
+    assert!(body.is_empty());
+    assert!(NetworkConnection::deserialize_message(body).is_err());
+}
+
+// Messages announcing more than `--max-message-size` bytes get the connection closed
+#[test]
+fn cli_max_message_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4022", "--max-message-size", "1024"],
+    );
+
+    let mut stream = TcpStream::connect("127.0.0.1:4022").unwrap();
+    stream.write_all(&(1u64 << 40).to_le_bytes()).unwrap();
+    stream.write_all(b"\n").unwrap();
+    let mut buf = [0u8; 16];
+    assert!(!matches!(stream.read(&mut buf), Ok(len) if len > 0));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", &"v".repeat(2048), "--addr", "127.0.0.1:4022"])
+        .assert()
+        .failure();
+
+    // the server keeps serving messages within the limit
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4022"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+// A client whose server restarted sends a read again on a new connection, and
+// reports a removal whose connection broke once it was received instead
+#[test]
+fn cli_client_reconnects() {
+    let temp_dir = TempDir::new().unwrap();
+    let args = ["--addr", "127.0.0.1:4042"];
+    let mut server = Some(spawn_server(&temp_dir, &args));
+    // the server serves one connection at a time, so each client is held
+    // across its own restart
+    let mut connect_and_restart = |max_retries| {
+        let mut client = KvsClient::connect("127.0.0.1:4042").unwrap();
+        client.set_max_retries(max_retries);
+        stop_server(server.take().unwrap());
+        server = Some(spawn_server(&temp_dir, &args));
+        client
+    };
+    let get = Commands::Get {
+        key: "key1".to_owned(),
+    };
+    let set = Commands::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+
+    assert!(matches!(
+        connect_and_restart(1).request(set).unwrap(),
+        NetworkConnection::Ok
+    ));
+    match connect_and_restart(1).request(get.clone()).unwrap() {
+        NetworkConnection::Response { value } => assert_eq!(value, "value1"),
+        response => panic!("unexpected response {:?}", response),
+    }
+    let no_retry = connect_and_restart(0).request(get);
+    stop_server(server.unwrap());
+    assert!(matches!(no_retry, Err(KvsError::Io(_))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        NetworkConnection::receive_network_message(&mut stream).unwrap();
+        NetworkConnection::send_network_message(
+            NetworkConnection::Handshake {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            &mut stream,
+        )
+        .unwrap();
+        NetworkConnection::receive_network_message(&mut stream).unwrap();
+    });
+    let rm = Commands::Rm {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        KvsClient::connect(addr).unwrap().request(rm),
+        Err(KvsError::UnknownOutcome(_))
+    ));
+    handle.join().unwrap();
+}
+
+// Sets of values larger than `--max-value-size` are refused before reaching the
+// store, without evicting a key to make room
+#[test]
+fn cli_max_value_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            "127.0.0.1:4041",
+            "--max-value-size",
+            "8",
+            "--max-keys",
+            "1",
+        ],
+    );
+    let client = || KvsClient::connect("127.0.0.1:4041").unwrap();
+    let set = |key: &str, value: &str| {
+        client()
+            .request(Commands::Set {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            })
+            .unwrap()
+    };
+    assert!(matches!(set("key0", "12345678"), NetworkConnection::Ok));
+    match set("key1", "123456789") {
+        NetworkConnection::Error { error } => assert!(error.contains("maximum value size")),
+        response => panic!("unexpected response {:?}", response),
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", &"v".repeat(64), "--addr", "127.0.0.1:4041"])
+        .assert()
+        .failure()
+        .stderr(contains("exceeds the maximum value size of 8 bytes"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4041"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key0", "--addr", "127.0.0.1:4041"])
+        .assert()
+        .success()
+        .stdout("12345678\n");
+    stop_server(server);
+}
+
+// A connection sending nothing is closed after `--idle-timeout` instead of
+// blocking other clients
+#[test]
+fn cli_idle_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4023", "--idle-timeout", "1"],
+    );
+
+    let mut idle = TcpStream::connect("127.0.0.1:4023").unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4023"])
+        .assert()
+        .success();
+    stop_server(server);
+}
+
+// Messages get through non-blocking sockets which are not ready right away
+#[test]
+fn protocol_nonblocking_sockets() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut receiver, _) = listener.accept().unwrap();
+    sender.set_nonblocking(true).unwrap();
+    receiver.set_nonblocking(true).unwrap();
+
+    // the receiver starts waiting before anything was sent, and the value is
+    // too large for the socket buffers, so the sender has to wait as well
+    let value = "v".repeat(8 * 1024 * 1024);
+    let handle = thread::spawn(move || {
+        let buf = NetworkConnection::receive_network_message(&mut receiver).unwrap();
+        NetworkConnection::deserialize_message(buf).unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+    NetworkConnection::send_network_message(
+        NetworkConnection::Response {
+            value: value.clone(),
+        },
+        &mut sender,
+    )
+    .unwrap();
+
+    match handle.join().unwrap() {
+        NetworkConnection::Response { value: received } => assert!(received == value),
+        response => panic!("unexpected response: {:?}", response),
+    }
+}
+
+// `kvs info` reports the state of the store in the current directory
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["set", "key1", "value1"]).assert().success();
+    kvs(&["set", "key2", "value2"]).assert().success();
+    kvs(&["set", "key1", "value3"]).assert().success();
+    kvs(&["rm", "key2"]).assert().success();
+
+    let output = kvs(&["info"]).output().unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let field = |name: &str| -> u64 {
+        report
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+
+    // the generations on disk include the one opened by `info` itself
+    let logs: Vec<_> = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    let log_bytes: u64 = logs.iter().map(|log| log.metadata().unwrap().len()).sum();
+    assert_eq!(field("key_count"), 1);
+    assert!(field("uncompacted_bytes") > 0);
+    assert_eq!(field("generation_count"), logs.len() as u64);
+    assert_eq!(field("log_bytes"), log_bytes);
+
+    let output = kvs(&["info", "--output", "json"]).output().unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.starts_with("{\"key_count\":1,\"uncompacted_bytes\":"));
+    assert!(report.trim_end().ends_with('}'));
+}
+
+// `kvs replay` prints the records of a generation file in log order
+#[test]
+fn cli_replay() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    store.remove("key2".to_owned()).unwrap();
+    drop(store);
+
+    let log = fs::read(temp_dir.path().join("1.log")).unwrap();
+    let mut expected = Vec::new();
+    let mut offset = 0;
+    for op in [
+        "set key1 value1",
+        "set key2 value2",
+        "set key1 value3",
+        "rm key2",
+    ] {
+        let len = 4 + u32::from_le_bytes(log[offset..offset + 4].try_into().unwrap()) as usize;
+        expected.push(format!("{} {} {}", offset, len, op));
+        offset += len;
+    }
+    assert_eq!(offset, log.len());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["replay", "1.log"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected.join("\n")));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["replay", "1.log", "--key", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n{}\n", expected[1], expected[3]));
+
+    // replaying leaves the store alone
+    assert!(!temp_dir.path().join("2.log").exists());
+}
+
+// `bench` reports a nonzero throughput for both phases and removes its store
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let scratch = TempDir::new().unwrap();
+    for engine in ["kvs", "sled"] {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args([
+                "bench",
+                "--ops",
+                "50",
+                "--value-size",
+                "16",
+                "--engine",
+                engine,
+            ])
+            .current_dir(&temp_dir)
+            .env("TMPDIR", scratch.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let phases: Vec<_> = stdout.lines().collect();
+        assert_eq!(phases.len(), 2);
+        for (phase, operation) in phases.iter().zip(["set", "get"]) {
+            assert!(phase.starts_with(&format!("{}: 50 ops, ", operation)));
+            let ops_per_sec: u64 = phase
+                .split(", ")
+                .nth(1)
+                .and_then(|field| field.strip_suffix(" ops/sec"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(ops_per_sec > 0);
+        }
+        assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// `engine show` prints the marker and `engine set` replaces it, even a corrupted one
+#[test]
+fn cli_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    kvs(&["engine", "show"])
+        .assert()
+        .success()
+        .stdout("No engine recorded\n");
+
+    fs::write(temp_dir.path().join("ENGINE_MODE.txt"), "sl\0ed").unwrap();
+    kvs(&["engine", "show"])
+        .assert()
+        .success()
+        .stdout("sl\0ed\n");
+    kvs(&["engine", "set", "sled"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    kvs(&["engine", "show"]).assert().success().stdout("sled\n");
+    assert_eq!(
+        get_current_engine(temp_dir.path()).unwrap().as_deref(),
+        Some("sled")
+    );
+    assert!(!temp_dir.path().join("ENGINE_MODE.tmp").exists());
+
+    kvs(&["engine", "set", "rocksdb"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid value"));
+    kvs(&["engine", "set", "kvs"]).assert().success();
+    kvs(&["engine", "show"]).assert().success().stdout("kvs\n");
+}
+
+// `migrate` copies a store to sled and back without losing a pair
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let (kvs_dir, sled_dir, back_dir) = (
+        temp_dir.path().join("kvs"),
+        temp_dir.path().join("sled"),
+        temp_dir.path().join("back"),
+    );
+    let mut store = KvStore::open(&kvs_dir).unwrap();
+    for id in 0..100 {
+        store
+            .set(format!("key{}", id), format!("value{}", id))
+            .unwrap();
+    }
+    store.remove("key50".to_owned()).unwrap();
+    let pairs = store.scan("").unwrap();
+    drop(store);
+    log_engine(&kvs_dir, "kvs".to_owned()).unwrap();
+
+    let migrate = |from: &str, to: &str, src: &Path, dst: &Path| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command
+            .args(["migrate", "--from", from, "--to", to])
+            .arg("--src")
+            .arg(src)
+            .arg("--dst")
+            .arg(dst);
+        command
+    };
+    migrate("kvs", "sled", &kvs_dir, &sled_dir)
+        .assert()
+        .success()
+        .stdout("Migrated 99 keys\n");
+    assert_eq!(get_current_engine(&sled_dir).unwrap().unwrap(), "sled");
+    let mut sled = SledKvsEngine::open(&sled_dir).unwrap();
+    assert_eq!(sled.scan("").unwrap(), pairs);
+    drop(sled);
+
+    // the recorded engine of the source must match
+    migrate("sled", "kvs", &kvs_dir, &back_dir)
+        .assert()
+        .failure()
+        .stderr(contains("used by a different engine than sled"));
+    migrate("sled", "kvs", &sled_dir, &back_dir)
+        .assert()
+        .success();
+    assert_eq!(get_current_engine(&back_dir).unwrap().unwrap(), "kvs");
+    let mut store = KvStore::open(&back_dir).unwrap();
+    assert_eq!(store.scan("").unwrap(), pairs);
+}
+
+// `verify` checks sealed generations by their footer, and reports a damaged one
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.rotate().unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["verify"])
+        .assert()
+        .success()
+        .stdout("1: ok (footer)\n2: ok (replay)\n");
+    kvs(&["verify", "--deep"])
+        .assert()
+        .success()
+        .stdout("1: ok (replay)\n2: ok (replay)\n");
+    // verifying leaves the store alone
+    assert!(!temp_dir.path().join("3.log").exists());
+
+    let sealed = temp_dir.path().join("1.log");
+    let mut log = fs::read(&sealed).unwrap();
+    let at = log
+        .windows(6)
+        .position(|window| window == b"value1")
+        .unwrap();
+    log[at + 5] = b'X';
+    fs::write(&sealed, log).unwrap();
+    kvs(&["verify"])
+        .assert()
+        .failure()
+        .stderr(contains("Generation 1 is corrupted"));
+}
+
+// `snapshot-get` reads all keys without a write interleaving
+#[test]
+fn cli_snapshot_get_consistency() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4024"]);
+    let request = |command| {
+        KvsClient::connect("127.0.0.1:4024")
+            .unwrap()
+            .request(command)
+            .unwrap()
+    };
+
+    // the writer always moves `first` ahead before `second` catches up
+    let writer = thread::spawn(move || {
+        for i in 0..200 {
+            for key in ["first", "second"] {
+                let value = i.to_string();
+                request(Commands::Set {
+                    key: key.to_owned(),
+                    value,
+                });
+            }
+        }
+    });
+    let mut snapshots = 0;
+    while !writer.is_finished() || snapshots == 0 {
+        let keys = vec!["second".to_owned(), "first".to_owned()];
+        let values = match request(Commands::SnapshotGet { keys }) {
+            NetworkConnection::Values { values } => values,
+            response => panic!("unexpected response: {:?}", response),
+        };
+        let value = |value: &Option<String>| value.as_ref().map_or(-1, |v| v.parse().unwrap());
+        let (second, first): (i64, i64) = (value(&values[0]), value(&values[1]));
+        assert!(
+            first == second || first == second + 1,
+            "{} {}",
+            first,
+            second
+        );
+        snapshots += 1;
+    }
+    writer.join().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "snapshot-get",
+            "first",
+            "missing",
+            "--addr",
+            "127.0.0.1:4024",
+        ])
+        .assert()
+        .success()
+        .stdout("199\nKey not found\n");
+    stop_server(server);
+}
+
+// A repeated `--addr` makes the server accept clients on every address
+#[test]
+fn cli_server_multiple_addrs() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(
+        &temp_dir,
+        &["--addr", "127.0.0.1:4025", "--addr", "127.0.0.1:4026"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4025"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4026"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    stop_server(server);
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_server_daemon() {
+    let temp_dir = TempDir::new().unwrap();
+    // the foreground process exits once the daemon is forked
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--daemon",
+            "--pidfile",
+            "server.pid",
+            "--log-file",
+            "server.out",
+            "--addr",
+            "127.0.0.1:4028",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let pid: libc::pid_t = fs::read_to_string(temp_dir.path().join("server.pid"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4028"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4028"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // SAFETY: the pid belongs to the daemon started above
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    for _ in 0..50 {
+        if !temp_dir.path().join("server.pid").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!temp_dir.path().join("server.pid").exists());
+    let log = fs::read_to_string(temp_dir.path().join("server.out")).unwrap();
+    assert!(log.contains("Server Startup"));
+
+    // a daemon failing to start makes the foreground process fail with its error
+    fs::write(temp_dir.path().join("not-a-dir"), "").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--daemon",
+            "--pidfile",
+            "server.pid",
+            "--addr",
+            "127.0.0.1:4028",
+        ])
+        .args(["--data-dir", "not-a-dir"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("could not open the store in not-a-dir"));
+    let _taken = TcpListener::bind("127.0.0.1:4028").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--daemon",
+            "--pidfile",
+            "server.pid",
+            "--addr",
+            "127.0.0.1:4028",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Address already in use"));
+    assert!(!temp_dir.path().join("server.pid").exists());
+}
+
+// Sets without reply are applied in order, and a ping confirms they all landed
+#[test]
+fn cli_set_no_reply_stream() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4029"]);
+
+    let mut client = KvsClient::connect("127.0.0.1:4029").unwrap();
+    for i in 0..2000 {
+        client
+            .set_no_reply(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    assert!(matches!(
+        client.request(Commands::Ping).unwrap(),
+        NetworkConnection::Ok
+    ));
+
+    for i in [0, 1000, 1999] {
+        let client = KvsClient::connect("127.0.0.1:4029").unwrap();
+        match client
+            .request(Commands::Get {
+                key: format!("key{}", i),
+            })
+            .unwrap()
+        {
+            NetworkConnection::Response { value } => assert_eq!(value, format!("value{}", i)),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    let client = KvsClient::connect("127.0.0.1:4029").unwrap();
+    match client.request(Commands::Stats).unwrap() {
+        NetworkConnection::Stats { stats } => assert_eq!(stats.key_count, 2000),
+        response => panic!("unexpected response {:?}", response),
+    }
+    stop_server(server);
+}
+
+// A large scan streams every pair through in order, within the namespace too
+#[test]
+fn cli_scan_stream() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    let pairs = (0..5000).map(|i| (format!("key{:05}", i), format!("value{}", i)));
+    store.bulk_load(pairs.clone()).unwrap();
+    store.set("other".to_owned(), "value".to_owned()).unwrap();
+    store
+        .set("ns:key1".to_owned(), "namespaced".to_owned())
+        .unwrap();
+    drop(store);
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4032"]);
+
+    let client = KvsClient::connect("127.0.0.1:4032").unwrap();
+    let scanned: Vec<_> = client
+        .scan("key".to_owned())
+        .unwrap()
+        .collect::<kvs::Result<_>>()
+        .unwrap();
+    assert_eq!(scanned, pairs.collect::<Vec<_>>());
+
+    let mut client = KvsClient::connect("127.0.0.1:4032").unwrap();
+    client.select_namespace("ns").unwrap();
+    let scanned: Vec<_> = client
+        .scan(String::new())
+        .unwrap()
+        .collect::<kvs::Result<_>>()
+        .unwrap();
+    assert_eq!(scanned, [("key1".to_owned(), "namespaced".to_owned())]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key0000", "--addr", "127.0.0.1:4032"])
+        .assert()
+        .success()
+        .stdout(
+            (0..10)
+                .map(|i| format!("key{:05} value{}\n", i, i))
+                .collect::<String>(),
+        );
+    stop_server(server);
+}
+
+// The server never returns an expired value and reports the TTL of keys like Redis
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    let ttl = Duration::from_secs(2);
+    store
+        .set_with_ttl("short".to_owned(), "value".to_owned(), ttl)
+        .unwrap();
+    let expires = Instant::now() + ttl;
+    store.set("forever".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4033"]);
+
+    let request = |command: Commands| {
+        KvsClient::connect("127.0.0.1:4033")
+            .unwrap()
+            .request(command)
+            .unwrap()
+    };
+    let ttl = |key: &str| match request(Commands::Ttl {
+        key: key.to_owned(),
+    }) {
+        NetworkConnection::Ttl { seconds } => seconds,
+        response => panic!("unexpected response {:?}", response),
+    };
+    let get = |key: &str| match request(Commands::Get {
+        key: key.to_owned(),
+    }) {
+        NetworkConnection::Response { value } => value,
+        response => panic!("unexpected response {:?}", response),
+    };
+
+    assert!((1..=2).contains(&ttl("short")));
+    assert_eq!(get("short"), "value");
+    assert_eq!(ttl("forever"), -1);
+    assert_eq!(ttl("missing"), -2);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "forever", "--addr", "127.0.0.1:4033"])
+        .assert()
+        .success()
+        .stdout("-1\n");
+
+    thread::sleep(expires.saturating_duration_since(Instant::now()) + Duration::from_millis(100));
+    assert_eq!(ttl("short"), -2);
+    assert_eq!(get("short"), "Key not found");
+    assert_eq!(get("forever"), "value");
+    stop_server(server);
+}
+
+// A conditional get only transfers values written after the given time
+#[test]
+fn cli_get_if_modified_since() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4039"]);
+    let client = || KvsClient::connect("127.0.0.1:4039").unwrap();
+    let response = client()
+        .request(Commands::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })
+        .unwrap();
+    assert!(matches!(response, NetworkConnection::Ok));
+    let modified_at = match client()
+        .request(Commands::ModifiedAt {
+            key: "key1".to_owned(),
+        })
+        .unwrap()
+    {
+        NetworkConnection::ModifiedAt { millis } => millis.unwrap(),
+        response => panic!("unexpected response {:?}", response),
+    };
+
+    let get = |key: &str, since| {
+        client()
+            .get_if_modified_since(key.to_owned(), since)
+            .unwrap()
+    };
+    assert_eq!(
+        get("key1", modified_at - 1),
+        Some(Some("value1".to_owned()))
+    );
+    assert_eq!(get("key1", modified_at), None);
+    assert_eq!(get("missing", 0), Some(None));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-if-modified-since", "key1", &modified_at.to_string()])
+        .args(["--addr", "127.0.0.1:4039"])
+        .assert()
+        .success()
+        .stdout("Not modified\n");
+    stop_server(server);
+}
+
+// `str-len` prints the length of the value in bytes and treats a missing key as a miss
+#[test]
+fn cli_str_len() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command.args(args).current_dir(&temp_dir);
+        command
+    };
+    kvs(&["set", "key1", "héllo"]).assert().success();
+    kvs(&["str-len", "key1"]).assert().success().stdout("6\n");
+    kvs(&["str-len", "key2"])
+        .assert()
+        .code(2)
+        .stdout(contains("Key not found"));
+
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4040"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["str-len", "key1", "--addr", "127.0.0.1:4040"])
+        .assert()
+        .success()
+        .stdout("6\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["str-len", "key2", "--addr", "127.0.0.1:4040"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    stop_server(server);
+}
+
+// Paging through the keys of the server visits every key exactly once, in order
+#[test]
+fn cli_keys_pagination() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    let keys: Vec<_> = (0..1000).map(|i| format!("key{:04}", i)).collect();
+    store
+        .bulk_load(keys.iter().map(|key| (key.clone(), "value".to_owned())))
+        .unwrap();
+    store
+        .set("ns:key1".to_owned(), "namespaced".to_owned())
+        .unwrap();
+    drop(store);
+    let server = spawn_server(&temp_dir, &["--addr", "127.0.0.1:4034"]);
+
+    let client = KvsClient::connect("127.0.0.1:4034").unwrap();
+    let listed: Vec<_> = client.keys(7).unwrap().collect::<kvs::Result<_>>().unwrap();
+    let mut expected = keys.clone();
+    expected.push("ns:key1".to_owned());
+    assert_eq!(listed, expected);
+
+    let mut client = KvsClient::connect("127.0.0.1:4034").unwrap();
+    client.select_namespace("ns").unwrap();
+    let listed: Vec<_> = client.keys(1).unwrap().collect::<kvs::Result<_>>().unwrap();
+    assert_eq!(listed, ["key1"]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["keys", "--cursor", "key0996", "--limit", "2"])
+        .args(["--addr", "127.0.0.1:4034"])
+        .assert()
+        .success()
+        .stdout("key0997\nkey0998\n")
+        .stderr(contains("Next cursor: key0998"));
+    stop_server(server);
+}
+
+// Past --max-keys the server evicts the least recently used, or the oldest, key
+#[test]
+fn cli_server_max_keys_eviction() {
+    let request =
+        |addr: &str, command: Commands| KvsClient::connect(addr).unwrap().request(command).unwrap();
+    let get = |addr: &str, key: &str| match request(
+        addr,
+        Commands::Get {
+            key: key.to_owned(),
+        },
+    ) {
+        NetworkConnection::Response { value } => value,
+        response => panic!("unexpected response {:?}", response),
+    };
+
+    for (eviction, addr, evicted) in [
+        ("lru", "127.0.0.1:4030", "key2"),
+        ("fifo", "127.0.0.1:4031", "key1"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = spawn_server(
+            &temp_dir,
+            &["--addr", addr, "--max-keys", "3", "--eviction", eviction],
+        );
+        for key in ["key1", "key2", "key3"] {
+            request(
+                addr,
+                Commands::Set {
+                    key: key.to_owned(),
+                    value: format!("{}-value", key),
+                },
+            );
+        }
+        // reading key1 only protects it from LRU eviction
+        assert_eq!(get(addr, "key1"), "key1-value");
+        request(
+            addr,
+            Commands::Set {
+                key: "key4".to_owned(),
+                value: "key4-value".to_owned(),
+            },
+        );
+
+        for key in ["key1", "key2", "key3", "key4"] {
+            if key == evicted {
+                assert_eq!(get(addr, key), "Key not found");
+            } else {
+                assert_eq!(get(addr, key), format!("{}-value", key));
+            }
+        }
+        match request(addr, Commands::Stats) {
+            NetworkConnection::Stats { stats } => assert_eq!(stats.key_count, 3),
+            response => panic!("unexpected response {:?}", response),
+        }
+        stop_server(server);
+    }
+}
+
+// Messages roundtrip through every codec
+#[test]
+fn network_message_codecs_roundtrip() {
+    let message = NetworkConnection::Generation {
+        gen: 3,
+        data: vec![0, 1, 2, 255],
+    };
+    let request = NetworkConnection::Request {
+        command: Commands::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        },
+    };
+    for codec in [CodecKind::Flexbuffers, CodecKind::Bincode, CodecKind::Json] {
+        for message in [&message, &request] {
+            let buf = message.serialize_message_with(&codec).unwrap();
+            let decoded = NetworkConnection::deserialize_message_with(buf, &codec).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+    }
+}
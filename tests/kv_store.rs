@@ -1,4 +1,23 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    set_buffer_pool_size, Clock, CodecKind, Collation, Commands, CompactionStrategy, DirLayout,
+    EngineStats, ErrorKind, FileHook, FlushPolicy, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    NetworkConnection, OpenMode, Result, ShardedKvStore, SledKvsEngine, StoreEvent, TtlClock,
+    ValueType, Verification, DEFAULT_BUFFER_POOL_SIZE,
+};
+use rand::Rng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +143,2751 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compaction removes the first generation file created on open
+fn compacted(temp_dir: &TempDir) -> bool {
+    !temp_dir.path().join("1.log").exists()
+}
+
+// The metadata of a value follows its record into the generation written by compaction
+#[test]
+fn get_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_meta("key1"), None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let before = store.get_meta("key2").expect("key not found");
+    assert!(before.pos > 0);
+    assert!(before.len > 0);
+
+    store.compact()?;
+    let after = store.get_meta("key2").expect("key not found");
+    assert_ne!(after.gen, before.gen);
+    assert_eq!(after.len, before.len);
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get_meta("key2"), None);
+    Ok(())
+}
+
+#[test]
+fn compaction_size_threshold_strategy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::new().compaction_strategy(CompactionStrategy::SizeThreshold(u64::MAX));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(!compacted(&temp_dir));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_strategy(CompactionStrategy::SizeThreshold(100));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(compacted(&temp_dir));
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_ratio_strategy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_strategy(CompactionStrategy::Ratio(0.5));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    // stale fraction is 1/3
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(!compacted(&temp_dir));
+    // stale fraction is 1/2
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!compacted(&temp_dir));
+    // stale fraction is 3/5
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(compacted(&temp_dir));
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A compaction reclaiming less than the minimum ratio is deferred past the threshold
+#[test]
+fn min_reclaim_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_strategy(CompactionStrategy::SizeThreshold(100))
+        .min_reclaim_ratio(0.5);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), "value".to_owned())?;
+    }
+    for iter in 0..20 {
+        store.set("key0".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.uncompacted_bytes() > 100);
+    assert!(!compacted(&temp_dir));
+
+    // once the stale bytes outweigh the live ones the compaction goes ahead
+    for iter in 0..200 {
+        store.set("key0".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(compacted(&temp_dir));
+    assert_eq!(store.get("key0".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A batch of durable writes should survive a crash
+#[test]
+fn set_many_sync_on_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_on_write(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store
+        .set_many((0..1000).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))))?;
+    assert_eq!(store.stats()?.flush_count, 1);
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.flush_count, 2);
+
+    // Simulate a crash by skipping the destructor
+    std::mem::forget(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let expected = if key_id == 1 {
+            "value".to_owned()
+        } else {
+            format!("value{}", key_id)
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+    }
+
+    Ok(())
+}
+
+// Durable writes from many threads share their syncs, and every acknowledged write
+// survives a crash
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_on_write(true);
+    let store = KvStore::open_shared(temp_dir.path(), options)?;
+
+    let (threads, writes) = (8, 100);
+    let acknowledged = Arc::new(Mutex::new(Vec::new()));
+    let writers: Vec<_> = (0..threads)
+        .map(|thread_id| {
+            let store = Arc::clone(&store);
+            let acknowledged = Arc::clone(&acknowledged);
+            thread::spawn(move || -> Result<()> {
+                for write in 0..writes {
+                    let key = format!("key{}-{}", thread_id, write);
+                    KvStore::set_shared(&store, key.clone(), format!("value{}", write))?;
+                    acknowledged
+                        .lock()
+                        .unwrap()
+                        .push((key, format!("value{}", write)));
+                }
+                KvStore::remove_shared(&store, format!("key{}-0", thread_id))
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let flush_count = store.lock().unwrap().stats()?.flush_count;
+    assert!(
+        flush_count * 2 < threads * (writes + 1),
+        "{} syncs for {} writes",
+        flush_count,
+        threads * (writes + 1)
+    );
+
+    // Simulate a crash by skipping the destructor
+    std::mem::forget(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for (key, value) in acknowledged.lock().unwrap().drain(..) {
+        let expected = (!key.ends_with("-0")).then_some(value);
+        assert_eq!(store.get(key)?, expected);
+    }
+    Ok(())
+}
+
+// A batch removal reports which keys it removed and commits them together
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_on_write(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_many((0..4).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))))?;
+
+    let before = store.stats()?.flush_count;
+    let keys = ["key0", "missing", "key2", "key0", "key3"].map(str::to_owned);
+    assert_eq!(store.remove_many(&keys)?, [true, false, true, false, true]);
+    assert_eq!(store.stats()?.flush_count - before, 1);
+    assert_eq!(store.keys(..), ["key1"]);
+
+    // the stale bytes match those found by replaying the log
+    let uncompacted = store.uncompacted_bytes();
+    std::mem::forget(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.uncompacted_bytes(), uncompacted);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // the batch is followed by a compaction if the strategy asks for one
+    let options = KvStoreOptions::new().compaction_strategy(CompactionStrategy::SizeThreshold(0));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.remove_many(&["key1".to_owned()])?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert!(store.keys(..).is_empty());
+
+    Ok(())
+}
+
+// Values stored under large keys should be read back unchanged
+#[test]
+fn get_with_large_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let key = |key_id: usize| format!("{}{}", "k".repeat(64 * 1024), key_id);
+    for key_id in 0..10 {
+        store.set(key(key_id), format!("value{}", key_id))?;
+    }
+    store.remove(key(3))?;
+
+    for key_id in 0..10 {
+        let expected = (key_id != 3).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(key(key_id))?, expected);
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        let expected = (key_id != 3).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(key(key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+// Reading a key whose index entry points at an `Rm` record reports where it looked
+#[test]
+fn get_unexpected_command_type() -> Result<()> {
+    // Produce the bytes of an `Rm` record in a separate store
+    let rm_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(rm_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let log = fs::read(rm_dir.path().join("1.log"))?;
+    let set_len = 4 + u32::from_le_bytes(log[..4].try_into().unwrap()) as usize;
+    let rm_record = &log[set_len..];
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fs::write(temp_dir.path().join("1.log"), rm_record)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvsError::UnexpectedCommandType { key, gen, pos }) => {
+            assert_eq!(key, "key1");
+            assert_eq!(gen, 1);
+            assert_eq!(pos, 0);
+        }
+        other => panic!("expected UnexpectedCommandType, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// An export holds `Set` records only, an `Rm` record fails the import where it is found
+#[test]
+fn import_unexpected_command_type() -> Result<()> {
+    let rm_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(rm_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let log = fs::read(rm_dir.path().join("1.log"))?;
+    let set_len = 4 + u32::from_le_bytes(log[..4].try_into().unwrap()) as u64;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    match store.import(log.as_slice()) {
+        Err(KvsError::UnexpectedCommandType { key, gen, pos }) => {
+            assert_eq!(key, "key1");
+            assert_eq!(gen, 0);
+            assert_eq!(pos, set_len);
+        }
+        other => panic!("expected UnexpectedCommandType, got {:?}", other),
+    }
+    // the records before it were imported
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A clean close persists the index so reopening skips replaying the log
+#[test]
+fn open_from_index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_snapshot(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("INDEX_SNAPSHOT").exists());
+
+    // Corrupt the length of the stale first record so a full replay fails
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log[..4].copy_from_slice(&1000u32.to_le_bytes());
+    fs::write(&log_path, log)?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    // Without the snapshot the whole log is replayed
+    fs::remove_file(temp_dir.path().join("INDEX_SNAPSHOT"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Closing the store persists the index, which the next open replays from, while
+// a crash leaves no snapshot behind
+#[test]
+fn close_writes_index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_snapshot(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    // a crash writes no snapshot
+    std::mem::forget(store);
+    assert!(!temp_dir.path().join("INDEX_SNAPSHOT").exists());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.close()?;
+    assert!(temp_dir.path().join("INDEX_SNAPSHOT").exists());
+
+    // Corrupt the length of the stale first record so only the snapshot can open the store
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log[..4].copy_from_slice(&1000u32.to_le_bytes());
+    fs::write(&log_path, log)?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Keys should spread over the shards and still behave like a single store
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: ShardedKvStore = KvStore::open_sharded(temp_dir.path(), 4)?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for shard in 0..4 {
+        let log = temp_dir
+            .path()
+            .join(format!("shard-{}", shard))
+            .join("1.log");
+        assert!(fs::metadata(log)?.len() > 0);
+    }
+
+    for key_id in (0..100).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_sharded(temp_dir.path(), 4)?;
+    for key_id in 0..100 {
+        let expected = (key_id % 2 == 1).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open_sharded(temp_dir.path(), 2),
+        Err(KvsError::WrongShardCount(_))
+    ));
+
+    Ok(())
+}
+
+// Writers of keys in different shards should run at the same time
+#[test]
+fn sharded_store_concurrent_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open_sharded(temp_dir.path(), 4)?);
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    assert_eq!(store.stats()?.key_count, 400);
+    for thread_id in 0..4 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Scanning and ranging over the shards should agree on the collation order
+#[test]
+fn sharded_store_collation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().collation(Collation::CaseInsensitive);
+    let mut store = ShardedKvStore::open_with_options(temp_dir.path(), 3, options)?;
+
+    for key in ["kB", "KA", "kc", "Kd", "ka"] {
+        store.set(key.to_owned(), key.to_lowercase())?;
+    }
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys(store.scan("k")?), ["ka", "kB", "kc"]);
+    assert_eq!(keys(store.range(..)?), ["KA", "ka", "kB", "kc", "Kd"]);
+    Ok(())
+}
+
+// Files left behind by an interrupted compaction or snapshot are cleaned up on open,
+// other files are left in place
+#[test]
+fn remove_orphaned_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let orphans = ["temp.log", "temp_log.log", "INDEX_SNAPSHOT.tmp"];
+    for orphan in orphans {
+        fs::write(temp_dir.path().join(orphan), b"garbage")?;
+    }
+
+    let unknown = temp_dir.path().join("build.log");
+    fs::write(&unknown, b"not ours")?;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = Arc::clone(&events);
+    let options =
+        KvStoreOptions::new().on_event(move |event| observed.lock().unwrap().push(event.clone()));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for orphan in orphans {
+        assert!(!temp_dir.path().join(orphan).exists());
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a file the store did not write is reported instead of removed
+    assert_eq!(fs::read(&unknown)?, b"not ours");
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![StoreEvent::UnknownFile { path: unknown }]
+    );
+
+    Ok(())
+}
+
+// A compaction killed halfway leaves only its temporary file, which the next open
+// removes, instead of a partial generation
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hook = DiskHook::default();
+    let options = KvStoreOptions::new().file_hook(hook.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    // enough to seal the current generation and copy some of the records
+    hook.0.lock().unwrap().remaining = Some(1000);
+    assert!(store.compact().is_err());
+    // kill the process without closing the store
+    std::mem::forget(store);
+    assert!(temp_dir.path().join("temp.log").exists());
+
+    let strict = KvStoreOptions::new().open_mode(OpenMode::Strict);
+    let mut store = KvStore::open_with_options(temp_dir.path(), strict.clone())?;
+    assert!(!temp_dir.path().join("temp.log").exists());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), strict)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Generation files named with a custom prefix and extension are found on open,
+// also next to the files of another store
+#[test]
+fn log_naming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .log_prefix("users-")
+        .log_extension(".kvlog")
+        .index_snapshot(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut other = KvStore::open(temp_dir.path())?;
+    for gen in 0..3 {
+        store.set(format!("key{}", gen), format!("value{}", gen))?;
+        store.rotate()?;
+        other.set(format!("key{}", gen), "other".to_owned())?;
+    }
+    drop(store);
+    drop(other);
+    assert!(temp_dir.path().join("users-1.kvlog").is_file());
+    assert!(temp_dir.path().join("users-INDEX_SNAPSHOT").is_file());
+
+    let mut other = KvStore::open(temp_dir.path())?;
+    assert_eq!(other.generation_count(), 2);
+    assert_eq!(other.get("key2".to_owned())?, Some("other".to_owned()));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.generation_count(), 4);
+    for gen in 0..3 {
+        assert_eq!(
+            store.get(format!("key{}", gen))?,
+            Some(format!("value{}", gen))
+        );
+    }
+
+    let options = KvStoreOptions::new().log_extension("");
+    let result = KvStore::open_with_options(temp_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+    Ok(())
+}
+
+// A parallel multi-get over many generations returns what a get of each key does
+#[test]
+fn multi_get_parallel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for threads in [1, 4] {
+        let options = KvStoreOptions::new().multi_get_threads(threads);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for gen in 0..8 {
+            for id in 0..200 {
+                if (id + gen) % 3 == 0 {
+                    store.set(format!("key{}", id), format!("value{}-{}", id, gen))?;
+                }
+            }
+            let removed = format!("key{}", gen);
+            if store.get(removed.clone())?.is_some() {
+                store.remove(removed)?;
+            }
+            store.rotate()?;
+        }
+
+        let mut keys: Vec<_> = (0..250).map(|id| format!("key{}", id)).collect();
+        keys.reverse();
+        keys.push("key7".to_owned());
+        let values = store.multi_get_parallel(&keys)?;
+        let expected = keys
+            .iter()
+            .map(|key| store.get(key.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, expected);
+        assert!(values.iter().filter(|value| value.is_some()).count() > 150);
+        assert_eq!(values.last(), Some(&None));
+
+        let result = store.multi_get_parallel(&["".to_owned()]);
+        assert!(matches!(result, Err(KvsError::InvalidKey(_))));
+    }
+    Ok(())
+}
+
+// Stores with many generations load identically with the flat and nested layouts
+#[test]
+fn nested_dir_layout() -> Result<()> {
+    let flat_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nested_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nested = KvStoreOptions::new().layout(DirLayout::Nested { gens_per_dir: 10 });
+
+    // Every open starts a new generation
+    for (dir, options) in [
+        (&flat_dir, KvStoreOptions::new()),
+        (&nested_dir, nested.clone()),
+    ] {
+        for iter in 0..30 {
+            let mut store = KvStore::open_with_options(dir.path(), options.clone())?;
+            store.set(format!("key{}", iter), format!("value{}", iter))?;
+            store.set("key".to_owned(), format!("value{}", iter))?;
+        }
+    }
+    assert!(nested_dir.path().join("002").join("25.log").exists());
+    assert!(!nested_dir.path().join("25.log").exists());
+
+    let mut flat_store = KvStore::open(flat_dir.path())?;
+    let mut nested_store = KvStore::open_with_options(nested_dir.path(), nested)?;
+    for key in (0..30)
+        .map(|iter| format!("key{}", iter))
+        .chain(["key".to_owned()])
+    {
+        let value = flat_store.get(key.clone())?;
+        assert!(value.is_some());
+        assert_eq!(nested_store.get(key)?, value);
+    }
+
+    let options = KvStoreOptions::new().layout(DirLayout::Nested { gens_per_dir: 0 });
+    let result = KvStore::open_with_options(nested_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+    Ok(())
+}
+
+// The event callback sees every committed operation
+#[test]
+fn store_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = Arc::clone(&events);
+    let options = KvStoreOptions::new()
+        .compaction_strategy(CompactionStrategy::SizeThreshold(0))
+        .on_event(move |event| observed.lock().unwrap().push(event.clone()));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 4);
+    assert_eq!(
+        events[0],
+        StoreEvent::Set {
+            key: "key1".to_owned()
+        }
+    );
+    assert_eq!(
+        events[1],
+        StoreEvent::Set {
+            key: "key1".to_owned()
+        }
+    );
+    assert!(matches!(events[2], StoreEvent::Compacted { reclaimed } if reclaimed > 0));
+    assert_eq!(
+        events[3],
+        StoreEvent::Removed {
+            key: "key1".to_owned()
+        }
+    );
+
+    Ok(())
+}
+
+// Range scans follow the collation while keys keep their exact bytes as identity
+#[test]
+fn range_case_insensitive_collation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().collation(Collation::CaseInsensitive);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for key in ["banana", "Apple", "cherry", "apple", "Banana"] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+    assert_eq!(store.get("Apple".to_owned())?, Some("APPLE".to_owned()));
+    assert_eq!(store.get("APPLE".to_owned())?, None);
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(store.range(..)?),
+        ["Apple", "apple", "Banana", "banana", "cherry"]
+    );
+    assert_eq!(
+        keys(store.range("b".to_owned().."c".to_owned())?),
+        ["Banana", "banana"]
+    );
+    assert!(store.range("c".to_owned().."b".to_owned())?.is_empty());
+
+    // the order survives a reopen with the same collation
+    store.remove("apple".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        keys(store.range(..)?),
+        ["Apple", "Banana", "banana", "cherry"]
+    );
+
+    // the binary collation orders by bytes
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        keys(store.range(..)?),
+        ["Apple", "Banana", "banana", "cherry"]
+    );
+    store.set("apple".to_owned(), "APPLE".to_owned())?;
+    assert_eq!(
+        keys(store.range(..)?),
+        ["Apple", "Banana", "apple", "banana", "cherry"]
+    );
+
+    Ok(())
+}
+
+// A bulk load is visible right away and after reopening, with later entries winning
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set("extra".to_owned(), "kept".to_owned())?;
+
+    let pairs = (0..50_000)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .chain((0..100).map(|id| (format!("key{}", id), format!("new{}", id))));
+    store.bulk_load(pairs)?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.keys(..).len(), 50_001);
+        for id in (0..50_000).step_by(997) {
+            let expected = if id < 100 {
+                format!("new{}", id)
+            } else {
+                format!("value{}", id)
+            };
+            assert_eq!(store.get(format!("key{}", id))?, Some(expected));
+        }
+        assert_eq!(store.get("extra".to_owned())?, Some("kept".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    store.set("key1".to_owned(), "after".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    store.set("key1".to_owned(), "new1".to_owned())?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// A corrupted record in the middle of the log is skipped over in `Fast` mode only
+#[test]
+fn open_mode_mid_log_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 1..=3 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    drop(store);
+
+    // garble the payload of the second record, keeping its length prefix intact
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let first_len = u32::from_le_bytes(log[..4].try_into().unwrap()) as usize;
+    let second = 4 + first_len;
+    let second_len = u32::from_le_bytes(log[second..second + 4].try_into().unwrap()) as usize;
+    log[second + 4..second + 4 + second_len].fill(0xff);
+    fs::write(&log_path, log)?;
+
+    let strict = KvStoreOptions::new().open_mode(OpenMode::Strict);
+    assert!(KvStore::open_with_options(temp_dir.path(), strict).is_err());
+
+    let fast = KvStoreOptions::new().open_mode(OpenMode::Fast);
+    let mut store = KvStore::open_with_options(temp_dir.path(), fast)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+/// A file hook tracking how many of the bytes written to each file reached the disk,
+/// to simulate a crash losing the others, which fails writes past `remaining` bytes
+#[derive(Clone, Default)]
+struct DiskHook(Arc<Mutex<DiskState>>);
+
+#[derive(Default)]
+struct DiskState {
+    // the bytes written to each file, and how many of them were synced
+    files: HashMap<PathBuf, (u64, u64)>,
+    dir_syncs: Vec<PathBuf>,
+    remaining: Option<usize>,
+}
+
+impl DiskHook {
+    /// Cuts every file written through the hook back to the bytes which were synced
+    fn crash(&self) -> Result<()> {
+        for (path, &(_, synced)) in &self.0.lock().unwrap().files {
+            if path.exists() {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(synced)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FileHook for DiskHook {
+    fn write(&self, path: &Path, len: usize) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        let len = match &mut state.remaining {
+            Some(0) => return Err(io::Error::other("injected fault")),
+            Some(remaining) => {
+                let len = len.min(*remaining);
+                *remaining -= len;
+                len
+            }
+            None => len,
+        };
+        state.files.entry(path.to_owned()).or_default().0 += len as u64;
+        Ok(len)
+    }
+
+    fn synced(&self, path: &Path) {
+        let mut state = self.0.lock().unwrap();
+        let (written, synced) = state.files.entry(path.to_owned()).or_default();
+        *synced = *written;
+    }
+
+    fn dir_synced(&self, path: &Path) {
+        self.0.lock().unwrap().dir_syncs.push(path.to_owned());
+    }
+
+    fn renamed(&self, from: &Path, to: &Path) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_owned(), file);
+        }
+    }
+}
+
+// A crash right after a compaction keeps the compacted records only if they were synced,
+// along with the directory the merged generations were removed from
+#[test]
+fn sync_compaction() -> Result<()> {
+    for sync_compaction in [true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let hook = DiskHook::default();
+        let options = KvStoreOptions::new()
+            .sync_compaction(sync_compaction)
+            .file_hook(hook.clone());
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..2 {
+            for id in 0..10 {
+                store.set(format!("key{}", id), format!("value{}", iter))?;
+            }
+        }
+        let before = store.stats()?.flush_count;
+        store.compact()?;
+        let synced = store.stats()?.flush_count - before;
+        assert_eq!(synced, u64::from(sync_compaction));
+        let dir_syncs = hook.0.lock().unwrap().dir_syncs.clone();
+        assert_eq!(
+            dir_syncs.contains(&temp_dir.path().to_path_buf()),
+            sync_compaction
+        );
+
+        // the crash keeps the synced bytes only
+        std::mem::forget(store);
+        hook.crash()?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        for id in 0..10 {
+            let value = store.get(format!("key{}", id))?;
+            let expected = sync_compaction.then(|| "value1".to_owned());
+            assert_eq!(value, expected, "sync_compaction: {}", sync_compaction);
+        }
+    }
+    Ok(())
+}
+
+// Compaction copies values much larger than its buffer intact
+#[test]
+fn compaction_buffer_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_buffer_bytes(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = |id: usize, iter: usize| {
+        let len = 3 * 1024 * 1024 + id * 1000 + iter;
+        (0..len)
+            .map(|i| char::from(b'a' + ((i + id + iter) % 26) as u8))
+            .collect::<String>()
+    };
+    for iter in 0..2 {
+        for id in 0..4 {
+            store.set(format!("key{}", id), value(id, iter))?;
+        }
+    }
+    store.compact()?;
+    for id in 0..4 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(value(id, 1)));
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 0..4 {
+        assert_eq!(store.get(format!("key{}", id))?, Some(value(id, 1)));
+    }
+    Ok(())
+}
+
+// A compressed export is smaller than a plain one and imports losslessly
+#[test]
+fn compressed_export_roundtrip() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    let mut rng = rand::thread_rng();
+    for id in 0..5000 {
+        let value = format!("user {} logged in from host-{}", id, rng.gen_range(0, 10));
+        source.set(format!("session:{:05}", id), value)?;
+    }
+    source.set("binary".to_owned(), "\u{0}\u{ff}\u{7f}".repeat(100))?;
+    let mut plain = Vec::new();
+    source.export(&mut plain)?;
+    let mut compressed = Vec::new();
+    source.export_compressed(&mut compressed, 3)?;
+    assert!(compressed.len() * 2 < plain.len());
+    // a standard zstd stream, which other tools can decompress
+    assert_eq!(zstd::decode_all(compressed.as_slice())?, plain);
+
+    for export in [&compressed, &plain] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.import_compressed(export.as_slice())?;
+        assert_eq!(store.keys(..), source.keys(..));
+        for key in source.keys(..) {
+            assert_eq!(store.get(key.clone())?, source.get(key)?);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    compressed.truncate(compressed.len() - 10);
+    assert!(store.import_compressed(compressed.as_slice()).is_err());
+    Ok(())
+}
+
+// A set torn by a crash opens in fast mode with every record before the tear
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let scenarios = [
+        ("torn length header", 2),
+        ("torn payload", 4 + 3),
+        ("torn before the record", 0),
+    ];
+    for (scenario, limit) in scenarios {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let hook = DiskHook::default();
+        let options = KvStoreOptions::new().file_hook(hook.clone());
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for id in 1..=2 {
+            store.set(format!("key{}", id), format!("value{}", id))?;
+        }
+        hook.0.lock().unwrap().remaining = Some(limit);
+        assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+        // crash without closing the store
+        std::mem::forget(store);
+
+        let strict = KvStoreOptions::new().open_mode(OpenMode::Strict);
+        let opened = KvStore::open_with_options(temp_dir.path(), strict);
+        assert_eq!(opened.is_ok(), limit == 0, "{}", scenario);
+        drop(opened);
+
+        // the records before the tear survive writes and reopening
+        let fast = KvStoreOptions::new().open_mode(OpenMode::Fast);
+        let mut store = KvStore::open_with_options(temp_dir.path(), fast.clone())?;
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), fast)?;
+        for id in 1..=2 {
+            let value = store.get(format!("key{}", id))?;
+            assert_eq!(value, Some(format!("value{}", id)), "{}", scenario);
+        }
+        assert_eq!(store.get("key3".to_owned())?, None, "{}", scenario);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Running out of generation numbers is an error rather than a panic or a wrap
+#[test]
+fn generation_number_overflow() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::rename(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join(format!("{}.log", u64::MAX - 1)),
+    )?;
+
+    // the last generation number is left for writing, but not for compacting
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.compact(),
+        Err(KvsError::GenerationOverflow(gen)) if gen == u64::MAX
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    fs::rename(
+        temp_dir.path().join(format!("{}.log", u64::MAX - 1)),
+        temp_dir.path().join("18446744073709551615.log"),
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::GenerationOverflow(gen)) if gen == u64::MAX
+    ));
+
+    Ok(())
+}
+
+// A length prefix spanning more than its record is reported instead of misaligning the replay
+#[test]
+fn misaligned_record_length_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // stretch the first prefix over the second record, whose bytes still decode on their own
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let stretched = (log.len() - 4) as u32;
+    log[..4].copy_from_slice(&stretched.to_le_bytes());
+    fs::write(&log_path, log)?;
+
+    let strict = KvStoreOptions::new().open_mode(OpenMode::Strict);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), strict),
+        Err(KvsError::MisalignedRecord(size)) if size == stretched as usize
+    ));
+
+    let fast = KvStoreOptions::new().open_mode(OpenMode::Fast);
+    let mut store = KvStore::open_with_options(temp_dir.path(), fast)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Empty generation files are removed on open without disturbing the index
+#[test]
+fn empty_generation_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_snapshot(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a session without writes leaves its generation empty, and a crash right
+    // after starting a generation does the same
+    drop(KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone(),
+    )?);
+    File::create(temp_dir.path().join("3.log"))?;
+    File::create(temp_dir.path().join("9.log"))?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for gen in [2, 3, 9] {
+        assert!(!temp_dir.path().join(format!("{}.log", gen)).exists());
+    }
+    // generation numbers keep increasing past the removed ones
+    assert!(temp_dir.path().join("10.log").exists());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `get_prefixed` returns exactly the pairs whose keys start with the prefix
+#[test]
+fn get_prefixed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["user:1", "user:2", "usage", "visit:1"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    store.remove("user:2".to_owned())?;
+
+    let users = store.get_prefixed("user:")?;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users["user:1"], "user:1-value");
+
+    assert!(store.get_prefixed("missing")?.is_empty());
+
+    let all = store.get_prefixed("")?;
+    assert_eq!(
+        all.keys().collect::<Vec<_>>(),
+        ["usage", "user:1", "visit:1"]
+    );
+
+    // with a case-insensitive collation the prefix still matches case-sensitively
+    drop(store);
+    let options = KvStoreOptions::new().collation(Collation::CaseInsensitive);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("User:3".to_owned(), "User:3-value".to_owned())?;
+    store.set("user:4".to_owned(), "user:4-value".to_owned())?;
+    assert_eq!(
+        store.get_prefixed("user:")?.keys().collect::<Vec<_>>(),
+        ["user:1", "user:4"]
+    );
+
+    Ok(())
+}
+
+// A reset store is empty, keeps working, and stays empty after reopening
+#[test]
+fn reset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+
+    store.reset()?;
+    assert!(store.keys(..).is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let log_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "log")
+        })
+        .count();
+    assert_eq!(log_files, 1);
+
+    store.set("key2".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(..), ["key2"]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A throttled compaction takes at least as long as its byte rate requires
+#[test]
+fn compaction_throttle() -> Result<()> {
+    let compaction_time = |options: KvStoreOptions| -> Result<Duration> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for id in 0..100 {
+            store.set(format!("key{}", id), "v".repeat(1000))?;
+        }
+        let started = Instant::now();
+        store.compact()?;
+        Ok(started.elapsed())
+    };
+
+    let unthrottled = compaction_time(KvStoreOptions::new())?;
+    let throttled = compaction_time(KvStoreOptions::new().compaction_bytes_per_sec(200_000))?;
+    assert!(throttled >= Duration::from_millis(400));
+    assert!(throttled > unthrottled);
+
+    Ok(())
+}
+
+// A write between the batches of a shared compaction wins over the copy of the key
+// made after it, once the compaction finishes and on reopen
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_bytes_per_sec(200_000)
+        .compaction_buffer_bytes(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // the key sorts after the fillers, so it is copied by one of the last batches
+    store.set("key".to_owned(), "before".to_owned())?;
+    for id in 0..100 {
+        store.set(format!("filler{}", id), "v".repeat(1000))?;
+    }
+    let status = store.compaction_status();
+    let store = Arc::new(Mutex::new(store));
+
+    let compaction = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || KvStore::compact_shared(&store))
+    };
+    // the lock is only free between batches, so the merge is still underway
+    let written_during = loop {
+        let mut store = store.lock().unwrap();
+        if status.is_compacting() {
+            store.set("key".to_owned(), "during".to_owned())?;
+            break true;
+        }
+        if compaction.is_finished() {
+            break false;
+        }
+        drop(store);
+        thread::sleep(Duration::from_millis(1));
+    };
+    assert!(written_during);
+    compaction.join().unwrap()?;
+
+    let mut store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+    assert_eq!(store.get("key".to_owned())?, Some("during".to_owned()));
+    let during_gen = store.get_meta("key").expect("key not found").gen;
+    assert!(during_gen > store.get_meta("filler0").expect("key not found").gen);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("during".to_owned()));
+    Ok(())
+}
+
+// A shared compaction only holds the lock while copying a batch, so writes go
+// through while it sleeps off the throttle, and win over the copies
+#[test]
+fn compact_shared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_bytes_per_sec(200_000)
+        .compaction_buffer_bytes(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..2 {
+        for id in 0..100 {
+            store.set(format!("filler{:02}", id), format!("{}", iter).repeat(1000))?;
+        }
+    }
+    let status = store.compaction_status();
+    let store = Arc::new(Mutex::new(store));
+
+    let compaction = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || KvStore::compact_shared(&store))
+    };
+    while !status.is_compacting() && !compaction.is_finished() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    {
+        let mut store = store.lock().unwrap();
+        // the compaction released the lock before it was done
+        assert!(status.is_compacting());
+        store.set("filler99".to_owned(), "during".to_owned())?;
+        store.remove("filler98".to_owned())?;
+    }
+    assert!(compaction.join().unwrap()? > 0);
+
+    let mut store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("filler00".to_owned())?, Some("1".repeat(1000)));
+        assert_eq!(store.get("filler99".to_owned())?, Some("during".to_owned()));
+        assert_eq!(store.get("filler98".to_owned())?, None);
+        assert_eq!(store.keys(..).len(), 99);
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+// A compaction of the store holding it mutably may run while a shared compaction
+// sleeps off the throttle after copying its last batch
+#[test]
+fn compact_during_compact_shared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a single batch, copied before the lock is first released
+    let options = KvStoreOptions::new()
+        .compaction_bytes_per_sec(100_000)
+        .compaction_buffer_bytes(1 << 20)
+        .retain_compacted(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..2 {
+        for id in 0..20 {
+            store.set(format!("filler{:02}", id), format!("{}", iter).repeat(1000))?;
+        }
+    }
+    let status = store.compaction_status();
+    let store = Arc::new(Mutex::new(store));
+
+    let compaction = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || KvStore::compact_shared(&store))
+    };
+    // the merge may also be over by the time this thread runs again
+    while !status.is_compacting() && !compaction.is_finished() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    while !compaction.is_finished() {
+        store.lock().unwrap().compact()?;
+        thread::sleep(Duration::from_millis(1));
+    }
+    compaction.join().unwrap()?;
+
+    let mut store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+    store.compact()?;
+    for id in 0..20 {
+        assert_eq!(
+            store.get(format!("filler{:02}", id))?,
+            Some("1".repeat(1000))
+        );
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(..).len(), 20);
+    Ok(())
+}
+
+// Every engine returns the same prefix scan for the same operations
+#[test]
+fn scan_conformance() -> Result<()> {
+    fn populate_and_scan(
+        engine: &mut impl KvsEngine,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>> {
+        for key in ["user:2", "user:10", "user:1", "usage", "visit:1", "user"] {
+            engine.set(key.to_owned(), format!("{}-value", key))?;
+        }
+        engine.set("user:1".to_owned(), "updated".to_owned())?;
+        engine.remove("user:2".to_owned())?;
+        engine.scan(prefix)
+    }
+
+    for prefix in ["user:", "user", "", "missing"] {
+        let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sharded_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let expected = populate_and_scan(&mut KvStore::open(kvs_dir.path())?, prefix)?;
+        let sled = populate_and_scan(&mut SledKvsEngine::open(sled_dir.path())?, prefix)?;
+        let sharded =
+            populate_and_scan(&mut KvStore::open_sharded(sharded_dir.path(), 3)?, prefix)?;
+        assert_eq!(sled, expected);
+        assert_eq!(sharded, expected);
+    }
+
+    Ok(())
+}
+
+// Every engine iterates over the same pairs for the same operations
+#[test]
+fn iter_conformance() -> Result<()> {
+    fn populate_and_iter(engine: &mut impl KvsEngine) -> Result<Vec<(String, String)>> {
+        for id in 0..50 {
+            engine.set(format!("key{}", id), format!("value{}", id))?;
+        }
+        engine.set("key7".to_owned(), "updated".to_owned())?;
+        engine.remove("key3".to_owned())?;
+        let mut pairs = engine.iter()?.collect::<Result<Vec<_>>>()?;
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sharded_dir = TempDir::new().expect("unable to create temporary working directory");
+    let expected = populate_and_iter(&mut KvStore::open(kvs_dir.path())?)?;
+    let sled = populate_and_iter(&mut SledKvsEngine::open(sled_dir.path())?)?;
+    let sharded = populate_and_iter(&mut KvStore::open_sharded(sharded_dir.path(), 3)?)?;
+    assert_eq!(expected.len(), 49);
+    assert!(expected.contains(&("key7".to_owned(), "updated".to_owned())));
+    assert_eq!(sled, expected);
+    assert_eq!(sharded, expected);
+
+    // the pairs are the ones live when iterating starts
+    let mut store = KvStore::open(kvs_dir.path())?;
+    let iter = store.iter()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(iter.count(), 49);
+
+    Ok(())
+}
+
+// Should report a growing size on disk as data is written and flushed
+#[test]
+fn size_on_disk_grows() -> Result<()> {
+    fn write_and_measure(engine: &mut impl KvsEngine) -> Result<(u64, u64)> {
+        engine.flush()?;
+        let before = engine.size_on_disk()?;
+        for key_id in 0..1000 {
+            engine.set(format!("key{}", key_id), "x".repeat(100))?;
+        }
+        engine.flush()?;
+        Ok((before, engine.size_on_disk()?))
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let (before, after) = write_and_measure(&mut KvStore::open(kvs_dir.path())?)?;
+    assert!(after > before);
+    let (before, after) = write_and_measure(&mut SledKvsEngine::open(sled_dir.path())?)?;
+    assert!(after > before);
+
+    Ok(())
+}
+
+// Every engine reports its keys and disk usage through the same stats
+#[test]
+fn stats_conformance() -> Result<()> {
+    fn populate_and_stats(engine: &mut impl KvsEngine) -> Result<EngineStats> {
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        engine.remove("key0".to_owned())?;
+        engine.flush()?;
+        engine.stats()
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sharded_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for stats in [
+        populate_and_stats(&mut KvStore::open(kvs_dir.path())?)?,
+        populate_and_stats(&mut SledKvsEngine::open(sled_dir.path())?)?,
+        populate_and_stats(&mut KvStore::open_sharded(sharded_dir.path(), 3)?)?,
+    ] {
+        assert_eq!(stats.key_count, 99);
+        assert!(stats.disk_bytes > 0);
+    }
+
+    Ok(())
+}
+
+// Should reject keys failing the key validator without writing anything
+#[test]
+fn key_validator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().key_validator(|key| !key.contains(':'));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.set("ns:key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::InvalidKey(key)) if key == "ns:key2"
+    ));
+    assert!(matches!(
+        store.set_many([
+            ("key3".to_owned(), "value3".to_owned()),
+            ("ns:key4".to_owned(), "value4".to_owned()),
+        ]),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert_eq!(store.get("ns:key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Every engine rejects the empty key the same way
+#[test]
+fn empty_key_conformance() -> Result<()> {
+    fn empty_key_results(engine: &mut impl KvsEngine) -> [bool; 3] {
+        let invalid = |result| matches!(result, Err(KvsError::InvalidKey(key)) if key.is_empty());
+        [
+            invalid(engine.set(String::new(), "value".to_owned())),
+            invalid(engine.get(String::new()).map(|_| ())),
+            invalid(engine.remove(String::new())),
+        ]
+    }
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sharded_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    assert_eq!(
+        empty_key_results(&mut KvStore::open(kvs_dir.path())?),
+        [true; 3]
+    );
+    assert_eq!(
+        empty_key_results(&mut SledKvsEngine::open(sled_dir.path())?),
+        [true; 3]
+    );
+    assert_eq!(
+        empty_key_results(&mut KvStore::open_sharded(sharded_dir.path(), 3)?),
+        [true; 3]
+    );
+
+    Ok(())
+}
+
+// Every read returns the latest value while compactions move the records around
+#[test]
+fn read_your_writes_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_strategy(CompactionStrategy::SizeThreshold(512));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut expected = Vec::new();
+    for round in 0..20 {
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id);
+            let value = format!("value{}-{}", key_id, round);
+            store.set(key.clone(), value.clone())?;
+            // writes may trigger a compaction at any point
+            assert_eq!(store.get(key)?, Some(value));
+        }
+        expected = (0..50)
+            .map(|key_id| format!("value{}-{}", key_id, round))
+            .collect();
+
+        if round % 3 == 0 {
+            store.compact()?;
+        }
+        for (key_id, value) in expected.iter().enumerate() {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+        }
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for (key_id, value) in expected.iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Should stream a large value from the log without changing its bytes
+#[test]
+fn get_reader_streams_large_value() -> Result<()> {
+    struct HashWriter(DefaultHasher);
+
+    impl Write for HashWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut rng = rand::thread_rng();
+    let value: String = (0..4 * 1024 * 1024)
+        .map(|_| rng.sample(rand::distributions::Alphanumeric))
+        .collect();
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), value.clone())?;
+    store.remove("small".to_owned())?;
+
+    let mut expected = DefaultHasher::new();
+    expected.write(value.as_bytes());
+    let mut reader = store.get_reader("large".to_owned())?.unwrap();
+    let mut streamed = HashWriter(DefaultHasher::new());
+    assert_eq!(io::copy(&mut reader, &mut streamed)?, value.len() as u64);
+    assert_eq!(streamed.0.finish(), expected.finish());
+
+    assert!(store.get_reader("small".to_owned())?.is_none());
+
+    // values of every length prefix width
+    for len in [0, 1, 255, 256, 70_000] {
+        store.set(format!("key{}", len), value[..len].to_owned())?;
+        let mut streamed = String::new();
+        let mut reader = store.get_reader(format!("key{}", len))?.unwrap();
+        reader.read_to_string(&mut streamed)?;
+        assert_eq!(streamed, value[..len]);
+    }
+
+    // the reader survives a compaction dropping the file it reads from
+    let mut reader = store.get_reader("large".to_owned())?.unwrap();
+    store.compact()?;
+    let mut streamed = String::new();
+    reader.read_to_string(&mut streamed)?;
+    assert!(streamed == value);
+
+    Ok(())
+}
+
+// Every engine syncs its writes as often as the flush policy asks
+#[test]
+fn flush_policy_conformance() -> Result<()> {
+    fn flush_counts(engine: &mut impl KvsEngine) -> Result<(u64, u64)> {
+        for key_id in 0..5 {
+            engine.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        engine.remove("key0".to_owned())?;
+        let after_writes = engine.stats()?.flush_count;
+        engine.flush()?;
+        Ok((after_writes, engine.stats()?.flush_count))
+    }
+
+    let policies = [
+        (FlushPolicy::Always, (6, 6)),
+        (FlushPolicy::OnCommitBatch, (6, 6)),
+        (FlushPolicy::Interval(Duration::ZERO), (6, 6)),
+        (FlushPolicy::Interval(Duration::from_secs(3600)), (0, 1)),
+        (FlushPolicy::Manual, (0, 1)),
+    ];
+    for (policy, expected) in policies {
+        let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+        let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let options = KvStoreOptions::new().flush_policy(policy);
+        let mut store = KvStore::open_with_options(kvs_dir.path(), options)?;
+        assert_eq!(flush_counts(&mut store)?, expected, "{:?}", policy);
+        let mut sled = SledKvsEngine::open_with_flush_policy(sled_dir.path(), policy)?;
+        assert_eq!(flush_counts(&mut sled)?, expected, "{:?}", policy);
+
+        // only `Always` syncs each record of a batch
+        let before = store.stats()?.flush_count;
+        store.set_many((0..3).map(|key_id| (format!("batch{}", key_id), "value".to_owned())))?;
+        let batch_flushes = match policy {
+            FlushPolicy::Always => 3,
+            FlushPolicy::OnCommitBatch | FlushPolicy::Interval(Duration::ZERO) => 1,
+            _ => 0,
+        };
+        assert_eq!(
+            store.stats()?.flush_count - before,
+            batch_flushes,
+            "{:?}",
+            policy
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction moves the generations it replaces into the archive when retention is on
+#[test]
+fn retain_compacted_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().retain_compacted(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let history = fs::read(temp_dir.path().join("1.log"))?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(
+        fs::read(temp_dir.path().join("archive").join("1.log"))?,
+        history
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // the archive is not replayed on open
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.key_count, 1);
+    drop(store);
+
+    // without retention the compacted generations are gone
+    let archived = || {
+        fs::read_dir(temp_dir.path().join("archive"))
+            .unwrap()
+            .count()
+    };
+    let archived_before = archived();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert_eq!(archived(), archived_before);
+
+    Ok(())
+}
+
+// Typed values keep their type across reopening and compaction
+#[test]
+fn typed_values_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("str".to_owned(), "42".to_owned())?;
+    store.set_int("int".to_owned(), -42)?;
+    store.set_float("float".to_owned(), 0.1)?;
+    store.set_bytes("bytes".to_owned(), &[0, 1, 0xfe, 0xff])?;
+    store.compact()?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.value_type("str".to_owned())?, Some(ValueType::Str));
+    assert_eq!(store.value_type("int".to_owned())?, Some(ValueType::Int));
+    assert_eq!(
+        store.value_type("float".to_owned())?,
+        Some(ValueType::Float)
+    );
+    assert_eq!(
+        store.value_type("bytes".to_owned())?,
+        Some(ValueType::Bytes)
+    );
+    assert_eq!(store.value_type("missing".to_owned())?, None);
+
+    assert_eq!(store.get("str".to_owned())?, Some("42".to_owned()));
+    assert_eq!(store.get_int("int".to_owned())?, Some(-42));
+    assert_eq!(store.get_float("float".to_owned())?, Some(0.1));
+    assert_eq!(
+        store.get_bytes("bytes".to_owned())?,
+        Some(vec![0, 1, 0xfe, 0xff])
+    );
+    // the string API reads the textual form of any value
+    assert_eq!(store.get("int".to_owned())?, Some("-42".to_owned()));
+    assert_eq!(store.get("bytes".to_owned())?, Some("0001feff".to_owned()));
+    assert!(matches!(
+        store.get_int("str".to_owned()),
+        Err(KvsError::WrongValueType {
+            value_type: ValueType::Str,
+            ..
+        })
+    ));
+
+    // overwriting through the string API makes the value a string again
+    store.set("int".to_owned(), "text".to_owned())?;
+    assert_eq!(store.value_type("int".to_owned())?, Some(ValueType::Str));
+
+    let mut json = Vec::new();
+    store.export_json(&mut json)?;
+    assert_eq!(
+        String::from_utf8(json).unwrap(),
+        r#"{"bytes":"0001feff","float":0.1,"int":"text","str":"42"}"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get_int("counter".to_owned())?, Some(-2));
+
+    // a string holding a number is still a string
+    store.set("text".to_owned(), "42".to_owned())?;
+    assert!(matches!(
+        store.increment("text".to_owned(), 1),
+        Err(KvsError::WrongValueType { key, value_type: ValueType::Str }) if key == "text"
+    ));
+    assert_eq!(store.get("text".to_owned())?, Some("42".to_owned()));
+
+    store.set_int("max".to_owned(), i64::MAX)?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow(_))
+    ));
+
+    Ok(())
+}
+
+// Generations beyond the limit are merged, oldest first, keeping every live value
+#[test]
+fn max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.set("shared".to_owned(), format!("value{}", i))?;
+        store.rotate()?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.generation_count(), 11);
+
+    drop(store);
+    let options = KvStoreOptions::new().max_generations(4);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    assert!(store.generation_count() <= 4);
+    for _ in 0..5 {
+        store.rotate()?;
+        assert!(store.generation_count() <= 4);
+    }
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..=10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("shared".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+/// A clock moved by hand, shared between a test and its store
+#[derive(Clone)]
+struct ManualClock(Arc<Mutex<(SystemTime, Instant)>>);
+
+impl ManualClock {
+    fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new((SystemTime::now(), Instant::now()))))
+    }
+
+    /// Advances both the wall clock and the monotonic clock
+    fn advance(&self, by: Duration) {
+        let mut now = self.0.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+
+    /// Moves the wall clock backward, as an NTP correction would
+    fn rewind_wall(&self, by: Duration) {
+        self.0.lock().unwrap().0 -= by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn wall(&self) -> SystemTime {
+        self.0.lock().unwrap().0
+    }
+
+    fn monotonic(&self) -> Instant {
+        self.0.lock().unwrap().1
+    }
+}
+
+// Keys with a TTL disappear at their deadline, also after reopening and compacting
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new();
+    let options = KvStoreOptions::new().clock(clock.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    store.set_with_ttl(
+        "reset".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set("reset".to_owned(), "value4".to_owned())?;
+    clock.advance(Duration::from_secs(20));
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KvsError::KeyDoesNotExist)
+    ));
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("reset".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.keys(..), vec!["long".to_owned(), "reset".to_owned()]);
+    assert_eq!(store.stats()?.key_count, 2);
+
+    store.compact()?;
+    clock.rewind_wall(Duration::from_secs(20));
+    // the compacted log no longer holds the expired value
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    clock.advance(Duration::from_secs(200));
+    assert_eq!(store.get("long".to_owned())?, None);
+
+    Ok(())
+}
+
+// `update_value` keeps the deadline of a key, while `set` drops it
+#[test]
+fn update_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new();
+    let options = KvStoreOptions::new().clock(clock.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let ttl = Duration::from_secs(10);
+    store.set_with_ttl("updated".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl("set".to_owned(), "value1".to_owned(), ttl)?;
+    store.set("permanent".to_owned(), "value1".to_owned())?;
+    clock.advance(Duration::from_secs(4));
+
+    assert!(store.update_value("updated".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.ttl("updated"), Some(Some(Duration::from_secs(6))));
+    store.set("set".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.ttl("set"), Some(None));
+    assert!(store.update_value("permanent".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.ttl("permanent"), Some(None));
+    assert!(!store.update_value("missing".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("updated".to_owned())?, Some("value2".to_owned()));
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(store.get("updated".to_owned())?, None);
+    assert!(!store.update_value("updated".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("set".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get("permanent".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// The modification time of a key follows its writes and survives reopening and compaction
+#[test]
+fn modified_at() -> Result<()> {
+    for codec in [CodecKind::Flexbuffers, CodecKind::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = ManualClock::new();
+        let options = KvStoreOptions::new().clock(clock.clone()).codec(codec);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value1".to_owned())?;
+        let created = store.modified_at("key1").unwrap();
+        assert!(created > 0);
+        assert_eq!(store.modified_at("missing"), None);
+
+        clock.advance(Duration::from_secs(5));
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.modified_at("key1"), Some(created + 5000));
+        assert_eq!(store.modified_at("key2"), Some(created));
+        store.remove("key2".to_owned())?;
+        assert_eq!(store.modified_at("key2"), None);
+
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.modified_at("key1"), Some(created + 5000));
+        store.compact()?;
+        assert_eq!(store.modified_at("key1"), Some(created + 5000));
+    }
+    Ok(())
+}
+
+// Set records written before modification times were tracked still open, strictly,
+// with a modification time of 0
+#[test]
+fn open_records_without_modified_at() -> Result<()> {
+    // the records as written before `modified_at` existed
+    #[derive(serde::Serialize)]
+    enum OldLogLine {
+        Set { key: String, value: String },
+        Rm { key: String },
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = Vec::new();
+    for record in [
+        OldLogLine::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        OldLogLine::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        OldLogLine::Rm {
+            key: "key2".to_owned(),
+        },
+    ] {
+        let record = flexbuffers::to_vec(record).unwrap();
+        log.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        log.extend_from_slice(&record);
+    }
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let options = KvStoreOptions::new().open_mode(OpenMode::Strict);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.modified_at("key1"), Some(0));
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.modified_at("key1"), Some(0));
+    Ok(())
+}
+
+// The value length is the byte length of the value, kept across reopening and compaction
+#[test]
+fn value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_snapshot(true);
+    let values = [
+        ("ascii", "value1"),
+        ("empty", ""),
+        ("multibyte", "héllo wörld ✓"),
+    ];
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for (key, value) in values {
+        store.set(key.to_owned(), value.to_owned())?;
+    }
+    store.set("ascii".to_owned(), "a longer value".to_owned())?;
+    assert_eq!(store.value_len("ascii"), Some(14));
+    assert_eq!(store.value_len("missing"), None);
+
+    let check = |store: &KvStore| {
+        assert_eq!(store.value_len("ascii"), Some(14));
+        for (key, value) in &values[1..] {
+            assert_eq!(store.value_len(key), Some(value.len() as u64));
+        }
+    };
+    check(&store);
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store);
+    store.compact()?;
+    check(&store);
+    store.remove("empty".to_owned())?;
+    assert_eq!(store.value_len("empty"), None);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(store.value_len("multibyte"), Some(values[2].1.len() as u64));
+    Ok(())
+}
+
+// A backward jump of the wall clock is reported, and expired keys only come back
+// when deadlines follow the wall clock
+#[test]
+fn ttl_clock_moving_backward() -> Result<()> {
+    for (ttl_clock, resurrects) in [(TtlClock::Wall, true), (TtlClock::Monotonic, false)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = ManualClock::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&events);
+        let options = KvStoreOptions::new()
+            .ttl_clock(ttl_clock)
+            .clock(clock.clone())
+            .clock_skew_threshold(Duration::from_secs(5))
+            .on_event(move |event| {
+                if let StoreEvent::ClockMovedBackward { .. } = event {
+                    observed.lock().unwrap().push(event.clone());
+                }
+            });
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+        store.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(10),
+        )?;
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // small corrections are not reported
+        clock.rewind_wall(Duration::from_secs(1));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        clock.rewind_wall(Duration::from_secs(60));
+        assert_eq!(store.get("key1".to_owned())?.is_some(), resurrects);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![StoreEvent::ClockMovedBackward {
+                by: Duration::from_secs(60)
+            }]
+        );
+    }
+
+    Ok(())
+}
+
+// With a reader cache, only that many generation files stay open while reads reopen the others
+#[cfg(target_os = "linux")]
+#[test]
+fn reader_cache_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open_files = || {
+        fs::read_dir("/proc/self/fd")
+            .expect("unable to list open files")
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| target.starts_with(temp_dir.path()))
+            .count()
+    };
+    let options = KvStoreOptions::new().reader_cache_size(4);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..50 {
+        let pair = (format!("key{}", key_id), format!("value{}", key_id));
+        store.bulk_load(std::iter::once(pair))?;
+    }
+    assert!(store.generation_count() > 50);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // the readers and the writer
+    let bound = 4 + 1;
+    assert!(open_files() <= bound, "{} open files", open_files());
+    for key_id in (0..50).rev().chain(0..50) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+        assert!(open_files() <= bound, "{} open files", open_files());
+    }
+    Ok(())
+}
+
+// Every generation holds a reader open unless the reader cache bounds them, and
+// reaching the warning ratio of the open file limit is reported once
+#[test]
+fn open_reader_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = Arc::clone(&events);
+    let options = KvStoreOptions::new()
+        .reader_fd_warning_ratio(f64::MIN_POSITIVE)
+        .on_event(move |event| {
+            if let StoreEvent::ReadersNearFdLimit { open, .. } = event {
+                observed.lock().unwrap().push(*open);
+            }
+        });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..20 {
+        let pair = (format!("key{}", key_id), format!("value{}", key_id));
+        store.bulk_load(std::iter::once(pair))?;
+    }
+    assert!(store.generation_count() > 20);
+    assert_eq!(store.open_reader_count(), store.generation_count());
+    assert_eq!(store.stats()?.open_readers, store.generation_count() as u64);
+    assert_eq!(*events.lock().unwrap(), vec![1]);
+    let generations = store.generation_count();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.reader_cache_size(4))?;
+    assert_eq!(store.generation_count(), generations);
+    assert!(store.open_reader_count() <= 4);
+    assert_eq!(events.lock().unwrap().len(), 2);
+
+    let options = KvStoreOptions::new().reader_fd_warning_ratio(0.0);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options),
+        Err(KvsError::Config(_))
+    ));
+    Ok(())
+}
+
+// A read-only store replays the log of a read-only directory without touching it
+#[cfg(unix)]
+#[test]
+fn open_read_only() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_snapshot(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let snapshot_dir = temp_dir.path().join("snapshot");
+    fs::create_dir(&snapshot_dir)?;
+    let list = |dir: &std::path::Path| -> Vec<_> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    for name in list(temp_dir.path()) {
+        let from = temp_dir.path().join(&name);
+        if from.is_file() {
+            fs::copy(from, snapshot_dir.join(name))?;
+        }
+    }
+    let set_mode = |mode| {
+        fs::set_permissions(&snapshot_dir, fs::Permissions::from_mode(mode)).unwrap();
+    };
+    set_mode(0o555);
+    let files = list(&snapshot_dir);
+
+    let mut store = KvStore::open_with_options(&snapshot_dir, options.read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.flush()?, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(list(&snapshot_dir), files);
+    set_mode(0o755);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Stores written with any codec roundtrip their records and reopen with the same codec
+#[test]
+fn log_codecs_roundtrip() -> Result<()> {
+    for codec in [CodecKind::Flexbuffers, CodecKind::Bincode, CodecKind::Json] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().codec(codec);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("str".to_owned(), "value \"quoted\"".to_owned())?;
+        store.set_int("int".to_owned(), -7)?;
+        store.set_with_ttl("ttl".to_owned(), "soon".to_owned(), Duration::from_secs(60))?;
+        store.set("gone".to_owned(), "value".to_owned())?;
+        store.remove("gone".to_owned())?;
+
+        let mut value = String::new();
+        let mut reader = store.get_reader("str".to_owned())?.expect("key not found");
+        reader.read_to_string(&mut value)?;
+        assert_eq!(value, "value \"quoted\"");
+        drop(reader);
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(
+            store.get("str".to_owned())?,
+            Some("value \"quoted\"".to_owned())
+        );
+        assert_eq!(store.get_int("int".to_owned())?, Some(-7));
+        assert_eq!(store.get("ttl".to_owned())?, Some("soon".to_owned()));
+        assert_eq!(store.get("gone".to_owned())?, None);
+
+        let mut export = Vec::new();
+        store.export(&mut export)?;
+        let records = KvStore::decode_log_with(io::Cursor::new(export), codec)?;
+        assert_eq!(records.len(), 3, "{:?}", codec);
+
+        // sealed generations end with a footer in every codec
+        store.set("str".to_owned(), "value".to_owned())?;
+        store.rotate()?;
+        let checks = store.verify(false)?;
+        assert_eq!(checks[0], (1, Verification::Replay), "{:?}", codec);
+        assert_eq!(checks[1], (2, Verification::Footer), "{:?}", codec);
+    }
+    Ok(())
+}
+
+/// Counts the allocations made by each thread, so tests running in parallel do not interfere
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Replaying the log on open reuses its buffers instead of allocating per record
+#[test]
+fn open_allocations_per_record() -> Result<()> {
+    fn open_allocations(records: u64) -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..records {
+            store.set(format!("key{}", key_id % 100), "v".repeat(100))?;
+        }
+        drop(store);
+
+        let before = ALLOCATIONS.with(Cell::get);
+        let store = KvStore::open(temp_dir.path())?;
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+        drop(store);
+        Ok(allocations)
+    }
+
+    // the extra records only allocate their decoded key and value
+    let extra = open_allocations(2000)? - open_allocations(1000)?;
+    assert!(extra <= 2 * 1000, "{} allocations for 1000 records", extra);
+
+    Ok(())
+}
+
+// Reusing the serializer and the received buffers takes fewer allocations than
+// allocating them for every message, and reused buffers hold no stale content
+#[test]
+fn buffer_pool_allocations() {
+    fn round_trip_allocations(
+        sender: &mut TcpStream,
+        receiver: &mut TcpStream,
+        count: usize,
+    ) -> u64 {
+        let before = ALLOCATIONS.with(Cell::get);
+        for i in 0..count {
+            NetworkConnection::send_network_message(
+                NetworkConnection::Request {
+                    command: Commands::Set {
+                        key: "key1".to_owned(),
+                        value: "value".repeat(i % 20),
+                    },
+                },
+                sender,
+            )
+            .unwrap();
+            let buf = NetworkConnection::receive_network_message(receiver).unwrap();
+            match NetworkConnection::deserialize_message(buf).unwrap() {
+                NetworkConnection::Request {
+                    command: Commands::Set { value, .. },
+                } => assert_eq!(value, "value".repeat(i % 20)),
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut receiver, _) = listener.accept().unwrap();
+
+    set_buffer_pool_size(0);
+    let unpooled = round_trip_allocations(&mut sender, &mut receiver, 200);
+    set_buffer_pool_size(DEFAULT_BUFFER_POOL_SIZE);
+    // the first messages fill the pool
+    round_trip_allocations(&mut sender, &mut receiver, 20);
+    let pooled = round_trip_allocations(&mut sender, &mut receiver, 200);
+    assert!(
+        pooled * 2 < unpooled,
+        "{} allocations with the pool, {} without",
+        pooled,
+        unpooled
+    );
+}
+
+// Every error variant falls into its category
+#[test]
+fn error_kinds() {
+    let errors = [
+        (KvsError::KeyDoesNotExist, ErrorKind::NotFound),
+        (io::Error::from(io::ErrorKind::Other).into(), ErrorKind::Io),
+        (
+            sled::Error::Unsupported("unsupported".to_owned()).into(),
+            ErrorKind::Io,
+        ),
+        (
+            flexbuffers::SerializationError::KeyMustBeString.into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            flexbuffers::DeserializationError::Serde("invalid".to_owned()).into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            flexbuffers::ReaderError::InvalidPackedType.into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            bincode::deserialize::<String>(&[1]).unwrap_err().into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            serde_json::from_str::<String>("").unwrap_err().into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            u8::try_from(256_u32).unwrap_err().into(),
+            ErrorKind::Serialization,
+        ),
+        (
+            KvsError::UnexpectedCommandType {
+                key: "key".to_owned(),
+                gen: 1,
+                pos: 0,
+            },
+            ErrorKind::Serialization,
+        ),
+        (KvsError::MisalignedRecord(1), ErrorKind::Serialization),
+        (
+            KvsError::CorruptGeneration {
+                gen: 1,
+                reason: "checksum mismatch".to_owned(),
+            },
+            ErrorKind::Serialization,
+        ),
+        (KvsError::Remote("error".to_owned()), ErrorKind::Protocol),
+        (
+            KvsError::IncompatibleVersion("0.0.0".to_owned()),
+            ErrorKind::Protocol,
+        ),
+        (KvsError::MessageTooLarge(1), ErrorKind::Protocol),
+        (
+            "address"
+                .parse::<std::net::SocketAddr>()
+                .unwrap_err()
+                .into(),
+            ErrorKind::Config,
+        ),
+        (
+            KvsError::UnknownEngineType("engine".to_owned()),
+            ErrorKind::Config,
+        ),
+        (
+            KvsError::WrongEngineType("engine".to_owned()),
+            ErrorKind::Config,
+        ),
+        (KvsError::WrongShardCount("1".to_owned()), ErrorKind::Config),
+        (KvsError::ReadOnly, ErrorKind::Config),
+        (KvsError::Config("invalid".to_owned()), ErrorKind::Config),
+        (KvsError::InvalidKey("key".to_owned()), ErrorKind::Other),
+        (
+            KvsError::WrongValueType {
+                key: "key".to_owned(),
+                value_type: ValueType::Int,
+            },
+            ErrorKind::Other,
+        ),
+        (
+            KvsError::IntegerOverflow("key".to_owned()),
+            ErrorKind::Other,
+        ),
+        (KvsError::GenerationOverflow(u64::MAX), ErrorKind::Other),
+    ];
+    for (error, kind) in errors {
+        assert_eq!(error.kind(), kind, "{:?}", error);
+    }
+}
+
+// A wrapped error is reported as the source of the kvs error
+#[test]
+fn error_source() {
+    let error = KvsError::from(io::Error::new(io::ErrorKind::NotFound, "missing log"));
+    let source = error.source().expect("an Io error has a source");
+    let source = source
+        .downcast_ref::<io::Error>()
+        .expect("the source is the io::Error");
+    assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    assert_eq!(source.to_string(), "missing log");
+
+    assert!(KvsError::KeyDoesNotExist.source().is_none());
+}
+
+// Options with an invalid value are refused when the store is opened
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_bytes_per_sec(0);
+    let result = KvStore::open_with_options(temp_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+    let options = KvStoreOptions::new().min_reclaim_ratio(1.0);
+    let result = KvStore::open_with_options(temp_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+
+    let result = KvStore::open_sharded(temp_dir.path(), 0);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+}
+
+// A shared store with a compaction interval is compacted in the background
+#[test]
+fn compaction_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compactions = Arc::new(Mutex::new(0));
+    let observed = Arc::clone(&compactions);
+    let options = KvStoreOptions::new()
+        .compaction_interval(Duration::from_millis(50))
+        .on_event(move |event| {
+            if let StoreEvent::Compacted { .. } = event {
+                *observed.lock().unwrap() += 1;
+            }
+        });
+    let store = KvStore::open_shared(temp_dir.path(), options)?;
+
+    // nothing to reclaim, nothing compacted
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(*compactions.lock().unwrap(), 0);
+
+    for iter in 0..2 {
+        store
+            .lock()
+            .unwrap()
+            .set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while *compactions.lock().unwrap() == 0 {
+        assert!(Instant::now() < deadline, "no compaction ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(*compactions.lock().unwrap(), 1);
+
+    store.lock().unwrap().remove("key1".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while *compactions.lock().unwrap() == 1 {
+        assert!(Instant::now() < deadline, "no second compaction ran");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.lock().unwrap().get("key1".to_owned())?, None);
+    drop(store);
+
+    let options = KvStoreOptions::new().compaction_interval(Duration::ZERO);
+    let result = KvStore::open_shared(temp_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+    Ok(())
+}
+
+// A burst of writes followed by silence is synced once the store is idle
+#[test]
+fn flush_idle_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().flush_idle_after(Duration::from_millis(200));
+    let store = KvStore::open_shared(temp_dir.path(), options)?;
+    for iter in 0..5 {
+        for id in 0..100 {
+            store
+                .lock()
+                .unwrap()
+                .set(format!("key{}", id), format!("value{}", iter))?;
+        }
+        // writes keep coming, so nothing is synced yet
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(store.lock().unwrap().stats()?.flush_count, 0);
+    }
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.lock().unwrap().stats()?.flush_count, 1);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.lock().unwrap().stats()?.flush_count, 1);
+
+    // the process dies without closing the store
+    std::mem::forget(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 0..100 {
+        assert_eq!(store.get(format!("key{}", id))?, Some("value4".to_owned()));
+    }
+
+    let options = KvStoreOptions::new().flush_idle_after(Duration::ZERO);
+    let result = KvStore::open_shared(temp_dir.path(), options);
+    assert!(matches!(result, Err(KvsError::Config(_))));
+    Ok(())
+}
+
+// A compaction running in another thread is visible through the compaction status
+#[test]
+fn is_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // copying the records takes half a second
+    let options = KvStoreOptions::new().compaction_bytes_per_sec(200_000);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for id in 0..100 {
+        store.set(format!("key{}", id), "value".repeat(200))?;
+    }
+    let status = store.compaction_status();
+    assert!(!store.is_compacting());
+    assert!(!store.stats()?.compacting);
+
+    let store = Arc::new(Mutex::new(store));
+    let compactor = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || store.lock().unwrap().compact())
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !status.is_compacting() {
+        assert!(Instant::now() < deadline, "the compaction never started");
+        thread::sleep(Duration::from_millis(1));
+    }
+    compactor.join().unwrap()?;
+
+    assert!(!status.is_compacting());
+    let store = store.lock().unwrap();
+    assert!(!store.is_compacting());
+    assert!(!store.stats()?.compacting);
+    Ok(())
+}
+
+// Damage to a sealed generation that still decodes is caught by its footer
+#[test]
+fn generation_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 1..=3 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    store.rotate()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(
+        store.verify(false)?,
+        [(1, Verification::Footer), (2, Verification::Replay)]
+    );
+    assert_eq!(
+        store.verify(true)?,
+        [(1, Verification::Replay), (2, Verification::Replay)]
+    );
+    drop(store);
+
+    // the footer is not a record
+    let records = KvStore::decode_log(File::open(temp_dir.path().join("1.log"))?)?;
+    assert_eq!(records.len(), 3);
+
+    let sealed = temp_dir.path().join("1.log");
+    let mut log = fs::read(&sealed)?;
+    let at = log
+        .windows(6)
+        .position(|window| window == b"value2")
+        .expect("the sealed generation holds value2");
+    log[at + 5] = b'X';
+    fs::write(&sealed, log)?;
+
+    // the damaged value still decodes, so only the footer reveals it
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptGeneration { gen, .. }) => assert_eq!(gen, 1),
+        result => panic!("unexpected result {:?}", result.map(|_| ())),
+    }
+    let fast = KvStoreOptions::new().open_mode(OpenMode::Fast);
+    let mut store = KvStore::open_with_options(temp_dir.path(), fast)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("valueX".to_owned()));
+    for deep in [false, true] {
+        match store.verify(deep) {
+            Err(KvsError::CorruptGeneration { gen, .. }) => assert_eq!(gen, 1),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+    Ok(())
+}
+
+/// A subscriber recording the fields of the spans created while it is the default
+#[cfg(feature = "tracing")]
+#[derive(Default, Clone)]
+struct SpanCapture {
+    spans: Arc<Mutex<Vec<SpanFields>>>,
+}
+
+/// The name and fields recorded on a span
+#[cfg(feature = "tracing")]
+#[derive(Default, Clone, Debug)]
+struct SpanFields {
+    name: &'static str,
+    fields: std::collections::BTreeMap<&'static str, String>,
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for SpanFields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.fields.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanCapture {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut fields = SpanFields {
+            name: span.metadata().name(),
+            ..SpanFields::default()
+        };
+        span.record(&mut fields);
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(fields);
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+    }
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, _event: &tracing::Event<'_>) {}
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+// With the `tracing` feature, store operations are wrapped in spans recording
+// the key and value sizes, the generation touched and the duration
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let capture = SpanCapture::default();
+    tracing::subscriber::with_default(capture.clone(), || -> Result<()> {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.remove("key1".to_owned())?;
+        store.compact()?;
+        Ok(())
+    })?;
+
+    let spans = capture.spans.lock().unwrap();
+    let names: Vec<_> = spans.iter().map(|span| span.name).collect();
+    assert_eq!(names, ["set", "get", "remove", "compaction"]);
+    let get = &spans[1].fields;
+    assert_eq!(get["key_size"], "4");
+    assert_eq!(get["value_size"], "6");
+    assert_eq!(get["gen"], "1");
+    assert!(get.contains_key("duration_micros"));
+    assert_eq!(spans[0].fields["value_size"], "6");
+    assert!(spans[3].fields.contains_key("gen"));
+    Ok(())
+}